@@ -1,4 +1,8 @@
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+use crate::config::ConflictStrategy;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -18,5 +22,120 @@ pub enum Commands {
         /// Provider name to sync (if not specified, syncs all enabled providers)
         #[arg(short, long)]
         provider: Option<String>,
+        /// Print what would be synced without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// How to print the plan of a dry run
+        #[arg(long, value_enum, default_value_t = PlanFormat::Table)]
+        format: PlanFormat,
+    },
+    /// List the conflicts recorded for each mapping
+    Conflicts {
+        /// Only show conflicts for this provider
+        #[arg(short, long)]
+        provider: Option<String>,
+        /// Include conflicts that were already resolved
+        #[arg(short, long)]
+        all: bool,
+    },
+    /// Resolve a conflict by hand
+    Resolve {
+        /// Provider the file is synced with
+        #[arg(short, long)]
+        provider: String,
+        /// Local path of the conflicting file
+        path: PathBuf,
+        /// Which version to keep
+        #[arg(short, long, value_enum)]
+        keep: Keep,
     },
+    /// List the mappings paused by the deletion guard
+    Paused {
+        /// Only show mappings of this provider
+        #[arg(short, long)]
+        provider: Option<String>,
+    },
+    /// Settle the deletions that paused a mapping and resume it
+    Confirm {
+        /// Provider the mapping belongs to
+        #[arg(short, long)]
+        provider: String,
+        /// Local folder of the mapping, or any path inside it
+        path: PathBuf,
+        /// Copy the files back instead of deleting them
+        #[arg(long)]
+        restore: bool,
+    },
+    /// List the revisions the provider keeps of a file
+    History {
+        /// Provider the file is synced with
+        #[arg(short, long)]
+        provider: String,
+        /// Local path of the file
+        path: PathBuf,
+    },
+    /// Bring a mapping, or a folder or file in it, back to an earlier point
+    /// in time, or a file back to one of its remote revisions
+    Restore {
+        /// Provider the mapping belongs to
+        #[arg(short, long)]
+        provider: String,
+        /// Local folder of the mapping, or any path inside it
+        path: PathBuf,
+        /// Point in time to go back to, as RFC 3339 or local `YYYY-MM-DD[ HH:MM[:SS]]`
+        #[arg(long, value_parser = parse_time, required_unless_present = "revision")]
+        at: Option<DateTime<Utc>>,
+        /// Remote revision of the file to go back to, as listed by `history`
+        #[arg(long, conflicts_with = "at")]
+        revision: Option<String>,
+        /// Restore into this folder instead of over the local files
+        #[arg(long)]
+        into: Option<PathBuf>,
+        /// Only print what would change
+        #[arg(long)]
+        preview: bool,
+    },
+}
+
+/// A timestamp given on the command line, in local time unless it says otherwise
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)))
+        .ok_or_else(|| format!("{:?} is not a date and time", value))?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| format!("{:?} does not exist in the local time zone", value))
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum PlanFormat {
+    Table,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Keep {
+    Local,
+    Remote,
+    Newest,
+    Both,
+}
+
+impl From<Keep> for ConflictStrategy {
+    fn from(keep: Keep) -> Self {
+        match keep {
+            Keep::Local => ConflictStrategy::PreferLocal,
+            Keep::Remote => ConflictStrategy::PreferRemote,
+            Keep::Newest => ConflictStrategy::PreferNewest,
+            Keep::Both => ConflictStrategy::KeepBoth,
+        }
+    }
 }
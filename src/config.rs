@@ -1,6 +1,9 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -15,18 +18,240 @@ pub struct ProviderConfig {
     pub mappings: Vec<FolderMapping>,
 }
 
+/// How to reach a provider, chosen by `type`. Each backend only takes the
+/// settings it uses, so a missing or misspelled one fails when the config loads.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ProviderCredentials {
-    #[serde(rename = "type")]
-    pub provider_type: String,
-    pub client_id: String,
-    pub client_secret: String,
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ProviderCredentials {
+    #[serde(alias = "GoogleDrive")]
+    GoogleDrive {
+        client_id: String,
+        client_secret: String,
+        /// Overrides the API base URL, e.g. a test server
+        #[serde(default)]
+        endpoint: Option<String>,
+    },
+    #[serde(alias = "OneDrive")]
+    OneDrive {
+        client_id: String,
+        client_secret: String,
+        /// Overrides the Graph base URL, e.g. a national cloud or a test server
+        #[serde(default)]
+        endpoint: Option<String>,
+    },
+    Local {
+        /// Directory used as the remote side
+        root: PathBuf,
+    },
+    WebDav {
+        endpoint: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        /// Bearer token, used instead of username and password when set
+        #[serde(default)]
+        token: Option<String>,
+    },
+    S3 {
+        endpoint: String,
+        /// Defaults to us-east-1
+        #[serde(default)]
+        region: Option<String>,
+        bucket: String,
+        /// Key prefix inside the bucket that acts as the remote root
+        #[serde(default)]
+        prefix: Option<String>,
+        access_key_id: String,
+        secret_access_key: String,
+    },
+    Sftp {
+        host: String,
+        /// Defaults to 22
+        #[serde(default)]
+        port: Option<u16>,
+        username: String,
+        /// The login password, or the passphrase of `key_file` when that is set
+        #[serde(default)]
+        password: Option<String>,
+        /// Private key used instead of the password
+        #[serde(default)]
+        key_file: Option<PathBuf>,
+        /// Defaults to ~/.ssh/known_hosts
+        #[serde(default)]
+        known_hosts: Option<PathBuf>,
+        /// Directory on the server used as the remote side
+        #[serde(default)]
+        root: Option<PathBuf>,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ProviderCredentials {
+    /// The `type` this was configured with
+    pub fn provider_type(&self) -> &'static str {
+        match self {
+            ProviderCredentials::GoogleDrive { .. } => "googledrive",
+            ProviderCredentials::OneDrive { .. } => "onedrive",
+            ProviderCredentials::Local { .. } => "local",
+            ProviderCredentials::WebDav { .. } => "webdav",
+            ProviderCredentials::S3 { .. } => "s3",
+            ProviderCredentials::Sftp { .. } => "sftp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct FolderMapping {
     pub local_path: PathBuf,
     pub remote_path: String,
+    #[serde(default)]
+    pub mode: SyncMode,
+    /// What to do when a file changed on both sides since the last sync
+    #[serde(default)]
+    pub conflict_strategy: ConflictStrategy,
+    /// Glob patterns of files to sync; empty means everything
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Glob patterns of files and folders to leave alone, checked before `include`
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub deletion_guard: DeletionGuard,
+    /// Days deleted files stay in the trash before they are purged, 30 when
+    /// unset. 0 deletes them right away.
+    #[serde(default)]
+    pub trash_days: Option<u32>,
+    /// Keep past versions of synced files, pruned with this policy. Off when unset.
+    #[serde(default)]
+    pub versions: Option<Retention>,
+    /// Minutes between full scans that pick up changes the watcher missed. Off when unset.
+    #[serde(default)]
+    pub rescan_minutes: Option<u32>,
+    /// How local changes are noticed
+    #[serde(default)]
+    pub watch: WatchMode,
+    /// Seconds between polls when the folder is polled, 30 when unset
+    #[serde(default)]
+    pub poll_seconds: Option<u32>,
+    /// Seconds a file must stay unchanged before it is synced, unless its
+    /// writer closes it first. 2 when unset.
+    #[serde(default)]
+    pub settle_seconds: Option<u32>,
+}
+
+/// Which past versions of a file the version store keeps. A version is kept
+/// if any of the rules wants it, and the newest one is always kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    /// The most recent versions
+    pub keep_last: usize,
+    /// The last version of each of the past days
+    pub keep_daily: u32,
+    /// The last version of each of the past weeks
+    pub keep_weekly: u32,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self { keep_last: 10, keep_daily: 7, keep_weekly: 4 }
+    }
+}
+
+impl FolderMapping {
+    pub fn trash_retention_days(&self) -> u32 {
+        self.trash_days.unwrap_or(30)
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(u64::from(self.poll_seconds.unwrap_or(30).max(1)))
+    }
+
+    pub fn settle_time(&self) -> Duration {
+        Duration::from_secs(u64::from(self.settle_seconds.unwrap_or(2)))
+    }
+}
+
+/// Limits on how much a single batch may delete before the mapping pauses
+/// and waits for confirmation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeletionGuard {
+    /// Most files one batch may delete
+    pub max_count: usize,
+    /// Most of the mapping's synced files, in percent, one batch may delete.
+    /// Batches of up to `PERCENT_FLOOR` files are never held back by this.
+    pub max_percent: u8,
+}
+
+impl DeletionGuard {
+    const PERCENT_FLOOR: usize = 10;
+
+    /// Whether deleting `deletes` of the `tracked` synced files needs confirmation
+    pub fn trips(&self, deletes: usize, tracked: usize) -> bool {
+        deletes > self.max_count
+            || (deletes > Self::PERCENT_FLOOR && deletes * 100 > tracked * usize::from(self.max_percent))
+    }
+}
+
+impl Default for DeletionGuard {
+    fn default() -> Self {
+        Self { max_count: 100, max_percent: 30 }
+    }
+}
+
+/// Which way changes flow for a mapping
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncMode {
+    #[default]
+    TwoWay,
+    /// Backup: local changes go up, the local copy wins over anything done remotely
+    #[serde(alias = "backup")]
+    UploadOnly,
+    /// Mirror: remote changes come down, local edits are reverted
+    #[serde(alias = "mirror")]
+    DownloadOnly,
+    /// Like upload-only, but nothing is ever deleted or moved away remotely
+    Archive,
+}
+
+impl SyncMode {
+    pub fn uploads(self) -> bool {
+        self != SyncMode::DownloadOnly
+    }
+
+    pub fn downloads(self) -> bool {
+        matches!(self, SyncMode::TwoWay | SyncMode::DownloadOnly)
+    }
+}
+
+/// How a mapping's local folder is watched
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatchMode {
+    /// Poll folders on network filesystems, use native events everywhere else
+    #[default]
+    Auto,
+    /// Events from the operating system
+    Native,
+    /// Compare snapshots of the folder every `poll_seconds`, which also sees
+    /// changes other machines make on a network share
+    Poll,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictStrategy {
+    /// Keep the remote version under the original name and the local one as a conflict copy
+    #[default]
+    KeepBoth,
+    PreferLocal,
+    PreferRemote,
+    /// Keep whichever side was modified last
+    PreferNewest,
+    /// Record the conflict and leave both sides alone until it is resolved by hand
+    Manual,
 }
 
 impl Config {
@@ -36,3 +261,32 @@ impl Config {
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deletion_guard() {
+        let guard = DeletionGuard::default();
+        assert!(!guard.trips(0, 0));
+        assert!(!guard.trips(100, 10_000));
+        assert!(guard.trips(101, 10_000));
+        // Small batches never trip the percentage
+        assert!(!guard.trips(8, 8));
+        assert!(guard.trips(11, 20));
+        assert!(!guard.trips(11, 100));
+    }
+
+    #[test]
+    fn test_credentials_per_provider_type() {
+        let config: Config = toml::from_str(include_str!("../examples/config.toml")).unwrap();
+        let types: Vec<&str> = config.providers.iter().map(|p| p.credentials.provider_type()).collect();
+        assert_eq!(types, ["googledrive", "onedrive", "googledrive", "local", "webdav", "s3", "sftp"]);
+
+        // What a backend needs is checked when the config loads, not when it connects
+        let missing = toml::from_str::<ProviderCredentials>("type = \"s3\"\nendpoint = \"http://localhost:9000\"");
+        assert!(missing.unwrap_err().to_string().contains("bucket"));
+        assert!(toml::from_str::<ProviderCredentials>("type = \"dropbox\"").is_err());
+    }
+}
@@ -2,8 +2,10 @@ pub mod cli;
 pub mod config;
 pub mod provider;
 pub mod service;
+pub mod state;
 pub mod sync;
 pub mod tui;
+pub mod watcher;
 
 pub use service::SyncService;
 pub use sync::SyncOperation;
@@ -1,38 +1,333 @@
 use anyhow::Result;
 use clap::Parser;
-use filesync::{config::Config, service::SyncService, tui::Tui};
-
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-struct Cli {
-    /// Path to the configuration file
-    #[arg(short, long, default_value = "config.toml")]
-    config: String,
-
-    /// Provider to sync (if not specified, syncs all enabled providers)
-    #[arg(short, long)]
-    provider: Option<String>,
-}
+use filesync::{
+    cli::{Cli, Commands, Keep, PlanFormat},
+    config::{Config, FolderMapping, ProviderConfig},
+    provider::factory,
+    service::SyncService,
+    state::{Decision, SyncState},
+    sync::{MappingPlan, RestorePlan, SyncOperation},
+    tui::Tui,
+};
+use chrono::{DateTime, Utc};
+use std::path::Path;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
     let config = Config::from_file(&cli.config).await?;
 
-    if let Some(provider_name) = cli.provider {
-        // Sync specific provider
-        let provider = config.providers
-            .iter()
-            .find(|p| p.name == provider_name)
-            .ok_or_else(|| anyhow::anyhow!("Provider not found: {}", provider_name))?;
+    match cli.command {
+        Some(Commands::Sync { provider, dry_run: false, .. }) => run_sync(&config, provider.as_deref()).await?,
+        Some(Commands::Sync { provider, dry_run: true, format }) => dry_run(&config, provider.as_deref(), format).await?,
+        Some(Commands::Conflicts { provider, all }) => list_conflicts(&config, provider.as_deref(), all).await?,
+        Some(Commands::Resolve { provider, path, keep }) => resolve(&config, &provider, &path, keep).await?,
+        Some(Commands::Paused { provider }) => list_paused(&config, provider.as_deref()).await?,
+        Some(Commands::Confirm { provider, path, restore }) => confirm(&config, &provider, &path, restore).await?,
+        Some(Commands::History { provider, path }) => history(&config, &provider, &path).await?,
+        Some(Commands::Restore { provider, path, revision: Some(revision), into, preview, .. }) => {
+            restore_revision(&config, &provider, &path, &revision, into.as_deref(), preview).await?
+        }
+        Some(Commands::Restore { provider, path, at: Some(at), into, preview, .. }) => {
+            restore(&config, &provider, &path, at, into.as_deref(), preview).await?
+        }
+        Some(Commands::Restore { .. }) => unreachable!("clap requires --at or --revision"),
+        None => {
+            // Start TUI mode
+            let mut tui = Tui::new(config)?;
+            tui.run().await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn find_provider<'a>(config: &'a Config, name: &str) -> Result<&'a ProviderConfig> {
+    config.providers
+        .iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| anyhow::anyhow!("Provider not found: {}", name))
+}
+
+fn selected_providers(config: &Config, provider_name: Option<&str>) -> Result<Vec<ProviderConfig>> {
+    Ok(match provider_name {
+        Some(name) => vec![find_provider(config, name)?.clone()],
+        None => config.providers.iter().filter(|p| p.enabled).cloned().collect(),
+    })
+}
+
+async fn run_sync(config: &Config, provider_name: Option<&str>) -> Result<()> {
+    let providers = selected_providers(config, provider_name)?;
+
+    let mut service = SyncService::new(providers);
+    service.start().await?;
+
+    // The watchers run in the background until interrupted
+    tokio::signal::ctrl_c().await?;
+    service.stop().await
+}
+
+async fn dry_run(config: &Config, provider_name: Option<&str>, format: PlanFormat) -> Result<()> {
+    let mut service = SyncService::new(selected_providers(config, provider_name)?).with_dry_run(true);
+    service.start().await?;
+
+    match format {
+        PlanFormat::Json => println!("{}", serde_json::to_string_pretty(service.plans())?),
+        PlanFormat::Table => service.plans().iter().for_each(print_plan),
+    }
+    Ok(())
+}
 
-        let mut service = SyncService::new(vec![provider.clone()]);
-        service.start().await?;
+fn print_plan(plan: &MappingPlan) {
+    println!("{}: {} <-> {}", plan.provider, plan.local_path.display(), plan.remote_path);
+    if plan.actions.is_empty() {
+        println!("  nothing to do");
+    }
+
+    for action in &plan.actions {
+        let size = match action.decision {
+            Decision::Upload => action.local.as_ref().map(|local| local.size),
+            Decision::Download => action.remote.as_ref().map(|remote| remote.size),
+            _ => None,
+        };
+        let path = match &action.moved_from {
+            Some(from) => format!("{} -> {}", from, action.relative),
+            None => action.relative.clone(),
+        };
+        println!(
+            "  {:<14} {:>10}  {}",
+            action.decision.as_str(),
+            size.map(format_bytes).unwrap_or_default(),
+            path
+        );
+    }
+
+    let summary = &plan.summary;
+    println!(
+        "  {} upload(s), {} download(s), {} local delete(s), {} remote delete(s), {} move(s), {} conflict(s)",
+        summary.uploads, summary.downloads, summary.local_deletes, summary.remote_deletes, summary.moves, summary.conflicts
+    );
+    println!(
+        "  {} to upload, {} to download",
+        format_bytes(summary.upload_bytes),
+        format_bytes(summary.download_bytes)
+    );
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
     } else {
-        // Start TUI mode
-        let mut tui = Tui::new(config)?;
-        tui.run().await?;
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+async fn list_conflicts(config: &Config, provider_name: Option<&str>, all: bool) -> Result<()> {
+    for provider in &config.providers {
+        if provider_name.is_some_and(|name| name != provider.name) {
+            continue;
+        }
+
+        for mapping in &provider.mappings {
+            let state = SyncState::for_mapping(&provider.name, mapping).await?;
+            let conflicts = if all { state.conflicts().await } else { state.unresolved_conflicts().await };
+
+            for conflict in conflicts {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    provider.name,
+                    mapping.local_path.join(&conflict.relative).display(),
+                    conflict.detected_at.format("%Y-%m-%d %H:%M:%S"),
+                    conflict.resolution.as_deref().unwrap_or("unresolved"),
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn resolve(config: &Config, provider_name: &str, path: &Path, keep: Keep) -> Result<()> {
+    let provider_config = find_provider(config, provider_name)?;
+    let path = std::path::absolute(path)?;
+    let (mapping, relative) = find_file(provider_config, &path)?;
+
+    let sync_op = connect(provider_config).await?;
+    let state = SyncState::for_mapping(&provider_config.name, mapping).await?;
+
+    sync_op.resolve(mapping, &state, &relative, keep.into()).await?;
+    println!("Resolved {}", path.display());
+    Ok(())
+}
+
+/// The mapping a local file belongs to, and its path relative to the mapping root
+fn find_file<'a>(provider_config: &'a ProviderConfig, path: &Path) -> Result<(&'a FolderMapping, String)> {
+    provider_config.mappings
+        .iter()
+        .find_map(|mapping| SyncOperation::relative_path(path, mapping).map(|relative| (mapping, relative)))
+        .ok_or_else(|| anyhow::anyhow!("{:?} is not inside any mapping of {}", path, provider_config.name))
+}
+
+async fn connect(provider_config: &ProviderConfig) -> Result<SyncOperation> {
+    let mut provider = factory::create_provider(provider_config).await?;
+    provider.initialize().await?;
+    Ok(SyncOperation::new(provider))
+}
+
+async fn list_paused(config: &Config, provider_name: Option<&str>) -> Result<()> {
+    for provider in &config.providers {
+        if provider_name.is_some_and(|name| name != provider.name) {
+            continue;
+        }
+
+        for mapping in &provider.mappings {
+            let state = SyncState::for_mapping(&provider.name, mapping).await?;
+            let Some(paused) = state.paused().await else { continue };
+
+            println!(
+                "{}\t{}\t{}\t{} local and {} remote deletion(s)",
+                provider.name,
+                mapping.local_path.display(),
+                paused.detected_at.format("%Y-%m-%d %H:%M:%S"),
+                paused.delete_local.len(),
+                paused.delete_remote.len(),
+            );
+            for relative in &paused.delete_local {
+                println!("  delete local   {}", relative);
+            }
+            for relative in &paused.delete_remote {
+                println!("  delete remote  {}", relative);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn confirm(config: &Config, provider_name: &str, path: &Path, restore: bool) -> Result<()> {
+    let provider_config = find_provider(config, provider_name)?;
+    let path = std::path::absolute(path)?;
+    let mapping = provider_config.mappings
+        .iter()
+        .find(|mapping| mapping.local_path == path || SyncOperation::relative_path(&path, mapping).is_some())
+        .ok_or_else(|| anyhow::anyhow!("{:?} is not a mapping of {}", path, provider_name))?;
+
+    let sync_op = connect(provider_config).await?;
+    let state = SyncState::for_mapping(&provider_config.name, mapping).await?;
+
+    let applied = sync_op.confirm_deletions(mapping, &state, restore).await?;
+    println!("Resumed {} with {} change(s)", path.display(), applied.len());
+    Ok(())
+}
+
+async fn restore(
+    config: &Config,
+    provider_name: &str,
+    path: &Path,
+    at: DateTime<Utc>,
+    into: Option<&Path>,
+    preview: bool,
+) -> Result<()> {
+    let provider_config = find_provider(config, provider_name)?;
+    let path = std::path::absolute(path)?;
+    let (mapping, subtree) = provider_config.mappings
+        .iter()
+        .find_map(|mapping| match SyncOperation::relative_path(&path, mapping) {
+            Some(relative) => Some((mapping, relative)),
+            None => (mapping.local_path == path).then(|| (mapping, String::new())),
+        })
+        .ok_or_else(|| anyhow::anyhow!("{:?} is not inside any mapping of {}", path, provider_name))?;
+
+    let state = SyncState::for_mapping(&provider_config.name, mapping).await?;
+    let into = into.map(std::path::absolute).transpose()?;
+    let plan = RestorePlan::new(mapping, &state, &subtree, at, into.as_deref()).await?;
+
+    println!("Restoring {} as of {} into {}", path.display(), at.with_timezone(&chrono::Local), plan.target.display());
+    for action in &plan.actions {
+        println!("  {:<10} {}", action.change.as_str(), action.relative);
+    }
+    if plan.actions.is_empty() {
+        println!("  nothing to do");
+    }
+    if preview || plan.actions.is_empty() {
+        return Ok(());
+    }
+
+    let changed = plan.apply(mapping, &state).await?;
+    if plan.in_place {
+        // Carry the restored files over to the remote side right away
+        let sync_op = connect(provider_config).await?;
+        let relatives: Vec<String> = plan.actions.iter().map(|action| action.relative.clone()).collect();
+        sync_op.sync_batch(mapping, &state, &relatives).await?;
+    }
+    println!("Restored {} file(s)", changed);
+    Ok(())
+}
+
+async fn history(config: &Config, provider_name: &str, path: &Path) -> Result<()> {
+    let provider_config = find_provider(config, provider_name)?;
+    let path = std::path::absolute(path)?;
+    let (mapping, relative) = find_file(provider_config, &path)?;
+
+    let sync_op = connect(provider_config).await?;
+    let revisions = sync_op.revisions(mapping, &relative).await?;
+    if revisions.is_empty() {
+        println!("{} keeps no revisions of {}", provider_name, path.display());
+    }
+    for revision in revisions {
+        println!(
+            "{}\t{}\t{:>10}\t{}",
+            revision.id,
+            revision.modified.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+            format_bytes(revision.size),
+            revision.modified_by.as_deref().unwrap_or(""),
+        );
+    }
+    Ok(())
+}
+
+async fn restore_revision(
+    config: &Config,
+    provider_name: &str,
+    path: &Path,
+    revision_id: &str,
+    into: Option<&Path>,
+    preview: bool,
+) -> Result<()> {
+    let provider_config = find_provider(config, provider_name)?;
+    let path = std::path::absolute(path)?;
+    let (mapping, relative) = find_file(provider_config, &path)?;
+
+    let sync_op = connect(provider_config).await?;
+    let revision = sync_op
+        .revisions(mapping, &relative)
+        .await?
+        .into_iter()
+        .find(|revision| revision.id == revision_id)
+        .ok_or_else(|| anyhow::anyhow!("{} has no revision {} of {}", provider_name, revision_id, path.display()))?;
+
+    println!(
+        "Restoring {} to revision {} of {} ({})",
+        path.display(),
+        revision.id,
+        revision.modified.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+        format_bytes(revision.size),
+    );
+    if preview {
+        return Ok(());
     }
 
+    let state = SyncState::for_mapping(&provider_config.name, mapping).await?;
+    let into = into.map(std::path::absolute).transpose()?;
+    let target = sync_op.restore_revision(mapping, &state, &relative, &revision.id, into.as_deref()).await?;
+    println!("Restored {}", target.display());
     Ok(())
 }
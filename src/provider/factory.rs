@@ -1,23 +1,114 @@
 use anyhow::{anyhow, Result};
-use crate::config::ProviderConfig;
-use crate::provider::{CloudProvider, google_drive::GoogleDriveProvider, onedrive::OneDriveProvider};
+use std::path::PathBuf;
+use crate::config::{ProviderConfig, ProviderCredentials};
+use crate::provider::{
+    CloudProvider, google_drive::GoogleDriveProvider, local::LocalProvider, onedrive::OneDriveProvider,
+    s3::{S3Provider, S3Settings},
+    sftp::{SftpAuth, SftpProvider, SftpSettings},
+    webdav::{WebDavAuth, WebDavProvider},
+};
+
+/// Where a provider that logs in through the browser keeps its tokens
+pub fn token_path(credentials: &ProviderCredentials) -> Option<PathBuf> {
+    let file_name = match credentials {
+        ProviderCredentials::GoogleDrive { client_id, .. } => format!("googledrive_{}.json", client_id),
+        ProviderCredentials::OneDrive { client_id, .. } => format!("onedrive_{}.json", client_id),
+        _ => return None,
+    };
+    dirs::config_dir().map(|config_dir| config_dir.join("filesynchub").join(file_name))
+}
+
+/// Whether using the provider would first open a browser to log in
+pub fn needs_login(credentials: &ProviderCredentials) -> bool {
+    match credentials {
+        ProviderCredentials::GoogleDrive { .. } | ProviderCredentials::OneDrive { .. } => {
+            !token_path(credentials).is_some_and(|path| path.exists())
+        }
+        _ => false,
+    }
+}
 
 pub async fn create_provider(config: &ProviderConfig) -> Result<Box<dyn CloudProvider>> {
-    match &config.credentials.provider_type {
-        "googledrive" => {
-            let provider = GoogleDriveProvider::new(
-                &config.credentials.client_id,
-                &config.credentials.client_secret,
+    match &config.credentials {
+        ProviderCredentials::GoogleDrive { client_id, client_secret, endpoint } => {
+            // Keep the tokens next to OneDrive's, so a restart does not ask to log in again
+            let token_path = token_path(&config.credentials);
+            if let Some(token_dir) = token_path.as_ref().and_then(|path| path.parent()) {
+                tokio::fs::create_dir_all(token_dir).await?;
+            }
+            let mut provider = GoogleDriveProvider::new(
+                client_id.clone(),
+                client_secret.clone(),
+                token_path,
+                config.mappings.clone(),
             ).await?;
+            if let Some(endpoint) = endpoint {
+                provider = provider.with_base_url(endpoint.as_str());
+            }
             Ok(Box::new(provider))
         }
-        "onedrive" => {
-            let provider = OneDriveProvider::new(
-                &config.credentials.client_id,
-                &config.credentials.client_secret,
+        ProviderCredentials::OneDrive { client_id, client_secret, endpoint } => {
+            let mut provider = OneDriveProvider::new(
+                client_id.clone(),
+                client_secret.clone(),
+                config.mappings.clone(),
             );
+            if let Some(endpoint) = endpoint {
+                provider = provider.with_base_url(endpoint.as_str());
+            }
             Ok(Box::new(provider))
         }
-        _ => Err(anyhow!("Unsupported provider type: {}", config.credentials.provider_type)),
+        ProviderCredentials::Local { root } => {
+            Ok(Box::new(LocalProvider::new(root.clone(), config.mappings.clone())))
+        }
+        ProviderCredentials::WebDav { endpoint, username, password, token } => {
+            let auth = match (token, username) {
+                (Some(token), _) => WebDavAuth::Bearer(token.clone()),
+                (None, Some(username)) => WebDavAuth::Basic {
+                    username: username.clone(),
+                    password: password.clone().unwrap_or_default(),
+                },
+                (None, None) => WebDavAuth::None,
+            };
+            Ok(Box::new(WebDavProvider::new(endpoint, auth, config.mappings.clone())))
+        }
+        ProviderCredentials::S3 { endpoint, region, bucket, prefix, access_key_id, secret_access_key } => {
+            let settings = S3Settings {
+                endpoint: endpoint.clone(),
+                region: region.clone().unwrap_or_else(|| "us-east-1".to_string()),
+                bucket: bucket.clone(),
+                prefix: prefix.clone().unwrap_or_default(),
+                access_key_id: access_key_id.clone(),
+                secret_access_key: secret_access_key.clone(),
+            };
+            Ok(Box::new(S3Provider::new(settings, config.mappings.clone())))
+        }
+        ProviderCredentials::Sftp { host, port, username, password, key_file, known_hosts, root } => {
+            let auth = match (key_file, password) {
+                (Some(path), _) => SftpAuth::KeyFile {
+                    path: path.clone(),
+                    passphrase: password.clone(),
+                },
+                (None, Some(password)) => SftpAuth::Password(password.clone()),
+                (None, None) => return Err(anyhow!("Provider {} needs a key_file or a password", config.name)),
+            };
+            let known_hosts = match known_hosts {
+                Some(path) => path.clone(),
+                None => dirs::home_dir()
+                    .ok_or_else(|| anyhow!("Provider {} needs a known_hosts file", config.name))?
+                    .join(".ssh/known_hosts"),
+            };
+            let settings = SftpSettings {
+                host: host.clone(),
+                port: port.unwrap_or(22),
+                username: username.clone(),
+                auth,
+                known_hosts,
+                root: root.as_ref()
+                    .map(|root| root.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            };
+            Ok(Box::new(SftpProvider::new(settings, config.mappings.clone())))
+        }
     }
-} 
\ No newline at end of file
+}
@@ -1,15 +1,31 @@
-use std::path::Path;
-use anyhow::Result;
+use std::{collections::HashMap, io::Cursor, path::{Path, PathBuf}};
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use google_drive3::{DriveHub, oauth2, hyper, hyper_rustls};
-use tokio::sync::mpsc;
+use google_drive3::{
+    api::File,
+    client::GetToken,
+    hyper::{self, body::HttpBody},
+    hyper_rustls, oauth2, DriveHub, Error as DriveError,
+};
+use tokio::{fs, io::AsyncWriteExt, sync::RwLock};
 use chrono::{DateTime, Utc};
 
-use super::{CloudProvider, RemoteItem, ChangeType, FolderMapping};
+use super::{ChangeFeed, CloudProvider, RemoteChange, RemoteItem, Revision, FolderMapping};
+
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+/// Docs, Sheets and other files that only exist inside Drive
+const NATIVE_MIME_PREFIX: &str = "application/vnd.google-apps.";
+const ITEM_FIELDS: &str = "id,name,mimeType,size,modifiedTime,createdTime,md5Checksum,version";
+const REVISION_FIELDS: &str = "id,size,modifiedTime,lastModifyingUser(displayName,emailAddress)";
+/// Files above this size are sent with the resumable protocol instead of a single request
+const RESUMABLE_UPLOAD_THRESHOLD: u64 = 5 * 1024 * 1024;
+
+type HttpsConnector = hyper_rustls::HttpsConnector<hyper::client::HttpConnector>;
 
 pub struct GoogleDriveProvider {
-    #[allow(dead_code)]
-    hub: DriveHub<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>,
+    hub: DriveHub<HttpsConnector>,
+    /// Folder IDs keyed by normalized remote path, `/` is always `root`
+    folder_ids: RwLock<HashMap<String, String>>,
     mappings: Vec<FolderMapping>,
 }
 
@@ -17,7 +33,7 @@ impl GoogleDriveProvider {
     pub async fn new(
         client_id: String,
         client_secret: String,
-        _token_path: Option<String>,
+        token_path: Option<PathBuf>,
         mappings: Vec<FolderMapping>,
     ) -> Result<Self> {
         let secret = oauth2::ApplicationSecret {
@@ -25,18 +41,28 @@ impl GoogleDriveProvider {
             client_secret,
             token_uri: "https://oauth2.googleapis.com/token".to_string(),
             auth_uri: "https://accounts.google.com/o/oauth2/auth".to_string(),
-            redirect_uris: vec!["urn:ietf:wg:oauth:2.0:oob".to_string()],
+            redirect_uris: vec!["http://localhost".to_string()],
             ..Default::default()
         };
 
-        let auth = oauth2::InstalledFlowAuthenticator::builder(
+        let mut builder = oauth2::InstalledFlowAuthenticator::builder(
             secret,
-            oauth2::InstalledFlowReturnMethod::Interactive,
-        ).build().await?;
+            // Google no longer accepts the copy-and-paste (OOB) flow, the browser redirects to a local port instead
+            oauth2::InstalledFlowReturnMethod::HTTPRedirect,
+        );
+        if let Some(token_path) = token_path {
+            builder = builder.persist_tokens_to_disk(token_path);
+        }
+        let auth = builder.build().await?;
+
+        Self::with_auth(auth, mappings)
+    }
 
+    /// Build a provider around any token source, e.g. a fixed token or none at all
+    pub fn with_auth<A: GetToken + 'static>(auth: A, mappings: Vec<FolderMapping>) -> Result<Self> {
         let connector = hyper_rustls::HttpsConnectorBuilder::new()
             .with_native_roots()?
-            .https_only()
+            .https_or_http()
             .enable_http1()
             .enable_http2()
             .build();
@@ -46,7 +72,276 @@ impl GoogleDriveProvider {
             auth,
         );
 
-        Ok(Self { hub, mappings })
+        Ok(Self {
+            hub,
+            folder_ids: RwLock::new(HashMap::from([("/".to_string(), "root".to_string())])),
+            mappings,
+        })
+    }
+
+    /// Point the provider at a different API host, e.g. a mock server
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        let root = format!("{}/", base_url.trim_end_matches('/'));
+        self.hub.base_url(format!("{}drive/v3/", root));
+        self.hub.root_url(root);
+        self
+    }
+
+    /// Normalize a remote path to the `/a/b/c` form used as cache key
+    fn normalize(remote_path: &str) -> String {
+        let segments: Vec<&str> = remote_path.split('/').filter(|s| !s.is_empty()).collect();
+        format!("/{}", segments.join("/"))
+    }
+
+    fn split_parent(path: &str) -> (String, String) {
+        match path.rsplit_once('/') {
+            Some((parent, name)) => (Self::normalize(parent), name.to_string()),
+            None => ("/".to_string(), path.to_string()),
+        }
+    }
+
+    fn escape_query(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('\'', "\\'")
+    }
+
+    fn to_remote_item(file: File) -> RemoteItem {
+        RemoteItem {
+            name: file.name.unwrap_or_default(),
+            id: file.id.unwrap_or_default(),
+            size: file.size.unwrap_or_default() as u64,
+            modified: file.modified_time.unwrap_or(DateTime::<Utc>::UNIX_EPOCH),
+            is_folder: file.mime_type.as_deref() == Some(FOLDER_MIME_TYPE),
+            // Folders have no checksum, their version still moves on every change
+            etag: file.md5_checksum.or_else(|| file.version.map(|v| v.to_string())),
+        }
+    }
+
+    /// Docs, Sheets and the like have no content to download, so syncing leaves them out
+    fn is_native(file: &File) -> bool {
+        file.mime_type.as_deref().is_some_and(|mime| mime.starts_with(NATIVE_MIME_PREFIX) && mime != FOLDER_MIME_TYPE)
+    }
+
+    fn is_not_found(error: &DriveError) -> bool {
+        match error {
+            DriveError::BadRequest(value) => value["error"]["code"].as_u64() == Some(404),
+            DriveError::Failure(response) => response.status() == hyper::StatusCode::NOT_FOUND,
+            _ => false,
+        }
+    }
+
+    /// Drive answers a page token it no longer accepts, e.g. one that expired, with 400, 404 or 410
+    fn is_rejected_token(error: &DriveError) -> bool {
+        const REJECTED: [u16; 3] = [400, 404, 410];
+        match error {
+            DriveError::BadRequest(value) => value["error"]["code"].as_u64().is_some_and(|code| REJECTED.contains(&(code as u16))),
+            DriveError::Failure(response) => REJECTED.contains(&response.status().as_u16()),
+            _ => false,
+        }
+    }
+
+    async fn save_body(mut response: hyper::Response<hyper::Body>, local_path: &Path) -> Result<()> {
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut file = fs::File::create(local_path).await?;
+        let body = response.body_mut();
+        while let Some(chunk) = body.data().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+
+    async fn list_children(&self, query: &str) -> Result<Vec<File>> {
+        let mut files = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut call = self.hub
+                .files()
+                .list()
+                .q(query)
+                .order_by("createdTime")
+                .param("fields", &format!("nextPageToken,files({})", ITEM_FIELDS));
+            if let Some(token) = &page_token {
+                call = call.page_token(token);
+            }
+
+            let (_, file_list) = call.doit().await?;
+            files.extend(file_list.files.unwrap_or_default());
+
+            match file_list.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => break,
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Find a child by name. Drive allows several items with the same name in one folder,
+    /// in that case folders win when `want_folder` is set and otherwise the oldest item wins.
+    async fn find_child(&self, parent_id: &str, name: &str, want_folder: bool) -> Result<Option<File>> {
+        let query = format!(
+            "name = '{}' and '{}' in parents and trashed = false",
+            Self::escape_query(name),
+            Self::escape_query(parent_id)
+        );
+        let mut candidates = self.list_children(&query).await?;
+
+        if candidates.len() > 1 {
+            log::warn!(
+                "Found {} items named {:?} in folder {}, using the oldest one",
+                candidates.len(),
+                name,
+                parent_id
+            );
+        }
+        if want_folder {
+            if let Some(index) = candidates
+                .iter()
+                .position(|f| f.mime_type.as_deref() == Some(FOLDER_MIME_TYPE))
+            {
+                return Ok(Some(candidates.swap_remove(index)));
+            }
+        }
+
+        Ok(candidates.into_iter().next())
+    }
+
+    /// Find the oldest file, never a folder, with this name
+    async fn find_file(&self, parent_id: &str, name: &str) -> Result<Option<File>> {
+        let query = format!(
+            "name = '{}' and '{}' in parents and mimeType != '{}' and trashed = false",
+            Self::escape_query(name),
+            Self::escape_query(parent_id),
+            FOLDER_MIME_TYPE
+        );
+        Ok(self.list_children(&query).await?.into_iter().next())
+    }
+
+    /// Resolve a folder path to its Drive ID, walking and caching each segment.
+    /// With `create` set, missing folders are created along the way.
+    async fn resolve_folder(&self, remote_path: &str, create: bool) -> Result<Option<String>> {
+        let path = Self::normalize(remote_path);
+        if let Some(id) = self.folder_ids.read().await.get(&path) {
+            return Ok(Some(id.clone()));
+        }
+
+        let mut current_path = String::new();
+        let mut current_id = "root".to_string();
+
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            current_path = format!("{}/{}", current_path, segment);
+            if let Some(id) = self.folder_ids.read().await.get(&current_path) {
+                current_id = id.clone();
+                continue;
+            }
+
+            let found = self
+                .find_child(&current_id, segment, true)
+                .await?
+                .filter(|f| f.mime_type.as_deref() == Some(FOLDER_MIME_TYPE));
+
+            current_id = match found {
+                Some(folder) => folder.id.unwrap_or_default(),
+                None if create => {
+                    let folder = File {
+                        name: Some(segment.to_string()),
+                        mime_type: Some(FOLDER_MIME_TYPE.to_string()),
+                        parents: Some(vec![current_id.clone()]),
+                        ..Default::default()
+                    };
+                    // The generated client only creates items through an upload, folders get an empty body
+                    let (_, created) = self.hub
+                        .files()
+                        .create(folder)
+                        .param("fields", ITEM_FIELDS)
+                        .upload(Cursor::new(Vec::new()), FOLDER_MIME_TYPE.parse()?)
+                        .await?;
+                    created.id.unwrap_or_default()
+                }
+                None => return Ok(None),
+            };
+
+            self.folder_ids
+                .write()
+                .await
+                .insert(current_path.clone(), current_id.clone());
+        }
+
+        Ok(Some(current_id))
+    }
+
+    async fn resolve_item(&self, remote_path: &str) -> Result<Option<File>> {
+        let path = Self::normalize(remote_path);
+        if path == "/" {
+            let (_, root) = self.hub
+                .files()
+                .get("root")
+                .param("fields", ITEM_FIELDS)
+                .doit()
+                .await?;
+            return Ok(Some(root));
+        }
+
+        let (parent, name) = Self::split_parent(&path);
+        match self.resolve_folder(&parent, false).await? {
+            Some(parent_id) => self.find_child(&parent_id, &name, false).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Path of `file` if it lies below the folder `root_id` at `root_path`,
+    /// found by walking up its parents. `folders` keeps the name and parent
+    /// of every folder looked up on the way.
+    async fn path_below(
+        &self,
+        file: &File,
+        root_id: &str,
+        root_path: &str,
+        folders: &mut HashMap<String, (String, Option<String>)>,
+    ) -> Result<Option<String>> {
+        let mut names = vec![file.name.clone().unwrap_or_default()];
+        let mut parent = file.parents.as_ref().and_then(|parents| parents.first().cloned());
+
+        loop {
+            let Some(id) = parent else { return Ok(None) };
+            if id == root_id {
+                break;
+            }
+            if !folders.contains_key(&id) {
+                let folder = match self.hub.files().get(&id).param("fields", "id,name,parents").doit().await {
+                    Ok((_, folder)) => folder,
+                    // Shared with us from a folder we cannot see
+                    Err(e) if Self::is_not_found(&e) => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+                let grandparent = folder.parents.and_then(|parents| parents.into_iter().next());
+                folders.insert(id.clone(), (folder.name.unwrap_or_default(), grandparent));
+            }
+
+            let (name, grandparent) = folders[&id].clone();
+            // Only the top of My Drive has no parent, which is where a mapping of `/` starts
+            if grandparent.is_none() && root_id == "root" {
+                break;
+            }
+            names.push(name);
+            parent = grandparent;
+        }
+
+        names.reverse();
+        Ok(Some(format!("{}/{}", root_path.trim_end_matches('/'), names.join("/"))))
+    }
+
+    /// Drop cached folder IDs at or below `remote_path`
+    async fn forget(&self, remote_path: &str) {
+        let path = Self::normalize(remote_path);
+        let prefix = format!("{}/", path);
+        self.folder_ids
+            .write()
+            .await
+            .retain(|key, _| key == "/" || (key != &path && !key.starts_with(&prefix)));
     }
 }
 
@@ -59,75 +354,627 @@ impl CloudProvider for GoogleDriveProvider {
     }
 
     async fn list_files(&self, remote_path: &str) -> Result<Vec<RemoteItem>> {
-        let (_, file_list) = self.hub
+        let folder_id = self
+            .resolve_folder(remote_path, false)
+            .await?
+            .ok_or_else(|| anyhow!("Remote folder not found: {}", remote_path))?;
+
+        let query = format!("'{}' in parents and trashed = false", Self::escape_query(&folder_id));
+        let items = self
+            .list_children(&query)
+            .await?
+            .into_iter()
+            .filter(|file| !Self::is_native(file))
+            .map(Self::to_remote_item)
+            .collect();
+
+        Ok(items)
+    }
+
+    async fn upload_file(&self, local_path: &Path, remote_path: &str) -> Result<RemoteItem> {
+        let path = Self::normalize(remote_path);
+        let (parent, name) = Self::split_parent(&path);
+        let parent_id = self
+            .resolve_folder(&parent, true)
+            .await?
+            .ok_or_else(|| anyhow!("Could not create remote folder: {}", parent))?;
+
+        let size = fs::metadata(local_path).await?.len();
+        let content = std::fs::File::open(local_path)?;
+        let mime_type: mime::Mime = "application/octet-stream".parse()?;
+
+        let existing = self.find_file(&parent_id, &name).await?;
+
+        let (_, file) = match existing.and_then(|f| f.id) {
+            Some(id) => {
+                let call = self.hub
+                    .files()
+                    .update(File::default(), &id)
+                    .param("fields", ITEM_FIELDS);
+                if size > RESUMABLE_UPLOAD_THRESHOLD {
+                    call.upload_resumable(content, mime_type).await?
+                } else {
+                    call.upload(content, mime_type).await?
+                }
+            }
+            None => {
+                let metadata = File {
+                    name: Some(name),
+                    parents: Some(vec![parent_id]),
+                    ..Default::default()
+                };
+                let call = self.hub
+                    .files()
+                    .create(metadata)
+                    .param("fields", ITEM_FIELDS);
+                if size > RESUMABLE_UPLOAD_THRESHOLD {
+                    call.upload_resumable(content, mime_type).await?
+                } else {
+                    call.upload(content, mime_type).await?
+                }
+            }
+        };
+
+        Ok(Self::to_remote_item(file))
+    }
+
+    async fn download_file(&self, remote_path: &str, local_path: &Path) -> Result<()> {
+        let file_id = self
+            .resolve_item(remote_path)
+            .await?
+            .and_then(|f| f.id)
+            .ok_or_else(|| anyhow!("Remote file not found: {}", remote_path))?;
+
+        let (response, _) = self.hub
             .files()
-            .list()
-            .q(&format!("'{}' in parents", remote_path))
+            .get(&file_id)
+            .param("alt", "media")
             .doit()
             .await?;
 
-        let items = file_list.files.unwrap_or_default()
-            .into_iter()
-            .map(|file| {
-                let modified = file.modified_time
-                    .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
-                    .map(|t| t.with_timezone(&Utc))
-                    .unwrap_or_else(Utc::now);
-
-                RemoteItem {
-                    name: file.name.unwrap_or_default(),
-                    id: file.id.unwrap_or_default(),
-                    size: file.size.unwrap_or_default() as u64,
-                    modified,
-                    is_folder: file.mime_type.unwrap_or_default() == "application/vnd.google-apps.folder",
+        Self::save_body(response, local_path).await
+    }
+
+    async fn create_directory(&self, remote_path: &str) -> Result<RemoteItem> {
+        self.resolve_folder(remote_path, true).await?;
+        self.get_item(remote_path)
+            .await?
+            .ok_or_else(|| anyhow!("Remote folder not found after creation: {}", remote_path))
+    }
+
+    async fn delete(&self, remote_path: &str) -> Result<()> {
+        let file_id = self
+            .resolve_item(remote_path)
+            .await?
+            .and_then(|f| f.id)
+            .ok_or_else(|| anyhow!("Remote item not found: {}", remote_path))?;
+
+        match self.hub.files().delete(&file_id).doit().await {
+            Ok(_) => {}
+            Err(e) if Self::is_not_found(&e) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        self.forget(remote_path).await;
+        Ok(())
+    }
+
+    async fn move_to_trash(&self, remote_path: &str) -> Result<bool> {
+        let file_id = self
+            .resolve_item(remote_path)
+            .await?
+            .and_then(|f| f.id)
+            .ok_or_else(|| anyhow!("Remote item not found: {}", remote_path))?;
+
+        let metadata = File { trashed: Some(true), ..Default::default() };
+        self.hub.files().update(metadata, &file_id).doit_without_upload().await?;
+
+        self.forget(remote_path).await;
+        Ok(true)
+    }
+
+    async fn list_revisions(&self, remote_path: &str) -> Result<Vec<Revision>> {
+        let file_id = self
+            .resolve_item(remote_path)
+            .await?
+            .and_then(|f| f.id)
+            .ok_or_else(|| anyhow!("Remote file not found: {}", remote_path))?;
+        let mut revisions = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut call = self.hub
+                .revisions()
+                .list(&file_id)
+                .param("fields", &format!("nextPageToken,revisions({})", REVISION_FIELDS));
+            if let Some(token) = &page_token {
+                call = call.page_token(token);
+            }
+
+            let (_, revision_list) = call.doit().await?;
+            revisions.extend(revision_list.revisions.unwrap_or_default().into_iter().map(|revision| {
+                let user = revision.last_modifying_user.unwrap_or_default();
+                Revision {
+                    id: revision.id.unwrap_or_default(),
+                    size: revision.size.unwrap_or_default() as u64,
+                    modified: revision.modified_time.unwrap_or(DateTime::<Utc>::UNIX_EPOCH),
+                    modified_by: user.display_name.or(user.email_address),
                 }
-            })
-            .collect();
+            }));
 
-        Ok(items)
+            match revision_list.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => break,
+            }
+        }
+
+        // Drive lists the oldest revision first
+        revisions.reverse();
+        Ok(revisions)
+    }
+
+    async fn download_revision(&self, remote_path: &str, revision_id: &str, local_path: &Path) -> Result<()> {
+        let file = self
+            .resolve_item(remote_path)
+            .await?
+            .ok_or_else(|| anyhow!("Remote file not found: {}", remote_path))?;
+        if Self::is_native(&file) {
+            // Docs, Sheets and Slides only offer their revisions as exports
+            return Err(anyhow!("Revisions of native Google files can only be restored in Drive: {}", remote_path));
+        }
+        let file_id = file.id.unwrap_or_default();
+
+        let (response, _) = self.hub
+            .revisions()
+            .get(&file_id, revision_id)
+            .param("alt", "media")
+            .doit()
+            .await?;
+
+        Self::save_body(response, local_path).await
+    }
+
+    async fn exists(&self, remote_path: &str) -> Result<bool> {
+        Ok(self.resolve_item(remote_path).await?.is_some())
+    }
+
+    async fn get_item(&self, remote_path: &str) -> Result<Option<RemoteItem>> {
+        let file = self.resolve_item(remote_path).await?;
+        Ok(file.filter(|file| !Self::is_native(file)).map(Self::to_remote_item))
+    }
+
+    async fn move_item(&self, from: &str, to: &str) -> Result<RemoteItem> {
+        let from_path = Self::normalize(from);
+        let (old_parent, _) = Self::split_parent(&from_path);
+        let (new_parent, name) = Self::split_parent(&Self::normalize(to));
+
+        let file_id = self
+            .resolve_item(&from_path)
+            .await?
+            .and_then(|f| f.id)
+            .ok_or_else(|| anyhow!("Remote item not found: {}", from))?;
+        let old_parent_id = self
+            .resolve_folder(&old_parent, false)
+            .await?
+            .ok_or_else(|| anyhow!("Remote folder not found: {}", old_parent))?;
+        let new_parent_id = self
+            .resolve_folder(&new_parent, true)
+            .await?
+            .ok_or_else(|| anyhow!("Could not create remote folder: {}", new_parent))?;
+
+        let metadata = File { name: Some(name), ..Default::default() };
+        let mut call = self.hub
+            .files()
+            .update(metadata, &file_id)
+            .param("fields", ITEM_FIELDS);
+        if old_parent_id != new_parent_id {
+            call = call.add_parents(&new_parent_id).remove_parents(&old_parent_id);
+        }
+        let (_, file) = call.doit_without_upload().await?;
+
+        // Cached IDs below the old path are still valid, but under the wrong key now
+        self.forget(&from_path).await;
+        Ok(Self::to_remote_item(file))
+    }
+
+    /// Follows `changes.list`, whose page token is the cursor. Trashed files
+    /// count as deleted.
+    async fn changes_since(&self, remote_path: &str, cursor: Option<&str>) -> Result<Option<ChangeFeed>> {
+        let Some(cursor) = cursor else {
+            let (_, start) = self.hub.changes().get_start_page_token().doit().await?;
+            let cursor = start
+                .start_page_token
+                .ok_or_else(|| anyhow!("Drive returned no start page token"))?;
+            return Ok(Some(ChangeFeed { changes: Vec::new(), cursor, reset: false }));
+        };
+
+        let fields = format!("nextPageToken,newStartPageToken,changes(fileId,removed,file({},parents,trashed))", ITEM_FIELDS);
+        let mut listed = Vec::new();
+        let mut page_token = cursor.to_string();
+        let cursor = loop {
+            let listing = self.hub
+                .changes()
+                .list(&page_token)
+                .include_removed(true)
+                .page_size(1000)
+                .param("fields", &fields)
+                .doit()
+                .await;
+            let page = match listing {
+                Ok((_, page)) => page,
+                // The token expired, start over from now
+                Err(e) if Self::is_rejected_token(&e) => {
+                    let feed = self.changes_since(remote_path, None).await?;
+                    return Ok(feed.map(|feed| ChangeFeed { reset: true, ..feed }));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            listed.extend(page.changes.unwrap_or_default());
+
+            match (page.next_page_token, page.new_start_page_token) {
+                (Some(next), _) => page_token = next,
+                (None, Some(new_start)) => break new_start,
+                (None, None) => return Err(anyhow!("Drive returned no page token to continue from")),
+            }
+        };
+
+        // A folder that was renamed or moved leaves wrong paths behind in the cache
+        let is_folder = |file: &File| file.mime_type.as_deref() == Some(FOLDER_MIME_TYPE);
+        if listed.iter().any(|change| change.file.as_ref().is_some_and(is_folder)) {
+            self.folder_ids.write().await.retain(|key, _| key == "/");
+        }
+
+        let root_path = Self::normalize(remote_path);
+        let root_id = self.resolve_folder(&root_path, false).await?;
+        let mut folders = HashMap::new();
+        let mut changes = Vec::with_capacity(listed.len());
+        for change in listed {
+            // Changes to shared drives themselves have no file
+            let Some(id) = change.file_id else { continue };
+            if change.file.as_ref().is_some_and(Self::is_native) {
+                continue;
+            }
+            let path = match (&change.file, &root_id) {
+                (Some(file), Some(root_id)) => self.path_below(file, root_id, &root_path, &mut folders).await?,
+                _ => None,
+            };
+            let gone = change.removed.unwrap_or(false) || change.file.as_ref().is_some_and(|file| file.trashed == Some(true));
+            let item = change.file.filter(|_| !gone).map(Self::to_remote_item);
+            changes.push(RemoteChange { id, path, item });
+        }
+
+        Ok(Some(ChangeFeed { changes, cursor, reset: false }))
+    }
+
+    async fn get_mappings(&self) -> Vec<FolderMapping> {
+        self.mappings.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use google_drive3::client::NoToken;
+    use wiremock::{
+        matchers::{body_json, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn provider(server: &MockServer) -> GoogleDriveProvider {
+        GoogleDriveProvider::with_auth(NoToken, Vec::new())
+            .unwrap()
+            .with_base_url(&server.uri())
     }
 
-    async fn upload_file(&self, _local_path: &Path, _remote_path: &str) -> Result<RemoteItem> {
-        // TODO: Implement file upload
-        unimplemented!()
+    async fn mock_children(server: &MockServer, query: &str, files: serde_json::Value) {
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/files"))
+            .and(query_param("q", query))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "files": files
+            })))
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_list_files_resolves_and_caches_path() -> Result<()> {
+        let server = MockServer::start().await;
+        mock_children(
+            &server,
+            "name = 'docs' and 'root' in parents and trashed = false",
+            serde_json::json!([{ "id": "docs-id", "name": "docs", "mimeType": FOLDER_MIME_TYPE }]),
+        )
+        .await;
+        mock_children(
+            &server,
+            "name = 'reports' and 'docs-id' in parents and trashed = false",
+            serde_json::json!([{ "id": "reports-id", "name": "reports", "mimeType": FOLDER_MIME_TYPE }]),
+        )
+        .await;
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/files"))
+            .and(query_param("q", "'reports-id' in parents and trashed = false"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "files": [
+                    { "id": "a", "name": "q1.pdf", "mimeType": "application/pdf", "size": "42" },
+                    { "id": "b", "name": "drafts", "mimeType": FOLDER_MIME_TYPE },
+                    { "id": "c", "name": "notes", "mimeType": "application/vnd.google-apps.document" }
+                ]
+            })))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let provider = provider(&server);
+        for _ in 0..2 {
+            let files = provider.list_files("/docs/reports/").await?;
+            assert_eq!(files.len(), 2);
+            assert_eq!(files[0].name, "q1.pdf");
+            assert_eq!(files[0].size, 42);
+            assert!(!files[0].is_folder);
+            assert!(files[1].is_folder);
+        }
+
+        Ok(())
     }
 
-    async fn download_file(&self, _remote_path: &str, _local_path: &Path) -> Result<()> {
-        // TODO: Implement file download
-        unimplemented!()
+    #[tokio::test]
+    async fn test_duplicate_names_prefer_folder() -> Result<()> {
+        let server = MockServer::start().await;
+        mock_children(
+            &server,
+            "name = 'docs' and 'root' in parents and trashed = false",
+            serde_json::json!([
+                { "id": "file-id", "name": "docs", "mimeType": "text/plain" },
+                { "id": "old-folder", "name": "docs", "mimeType": FOLDER_MIME_TYPE },
+                { "id": "new-folder", "name": "docs", "mimeType": FOLDER_MIME_TYPE }
+            ]),
+        )
+        .await;
+
+        let provider = provider(&server);
+        assert_eq!(provider.resolve_folder("/docs", false).await?, Some("old-folder".to_string()));
+
+        Ok(())
     }
 
-    async fn create_directory(&self, _remote_path: &str) -> Result<RemoteItem> {
-        // TODO: Implement directory creation
-        unimplemented!()
+    #[tokio::test]
+    async fn test_missing_item() -> Result<()> {
+        let server = MockServer::start().await;
+        mock_children(
+            &server,
+            "name = 'it\\'s' and 'root' in parents and trashed = false",
+            serde_json::json!([]),
+        )
+        .await;
+
+        let provider = provider(&server);
+        assert!(!provider.exists("/it's").await?);
+
+        Ok(())
     }
 
-    async fn delete(&self, _remote_path: &str) -> Result<()> {
-        // TODO: Implement deletion
-        unimplemented!()
+    #[tokio::test]
+    async fn test_upload_updates_file_next_to_same_name_folder() -> Result<()> {
+        let server = MockServer::start().await;
+        mock_children(
+            &server,
+            "name = 'notes' and 'root' in parents and mimeType != 'application/vnd.google-apps.folder' and trashed = false",
+            serde_json::json!([{ "id": "file-id", "name": "notes", "mimeType": "text/plain" }]),
+        )
+        .await;
+        Mock::given(method("PATCH"))
+            .and(path("/upload/drive/v3/files/file-id"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "id": "file-id", "name": "notes" })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir()?;
+        let local = dir.path().join("notes");
+        std::fs::write(&local, "hello")?;
+        let item = provider(&server).upload_file(&local, "/notes").await?;
+        assert_eq!(item.id, "file-id");
+
+        Ok(())
     }
 
-    async fn exists(&self, _remote_path: &str) -> Result<bool> {
-        // TODO: Implement existence check
-        unimplemented!()
+    #[tokio::test]
+    async fn test_move_to_trash_sets_trashed() -> Result<()> {
+        let server = MockServer::start().await;
+        mock_children(
+            &server,
+            "name = 'old.txt' and 'root' in parents and trashed = false",
+            serde_json::json!([{ "id": "old-id", "name": "old.txt", "mimeType": "text/plain" }]),
+        )
+        .await;
+        Mock::given(method("PATCH"))
+            .and(path("/drive/v3/files/old-id"))
+            .and(body_json(serde_json::json!({ "trashed": true })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "id": "old-id", "trashed": true })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let provider = provider(&server);
+        assert!(provider.move_to_trash("/old.txt").await?);
+
+        Ok(())
     }
 
-    async fn get_item(&self, _remote_path: &str) -> Result<Option<RemoteItem>> {
-        // TODO: Implement item retrieval
-        unimplemented!()
+    #[tokio::test]
+    async fn test_revisions_newest_first() -> Result<()> {
+        let server = MockServer::start().await;
+        mock_children(
+            &server,
+            "name = 'budget.xlsx' and 'root' in parents and trashed = false",
+            serde_json::json!([{ "id": "budget-id", "name": "budget.xlsx", "mimeType": "application/vnd.ms-excel" }]),
+        )
+        .await;
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/files/budget-id/revisions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "revisions": [
+                    { "id": "r1", "size": "10", "modifiedTime": "2024-05-01T10:00:00Z" },
+                    { "id": "r2", "size": "12", "modifiedTime": "2024-05-02T10:00:00Z",
+                      "lastModifyingUser": { "displayName": "Ana" } }
+                ]
+            })))
+            .mount(&server)
+            .await;
+
+        let provider = provider(&server);
+        let revisions = provider.list_revisions("/budget.xlsx").await?;
+        let ids: Vec<_> = revisions.iter().map(|r| (r.id.as_str(), r.size, r.modified_by.as_deref())).collect();
+        assert_eq!(ids, [("r2", 12, Some("Ana")), ("r1", 10, None)]);
+
+        Ok(())
     }
 
-    async fn watch_local_changes(&self, _local_path: &Path, _tx: mpsc::Sender<ChangeType>) -> Result<()> {
-        // TODO: Implement
+    #[tokio::test]
+    async fn test_download_revision() -> Result<()> {
+        let server = MockServer::start().await;
+        mock_children(
+            &server,
+            "name = 'budget.xlsx' and 'root' in parents and trashed = false",
+            serde_json::json!([{ "id": "budget-id", "name": "budget.xlsx", "mimeType": "application/vnd.ms-excel" }]),
+        )
+        .await;
+        mock_children(
+            &server,
+            "name = 'plan' and 'root' in parents and trashed = false",
+            serde_json::json!([{ "id": "plan-id", "name": "plan", "mimeType": "application/vnd.google-apps.spreadsheet" }]),
+        )
+        .await;
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/files/budget-id/revisions/r1"))
+            .and(query_param("alt", "media"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"old budget".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let provider = provider(&server);
+        let dir = tempfile::tempdir()?;
+        let target = dir.path().join("budget.xlsx");
+        provider.download_revision("/budget.xlsx", "r1", &target).await?;
+        assert_eq!(fs::read(&target).await?, b"old budget");
+
+        // Native Google files have no content to download
+        assert!(provider.download_revision("/plan", "r1", &dir.path().join("plan")).await.is_err());
+
         Ok(())
     }
 
-    async fn watch_remote_changes(&self, _remote_path: &str, _tx: mpsc::Sender<RemoteItem>) -> Result<()> {
-        // TODO: Implement
+    #[tokio::test]
+    async fn test_change_feed() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/changes/startPageToken"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "startPageToken": "100" })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/changes"))
+            .and(query_param("pageToken", "100"))
+            .and(query_param("includeRemoved", "true"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "nextPageToken": "101",
+                "changes": [
+                    { "fileId": "a-id", "removed": false,
+                      "file": { "id": "a-id", "name": "a.txt", "mimeType": "text/plain", "size": "3", "parents": ["docs-id"] } },
+                    { "fileId": "gone-id", "removed": true }
+                ]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/changes"))
+            .and(query_param("pageToken", "101"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "newStartPageToken": "105",
+                "changes": [
+                    { "fileId": "q1-id",
+                      "file": { "id": "q1-id", "name": "q1.pdf", "mimeType": "application/pdf", "parents": ["reports-id"] } },
+                    { "fileId": "old-id",
+                      "file": { "id": "old-id", "name": "old.txt", "mimeType": "text/plain", "parents": ["docs-id"], "trashed": true } },
+                    { "fileId": "x-id",
+                      "file": { "id": "x-id", "name": "x.txt", "mimeType": "text/plain", "parents": ["other-id"] } }
+                ]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        mock_children(
+            &server,
+            "name = 'docs' and 'root' in parents and trashed = false",
+            serde_json::json!([{ "id": "docs-id", "name": "docs", "mimeType": FOLDER_MIME_TYPE }]),
+        )
+        .await;
+        for (id, name, parents) in [
+            ("reports-id", "reports", serde_json::json!(["docs-id"])),
+            ("other-id", "other", serde_json::json!(["top-id"])),
+            ("top-id", "My Drive", serde_json::json!(null)),
+        ] {
+            Mock::given(method("GET"))
+                .and(path(format!("/drive/v3/files/{}", id)))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "id": id, "name": name, "mimeType": FOLDER_MIME_TYPE, "parents": parents
+                })))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let provider = provider(&server);
+        let start = provider.changes_since("/docs", None).await?.unwrap();
+        assert!(start.changes.is_empty());
+        assert_eq!(start.cursor, "100");
+
+        let feed = provider.changes_since("/docs", Some(&start.cursor)).await?.unwrap();
+        assert_eq!(feed.cursor, "105");
+        let changes: Vec<_> = feed
+            .changes
+            .iter()
+            .map(|change| (change.id.as_str(), change.path.as_deref(), change.item.is_some()))
+            .collect();
+        assert_eq!(changes, [
+            ("a-id", Some("/docs/a.txt"), true),
+            ("gone-id", None, false),
+            ("q1-id", Some("/docs/reports/q1.pdf"), true),
+            ("old-id", Some("/docs/old.txt"), false),
+            ("x-id", None, true),
+        ]);
+
         Ok(())
     }
 
-    async fn get_mappings(&self) -> Vec<FolderMapping> {
-        self.mappings.clone()
+    #[tokio::test]
+    async fn test_change_feed_restarts_after_rejected_token() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/changes"))
+            .and(query_param("pageToken", "7"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "error": { "code": 400, "message": "Invalid Value" }
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/changes/startPageToken"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "startPageToken": "100" })))
+            .mount(&server)
+            .await;
+
+        let feed = provider(&server).changes_since("/docs", Some("7")).await?.unwrap();
+        assert!(feed.reset);
+        assert!(feed.changes.is_empty());
+        assert_eq!(feed.cursor, "100");
+
+        Ok(())
     }
-} 
\ No newline at end of file
+}
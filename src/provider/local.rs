@@ -0,0 +1,210 @@
+use std::path::{Component, Path, PathBuf};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::fs;
+
+use super::{CloudProvider, RemoteItem, FolderMapping};
+
+/// Treats a local directory (a NAS mount, a USB disk, ...) as the remote side
+pub struct LocalProvider {
+    root: PathBuf,
+    mappings: Vec<FolderMapping>,
+}
+
+impl LocalProvider {
+    pub fn new(root: PathBuf, mappings: Vec<FolderMapping>) -> Self {
+        Self { root, mappings }
+    }
+
+    /// Map a remote path onto the root directory, refusing to escape it
+    fn resolve(&self, remote_path: &str) -> Result<PathBuf> {
+        let relative = Path::new(remote_path.trim_start_matches('/'));
+        let mut resolved = self.root.clone();
+
+        for component in relative.components() {
+            match component {
+                Component::Normal(segment) => resolved.push(segment),
+                Component::CurDir => {}
+                _ => return Err(anyhow!("Invalid remote path: {}", remote_path)),
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    fn remote_id(remote_path: &str) -> String {
+        let segments: Vec<&str> = remote_path.split('/').filter(|s| !s.is_empty()).collect();
+        format!("/{}", segments.join("/"))
+    }
+
+    async fn to_remote_item(path: &Path, remote_path: &str) -> Result<RemoteItem> {
+        let metadata = fs::metadata(path).await?;
+        let modified: DateTime<Utc> = metadata.modified()?.into();
+        let size = if metadata.is_dir() { 0 } else { metadata.len() };
+
+        Ok(RemoteItem {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            id: Self::remote_id(remote_path),
+            size,
+            modified,
+            is_folder: metadata.is_dir(),
+            etag: Some(format!("{:x}-{:x}", modified.timestamp_nanos_opt().unwrap_or_default(), size)),
+        })
+    }
+}
+
+#[async_trait]
+impl CloudProvider for LocalProvider {
+    async fn initialize(&mut self) -> Result<()> {
+        if !fs::metadata(&self.root).await?.is_dir() {
+            return Err(anyhow!("Local provider root is not a directory: {:?}", self.root));
+        }
+        Ok(())
+    }
+
+    async fn list_files(&self, remote_path: &str) -> Result<Vec<RemoteItem>> {
+        let dir = self.resolve(remote_path)?;
+        let mut entries = fs::read_dir(&dir).await?;
+        let mut items = Vec::new();
+
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let child = format!("{}/{}", remote_path.trim_end_matches('/'), name);
+            items.push(Self::to_remote_item(&entry.path(), &child).await?);
+        }
+        items.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(items)
+    }
+
+    async fn upload_file(&self, local_path: &Path, remote_path: &str) -> Result<RemoteItem> {
+        let target = self.resolve(remote_path)?;
+        let parent = target
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid remote path: {}", remote_path))?;
+        fs::create_dir_all(parent).await?;
+
+        // Copy next to the target first so readers never see a half-written file
+        let file_name = target
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid remote path: {}", remote_path))?;
+        let partial = parent.join(format!(".{}.partial", file_name.to_string_lossy()));
+        fs::copy(local_path, &partial).await?;
+        fs::rename(&partial, &target).await?;
+
+        Self::to_remote_item(&target, remote_path).await
+    }
+
+    async fn download_file(&self, remote_path: &str, local_path: &Path) -> Result<()> {
+        let source = self.resolve(remote_path)?;
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::copy(&source, local_path).await?;
+        Ok(())
+    }
+
+    async fn create_directory(&self, remote_path: &str) -> Result<RemoteItem> {
+        let dir = self.resolve(remote_path)?;
+        fs::create_dir_all(&dir).await?;
+        Self::to_remote_item(&dir, remote_path).await
+    }
+
+    async fn delete(&self, remote_path: &str) -> Result<()> {
+        let target = self.resolve(remote_path)?;
+        if target == self.root {
+            return Err(anyhow!("Refusing to delete the provider root"));
+        }
+
+        if fs::metadata(&target).await?.is_dir() {
+            fs::remove_dir_all(&target).await?;
+        } else {
+            fs::remove_file(&target).await?;
+        }
+        Ok(())
+    }
+
+    async fn exists(&self, remote_path: &str) -> Result<bool> {
+        Ok(fs::try_exists(self.resolve(remote_path)?).await?)
+    }
+
+    async fn get_item(&self, remote_path: &str) -> Result<Option<RemoteItem>> {
+        let target = self.resolve(remote_path)?;
+        if !fs::try_exists(&target).await? {
+            return Ok(None);
+        }
+        Ok(Some(Self::to_remote_item(&target, remote_path).await?))
+    }
+
+    async fn move_item(&self, from: &str, to: &str) -> Result<RemoteItem> {
+        let source = self.resolve(from)?;
+        let target = self.resolve(to)?;
+        if source == self.root {
+            return Err(anyhow!("Refusing to move the provider root"));
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&source, &target).await?;
+        Self::to_remote_item(&target, to).await
+    }
+
+    async fn get_mappings(&self) -> Vec<FolderMapping> {
+        self.mappings.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_local_provider_roundtrip() -> Result<()> {
+        let remote = tempdir()?;
+        let local = tempdir()?;
+        let provider = LocalProvider::new(remote.path().to_path_buf(), Vec::new());
+
+        let source = local.path().join("report.txt");
+        fs::write(&source, b"quarterly numbers").await?;
+
+        let uploaded = provider.upload_file(&source, "/docs/2024/report.txt").await?;
+        assert_eq!(uploaded.name, "report.txt");
+        assert_eq!(uploaded.id, "/docs/2024/report.txt");
+        assert_eq!(uploaded.size, 17);
+        assert!(provider.exists("/docs/2024/report.txt").await?);
+
+        let listed = provider.list_files("/docs").await?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].name, "2024");
+        assert!(listed[0].is_folder);
+
+        let target = local.path().join("restored/report.txt");
+        provider.download_file("/docs/2024/report.txt", &target).await?;
+        assert_eq!(fs::read(&target).await?, b"quarterly numbers");
+
+        let moved = provider.move_item("/docs/2024", "/archive/2024").await?;
+        assert_eq!(moved.id, "/archive/2024");
+        assert!(provider.exists("/archive/2024/report.txt").await?);
+
+        provider.delete("/archive").await?;
+        assert!(provider.get_item("/archive/2024/report.txt").await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejects_paths_outside_root() -> Result<()> {
+        let remote = tempdir()?;
+        let provider = LocalProvider::new(remote.path().to_path_buf(), Vec::new());
+
+        assert!(provider.exists("/../etc/passwd").await.is_err());
+        assert!(provider.delete("/").await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,496 @@
+//! In-memory provider for tests, enabled with the `memory-provider` feature.
+//!
+//! Files live in a tree behind a shared handle, so a test can keep a clone of
+//! the provider after handing it to a `SyncOperation`, script failures and
+//! latency, mutate the "remote" side as another device would, and inspect
+//! the calls that were made.
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::fs;
+
+use super::{ChangeFeed, CloudProvider, RemoteChange, RemoteItem, Revision, FolderMapping};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    List,
+    Upload,
+    Download,
+    CreateDirectory,
+    Delete,
+    Exists,
+    GetItem,
+    Move,
+    ListRevisions,
+    DownloadRevision,
+    ListChanges,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    content: Option<Vec<u8>>,
+    modified: DateTime<Utc>,
+    version: u64,
+    /// Earlier contents of a file, newest first, kept as revisions
+    history: Vec<Node>,
+}
+
+impl Node {
+    fn is_folder(&self) -> bool {
+        self.content.is_none()
+    }
+
+    fn revision(&self) -> Revision {
+        Revision {
+            id: self.version.to_string(),
+            size: self.content.as_ref().map_or(0, |content| content.len() as u64),
+            modified: self.modified,
+            modified_by: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    // Keyed by normalized path; the root "/" is implicit
+    nodes: BTreeMap<String, Node>,
+    next_version: u64,
+    failures: HashMap<Operation, usize>,
+    interrupted_downloads: usize,
+    latency: Duration,
+    calls: Vec<(Operation, String)>,
+    /// Changes made as another device would, read by `changes_since`
+    changes: Vec<RemoteChange>,
+}
+
+impl State {
+    fn is_folder(&self, path: &str) -> bool {
+        path == "/" || self.nodes.get(path).is_some_and(Node::is_folder)
+    }
+
+    fn item(&self, path: &str) -> Option<RemoteItem> {
+        if path == "/" {
+            return Some(RemoteItem {
+                name: String::new(),
+                id: "/".to_string(),
+                size: 0,
+                modified: DateTime::<Utc>::UNIX_EPOCH,
+                is_folder: true,
+                etag: None,
+            });
+        }
+
+        let node = self.nodes.get(path)?;
+        Some(RemoteItem {
+            name: path.rsplit('/').next().unwrap_or_default().to_string(),
+            id: path.to_string(),
+            size: node.content.as_ref().map_or(0, |content| content.len() as u64),
+            modified: node.modified,
+            is_folder: node.is_folder(),
+            etag: Some(node.version.to_string()),
+        })
+    }
+
+    fn write(&mut self, path: &str, content: Option<Vec<u8>>) -> Result<RemoteItem> {
+        if path == "/" {
+            return Err(anyhow!("Cannot overwrite the provider root"));
+        }
+
+        // Parents are created implicitly, like the other backends do on upload
+        let mut current = String::new();
+        for segment in parent(path).split('/').filter(|s| !s.is_empty()) {
+            current.push('/');
+            current.push_str(segment);
+            match self.nodes.get(&current) {
+                Some(node) if !node.is_folder() => {
+                    return Err(anyhow!("Remote path {} exists and is not a directory", current))
+                }
+                Some(_) => {}
+                None => {
+                    let node = self.node(None);
+                    self.nodes.insert(current.clone(), node);
+                }
+            }
+        }
+
+        match (self.nodes.get(path), &content) {
+            (Some(existing), None) if existing.is_folder() => {}
+            (Some(existing), _) if existing.is_folder() != content.is_none() => {
+                return Err(anyhow!("Remote path {} already exists with a different type", path));
+            }
+            _ => {
+                let mut node = self.node(content);
+                if let Some(mut previous) = self.nodes.remove(path).filter(|previous| !previous.is_folder()) {
+                    node.history = std::mem::take(&mut previous.history);
+                    node.history.insert(0, previous);
+                }
+                self.nodes.insert(path.to_string(), node);
+            }
+        }
+
+        Ok(self.item(path).expect("node was just written"))
+    }
+
+    fn node(&mut self, content: Option<Vec<u8>>) -> Node {
+        self.next_version += 1;
+        Node { content, modified: Utc::now(), version: self.next_version, history: Vec::new() }
+    }
+
+    fn remove(&mut self, path: &str) -> Result<()> {
+        if path == "/" {
+            return Err(anyhow!("Refusing to delete the provider root"));
+        }
+        if self.nodes.remove(path).is_none() {
+            return Err(anyhow!("Remote path not found: {}", path));
+        }
+        let prefix = format!("{}/", path);
+        self.nodes.retain(|key, _| !key.starts_with(&prefix));
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<RemoteItem> {
+        if from == "/" || is_within(to, from) {
+            return Err(anyhow!("Cannot move {} into {}", from, to));
+        }
+        if !self.nodes.contains_key(from) {
+            return Err(anyhow!("Remote path not found: {}", from));
+        }
+        if self.nodes.contains_key(to) {
+            return Err(anyhow!("Remote path already exists: {}", to));
+        }
+
+        // Create the target's parents, then re-key the whole subtree
+        let parent_path = parent(to).to_string();
+        if parent_path != "/" {
+            self.write(&parent_path, None)?;
+        }
+        let moved: Vec<String> = self.nodes.keys().filter(|key| is_within(key, from)).cloned().collect();
+        for key in moved {
+            let node = self.nodes.remove(&key).expect("key was just listed");
+            self.nodes.insert(format!("{}{}", to, &key[from.len()..]), node);
+        }
+
+        Ok(self.item(to).expect("node was just moved"))
+    }
+
+    /// Log a change to `path` for the change feed, `item` is `None` for deletions
+    fn record(&mut self, path: &str, item: Option<RemoteItem>) {
+        self.changes.push(RemoteChange { id: path.to_string(), path: Some(path.to_string()), item });
+    }
+}
+
+fn normalize(remote_path: &str) -> String {
+    let segments: Vec<&str> = remote_path.split('/').filter(|s| !s.is_empty() && *s != ".").collect();
+    format!("/{}", segments.join("/"))
+}
+
+fn parent(path: &str) -> &str {
+    match path.rsplit_once('/') {
+        Some(("", _)) | None => "/",
+        Some((parent, _)) => parent,
+    }
+}
+
+fn is_within(path: &str, root: &str) -> bool {
+    root == "/" || path == root || path.starts_with(&format!("{}/", root))
+}
+
+/// A `CloudProvider` that keeps everything in memory. Clones share the same tree.
+#[derive(Clone, Default)]
+pub struct MemoryProvider {
+    state: Arc<Mutex<State>>,
+    mappings: Vec<FolderMapping>,
+}
+
+impl MemoryProvider {
+    pub fn new(mappings: Vec<FolderMapping>) -> Self {
+        Self { state: Arc::default(), mappings }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Make the next `times` calls of `operation` fail
+    pub fn fail_next(&self, operation: Operation, times: usize) {
+        *self.state().failures.entry(operation).or_default() += times;
+    }
+
+    /// Make the next `times` downloads break off halfway, leaving the first
+    /// half of the file behind like a dropped connection would
+    pub fn interrupt_next_download(&self, times: usize) {
+        self.state().interrupted_downloads += times;
+    }
+
+    /// Delay every call by `latency` before it touches the tree
+    pub fn set_latency(&self, latency: Duration) {
+        self.state().latency = latency;
+    }
+
+    /// Every call made so far, in order, with the normalized path it targeted
+    pub fn calls(&self) -> Vec<(Operation, String)> {
+        self.state().calls.clone()
+    }
+
+    /// Content of a file, or `None` for folders and missing paths
+    pub fn read(&self, remote_path: &str) -> Option<Vec<u8>> {
+        self.state().nodes.get(&normalize(remote_path))?.content.clone()
+    }
+
+    /// All stored paths, sorted
+    pub fn paths(&self) -> Vec<String> {
+        self.state().nodes.keys().cloned().collect()
+    }
+
+    /// Write a file as another device would, which shows up in the change feed
+    pub fn put_file(&self, remote_path: &str, content: impl Into<Vec<u8>>) -> Result<RemoteItem> {
+        let path = normalize(remote_path);
+        let mut state = self.state();
+        let item = state.write(&path, Some(content.into()))?;
+        state.record(&path, Some(item.clone()));
+        Ok(item)
+    }
+
+    /// Create a folder as another device would, which shows up in the change feed
+    pub fn put_folder(&self, remote_path: &str) -> Result<RemoteItem> {
+        let path = normalize(remote_path);
+        let mut state = self.state();
+        let item = state.write(&path, None)?;
+        state.record(&path, Some(item.clone()));
+        Ok(item)
+    }
+
+    /// Delete a subtree as another device would, which shows up in the change feed
+    pub fn remove(&self, remote_path: &str) -> Result<()> {
+        let path = normalize(remote_path);
+        let mut state = self.state();
+        state.remove(&path)?;
+        state.record(&path, None);
+        Ok(())
+    }
+
+    /// Record the call, apply latency and consume a scripted failure if one is pending
+    async fn enter(&self, operation: Operation, remote_path: &str) -> Result<String> {
+        let path = normalize(remote_path);
+        let latency = {
+            let mut state = self.state();
+            state.calls.push((operation, path.clone()));
+            state.latency
+        };
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        let mut state = self.state();
+        if let Some(remaining) = state.failures.get_mut(&operation).filter(|remaining| **remaining > 0) {
+            *remaining -= 1;
+            return Err(anyhow!("Scripted {:?} failure for {}", operation, path));
+        }
+        Ok(path)
+    }
+}
+
+#[async_trait]
+impl CloudProvider for MemoryProvider {
+    async fn initialize(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn list_files(&self, remote_path: &str) -> Result<Vec<RemoteItem>> {
+        let path = self.enter(Operation::List, remote_path).await?;
+        let state = self.state();
+        if !state.is_folder(&path) {
+            return Err(anyhow!("Remote folder not found: {}", path));
+        }
+
+        Ok(state
+            .nodes
+            .keys()
+            .filter(|key| key.as_str() != path && parent(key) == path)
+            .filter_map(|key| state.item(key))
+            .collect())
+    }
+
+    async fn upload_file(&self, local_path: &Path, remote_path: &str) -> Result<RemoteItem> {
+        let path = self.enter(Operation::Upload, remote_path).await?;
+        let content = fs::read(local_path).await?;
+        self.state().write(&path, Some(content))
+    }
+
+    async fn download_file(&self, remote_path: &str, local_path: &Path) -> Result<()> {
+        let path = self.enter(Operation::Download, remote_path).await?;
+        let (content, interrupted) = {
+            let mut state = self.state();
+            let content = state
+                .nodes
+                .get(&path)
+                .and_then(|node| node.content.clone())
+                .ok_or_else(|| anyhow!("Remote file not found: {}", path))?;
+            let interrupted = state.interrupted_downloads > 0;
+            if interrupted {
+                state.interrupted_downloads -= 1;
+            }
+            (content, interrupted)
+        };
+
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        if interrupted {
+            fs::write(local_path, &content[..content.len() / 2]).await?;
+            return Err(anyhow!("Scripted interruption of the download of {}", path));
+        }
+        fs::write(local_path, content).await?;
+        Ok(())
+    }
+
+    async fn create_directory(&self, remote_path: &str) -> Result<RemoteItem> {
+        let path = self.enter(Operation::CreateDirectory, remote_path).await?;
+        let mut state = self.state();
+        if path == "/" {
+            return Ok(state.item("/").expect("root always exists"));
+        }
+        state.write(&path, None)
+    }
+
+    async fn delete(&self, remote_path: &str) -> Result<()> {
+        let path = self.enter(Operation::Delete, remote_path).await?;
+        self.state().remove(&path)
+    }
+
+    async fn exists(&self, remote_path: &str) -> Result<bool> {
+        let path = self.enter(Operation::Exists, remote_path).await?;
+        Ok(self.state().item(&path).is_some())
+    }
+
+    async fn get_item(&self, remote_path: &str) -> Result<Option<RemoteItem>> {
+        let path = self.enter(Operation::GetItem, remote_path).await?;
+        Ok(self.state().item(&path))
+    }
+
+    async fn move_item(&self, from: &str, to: &str) -> Result<RemoteItem> {
+        let from = self.enter(Operation::Move, from).await?;
+        self.state().rename(&from, &normalize(to))
+    }
+
+    /// Every write of a file is a revision, the current one first
+    async fn list_revisions(&self, remote_path: &str) -> Result<Vec<Revision>> {
+        let path = self.enter(Operation::ListRevisions, remote_path).await?;
+        let state = self.state();
+        let node = state
+            .nodes
+            .get(&path)
+            .filter(|node| !node.is_folder())
+            .ok_or_else(|| anyhow!("Remote file not found: {}", path))?;
+
+        Ok(std::iter::once(node).chain(&node.history).map(Node::revision).collect())
+    }
+
+    async fn download_revision(&self, remote_path: &str, revision_id: &str, local_path: &Path) -> Result<()> {
+        let path = self.enter(Operation::DownloadRevision, remote_path).await?;
+        let content = {
+            let state = self.state();
+            let node = state.nodes.get(&path).ok_or_else(|| anyhow!("Remote file not found: {}", path))?;
+            std::iter::once(node)
+                .chain(&node.history)
+                .find(|revision| revision.version.to_string() == revision_id)
+                .and_then(|revision| revision.content.clone())
+                .ok_or_else(|| anyhow!("Revision {} of {} not found", revision_id, path))?
+        };
+
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(local_path, content).await?;
+        Ok(())
+    }
+
+    /// Changes made through `put_file`, `put_folder` and `remove`. The cursor
+    /// is a position in that log; one past its end counts as expired.
+    async fn changes_since(&self, remote_path: &str, cursor: Option<&str>) -> Result<Option<ChangeFeed>> {
+        let root = self.enter(Operation::ListChanges, remote_path).await?;
+        let state = self.state();
+        let end = state.changes.len();
+        let start = match cursor {
+            Some(cursor) => cursor.parse::<usize>().map_err(|_| anyhow!("Invalid change cursor {:?}", cursor))?,
+            None => end,
+        };
+
+        let changes = state
+            .changes
+            .get(start..)
+            .unwrap_or_default()
+            .iter()
+            .filter(|change| change.path.as_deref().is_some_and(|path| is_within(path, &root)))
+            .cloned()
+            .collect();
+        Ok(Some(ChangeFeed { changes, cursor: end.to_string(), reset: start > end }))
+    }
+
+    async fn get_mappings(&self) -> Vec<FolderMapping> {
+        self.mappings.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_memory_provider_tree() -> Result<()> {
+        let provider = MemoryProvider::new(Vec::new());
+        let local = tempdir()?;
+        let source = local.path().join("a.txt");
+        fs::write(&source, b"hello").await?;
+
+        let uploaded = provider.upload_file(&source, "/docs/2024/a.txt").await?;
+        assert_eq!(uploaded.id, "/docs/2024/a.txt");
+        assert_eq!(uploaded.size, 5);
+        assert_eq!(provider.paths(), ["/docs", "/docs/2024", "/docs/2024/a.txt"]);
+
+        let listed = provider.list_files("/docs").await?;
+        assert_eq!(listed.len(), 1);
+        assert!(listed[0].is_folder);
+
+        let again = provider.upload_file(&source, "/docs/2024/a.txt").await?;
+        assert_ne!(again.etag, uploaded.etag);
+
+        let target = local.path().join("out/a.txt");
+        provider.download_file("/docs/2024/a.txt", &target).await?;
+        assert_eq!(fs::read(&target).await?, b"hello");
+
+        assert!(provider.upload_file(&source, "/docs/2024/a.txt/nested").await.is_err());
+        provider.delete("/docs").await?;
+        assert!(provider.paths().is_empty());
+        assert!(provider.delete("/").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scripted_failures_and_remote_events() -> Result<()> {
+        let provider = MemoryProvider::new(Vec::new());
+        provider.fail_next(Operation::GetItem, 1);
+        assert!(provider.get_item("/x").await.is_err());
+        assert!(provider.get_item("/x").await?.is_none());
+        assert_eq!(provider.calls(), [
+            (Operation::GetItem, "/x".to_string()),
+            (Operation::GetItem, "/x".to_string()),
+        ]);
+
+        let start = provider.changes_since("/shared", None).await?.unwrap().cursor;
+        provider.put_file("/private/b.txt", "not watched")?;
+        provider.put_file("/shared/c.txt", "watched")?;
+        let feed = provider.changes_since("/shared", Some(&start)).await?.unwrap();
+        assert_eq!(feed.changes.iter().map(|change| change.id.as_str()).collect::<Vec<_>>(), ["/shared/c.txt"]);
+
+        Ok(())
+    }
+}
@@ -1,12 +1,17 @@
 use std::path::Path;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use tokio::sync::mpsc;
 
 pub mod factory;
 pub mod google_drive;
+pub mod local;
+#[cfg(any(test, feature = "memory-provider"))]
+pub mod memory;
 pub mod onedrive;
+pub mod s3;
+pub mod sftp;
+pub mod webdav;
 
 #[derive(Debug, Clone)]
 pub struct RemoteItem {
@@ -15,13 +20,39 @@ pub struct RemoteItem {
     pub size: u64,
     pub modified: DateTime<Utc>,
     pub is_folder: bool,
+    /// Opaque version tag that changes whenever the content changes, if the backend has one
+    pub etag: Option<String>,
 }
 
+/// A past version of a remote file, kept by the provider itself
 #[derive(Debug, Clone)]
-pub enum ChangeType {
-    Created(std::path::PathBuf),
-    Modified(std::path::PathBuf),
-    Deleted(std::path::PathBuf),
+pub struct Revision {
+    pub id: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    /// Who saved this revision, if the provider says
+    pub modified_by: Option<String>,
+}
+
+/// One entry of a provider's change feed
+#[derive(Debug, Clone)]
+pub struct RemoteChange {
+    /// The changed item's id, which finds it in the sync state when it is gone
+    pub id: String,
+    /// Full remote path of the item, if the provider could tell
+    pub path: Option<String>,
+    /// The item as it is now, `None` if it was deleted
+    pub item: Option<RemoteItem>,
+}
+
+/// What changed since a cursor, with the cursor to continue from
+#[derive(Debug, Clone)]
+pub struct ChangeFeed {
+    pub changes: Vec<RemoteChange>,
+    pub cursor: String,
+    /// The old cursor was no longer accepted and changes may have been lost,
+    /// so everything needs to be compared again
+    pub reset: bool,
 }
 
 pub use crate::config::FolderMapping;
@@ -52,7 +83,58 @@ pub trait CloudProvider: Send + Sync {
     /// Get item information from the remote location
     async fn get_item(&self, remote_path: &str) -> Result<Option<RemoteItem>>;
 
-    async fn watch_local_changes(&self, local_path: &Path, tx: mpsc::Sender<ChangeType>) -> Result<()>;
-    async fn watch_remote_changes(&self, remote_path: &str, tx: mpsc::Sender<RemoteItem>) -> Result<()>;
+    /// Move a file or directory into the provider's own trash, where it can be
+    /// restored from. Returns `false` if the provider has no trash, in which
+    /// case nothing was done and the caller keeps deleted items itself.
+    async fn move_to_trash(&self, _remote_path: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// The revisions the provider keeps of a file, newest first. Providers
+    /// without revision history return an empty list.
+    async fn list_revisions(&self, _remote_path: &str) -> Result<Vec<Revision>> {
+        Ok(Vec::new())
+    }
+
+    /// Download one of the revisions returned by `list_revisions`
+    async fn download_revision(&self, remote_path: &str, _revision_id: &str, _local_path: &Path) -> Result<()> {
+        Err(anyhow!("This provider keeps no revisions of {}", remote_path))
+    }
+
+    /// Move or rename a file or directory, creating missing parents of `to`.
+    /// Backends without a server-side move fall back to copy and delete.
+    async fn move_item(&self, from: &str, to: &str) -> Result<RemoteItem> {
+        let item = self
+            .get_item(from)
+            .await?
+            .ok_or_else(|| anyhow!("Remote item not found: {}", from))?;
+
+        if item.is_folder {
+            let moved = self.create_directory(to).await?;
+            for child in self.list_files(from).await? {
+                let child_from = format!("{}/{}", from.trim_end_matches('/'), child.name);
+                let child_to = format!("{}/{}", to.trim_end_matches('/'), child.name);
+                self.move_item(&child_from, &child_to).await?;
+            }
+            self.delete(from).await?;
+            return Ok(moved);
+        }
+
+        let staging = tempfile::tempdir()?;
+        let local_copy = staging.path().join("item");
+        self.download_file(from, &local_copy).await?;
+        let moved = self.upload_file(&local_copy, to).await?;
+        self.delete(from).await?;
+        Ok(moved)
+    }
+
+    /// Changes below `remote_path` since `cursor`, oldest first. Without a
+    /// cursor, returns no changes and the cursor to start from. `None` if the
+    /// provider has no change feed, in which case remote changes are only
+    /// found by scanning the whole mapping.
+    async fn changes_since(&self, _remote_path: &str, _cursor: Option<&str>) -> Result<Option<ChangeFeed>> {
+        Ok(None)
+    }
+
     async fn get_mappings(&self) -> Vec<FolderMapping>;
 } 
\ No newline at end of file
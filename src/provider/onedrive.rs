@@ -1,26 +1,397 @@
-use std::path::Path;
-use anyhow::Result;
+use std::{collections::HashMap, fmt, path::Path};
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use tokio::sync::mpsc;
 use chrono::{DateTime, Utc};
-use std::path::PathBuf;
+use hyper::{
+    body::{self, HttpBody},
+    client::HttpConnector,
+    header, Body, Client, Method, Request, Response, StatusCode,
+};
+use hyper_rustls::HttpsConnector;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use tokio::{fs, io::{AsyncReadExt, AsyncWriteExt}};
+use yup_oauth2::{
+    authenticator::DefaultAuthenticator, ApplicationSecret, InstalledFlowAuthenticator,
+    InstalledFlowReturnMethod,
+};
 
-use super::{CloudProvider, RemoteItem, ChangeType, FolderMapping};
+use super::{ChangeFeed, CloudProvider, RemoteChange, RemoteItem, Revision, FolderMapping};
+
+const GRAPH_BASE_URL: &str = "https://graph.microsoft.com/v1.0";
+const AUTH_URI: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/authorize";
+const TOKEN_URI: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
+const SCOPES: &[&str] = &["Files.ReadWrite", "offline_access"];
+
+/// Graph rejects simple uploads above 4 MiB, larger files go through an upload session
+const SIMPLE_UPLOAD_LIMIT: u64 = 4 * 1024 * 1024;
+/// Upload session fragments must be a multiple of 320 KiB
+const UPLOAD_CHUNK_SIZE: usize = 10 * 320 * 1024;
+
+/// Characters escaped in a single path segment of a Graph `root:/path:` address
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// A non-success answer from Graph, kept typed so callers can react to specific statuses
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "API request failed with status {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+fn has_status(error: &anyhow::Error, status: StatusCode) -> bool {
+    error
+        .downcast_ref::<ApiError>()
+        .is_some_and(|e| e.status == status)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DriveItem {
+    id: String,
+    /// Left out of some deleted items in a delta
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    size: u64,
+    last_modified_date_time: Option<DateTime<Utc>>,
+    folder: Option<serde_json::Value>,
+    /// Changes with the content only, unlike `eTag` which also follows metadata edits
+    c_tag: Option<String>,
+    e_tag: Option<String>,
+    /// Set on items a delta reports as deleted
+    deleted: Option<serde_json::Value>,
+    parent_reference: Option<ItemReference>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemReference {
+    id: Option<String>,
+}
+
+impl From<DriveItem> for RemoteItem {
+    fn from(item: DriveItem) -> Self {
+        RemoteItem {
+            name: item.name,
+            id: item.id,
+            size: item.size,
+            modified: item.last_modified_date_time.unwrap_or(DateTime::<Utc>::UNIX_EPOCH),
+            is_folder: item.folder.is_some(),
+            etag: item.c_tag.or(item.e_tag),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DriveItemPage {
+    value: Vec<DriveItem>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+/// One page of a `delta`, the last one carries the link to the next round
+#[derive(Debug, Deserialize)]
+struct DeltaPage {
+    #[serde(default)]
+    value: Vec<DriveItem>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+    #[serde(rename = "@odata.deltaLink")]
+    delta_link: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DriveItemVersion {
+    id: String,
+    #[serde(default)]
+    size: u64,
+    last_modified_date_time: Option<DateTime<Utc>>,
+    last_modified_by: Option<IdentitySet>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdentitySet {
+    user: Option<Identity>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Identity {
+    display_name: Option<String>,
+}
+
+impl From<DriveItemVersion> for Revision {
+    fn from(version: DriveItemVersion) -> Self {
+        Revision {
+            id: version.id,
+            size: version.size,
+            modified: version.last_modified_date_time.unwrap_or(DateTime::<Utc>::UNIX_EPOCH),
+            modified_by: version.last_modified_by.and_then(|by| by.user).and_then(|user| user.display_name),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DriveItemVersionPage {
+    value: Vec<DriveItemVersion>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadSession {
+    upload_url: String,
+}
 
 pub struct OneDriveProvider {
-    #[allow(dead_code)]
     client_id: String,
-    #[allow(dead_code)]
     client_secret: String,
+    base_url: String,
+    client: HttpsClient,
+    auth: Option<DefaultAuthenticator>,
     mappings: Vec<FolderMapping>,
 }
 
 impl OneDriveProvider {
-    pub fn new(client_id: &str, client_secret: &str) -> Self {
+    pub fn new(client_id: String, client_secret: String, mappings: Vec<FolderMapping>) -> Self {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
         Self {
-            client_id: client_id.to_string(),
-            client_secret: client_secret.to_string(),
-            mappings: Vec::new(),
+            client_id,
+            client_secret,
+            base_url: GRAPH_BASE_URL.to_string(),
+            client: Client::builder().build(connector),
+            auth: None,
+            mappings,
+        }
+    }
+
+    /// Point the provider at a different Graph endpoint, e.g. a national cloud or a mock server
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Build the Graph address of an item, optionally followed by an action such as `children`
+    fn drive_url(&self, remote_path: &str, action: &str) -> String {
+        let trimmed = remote_path.trim_matches('/');
+        let suffix = if action.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", action)
+        };
+
+        if trimmed.is_empty() {
+            format!("{}/me/drive/root{}", self.base_url, suffix)
+        } else {
+            let encoded = trimmed
+                .split('/')
+                .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+            format!("{}/me/drive/root:/{}:{}", self.base_url, encoded, suffix)
+        }
+    }
+
+    async fn access_token(&self) -> Result<Option<String>> {
+        match &self.auth {
+            Some(auth) => {
+                let token = auth.token(SCOPES).await?;
+                Ok(token.token().map(str::to_string))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        body: Body,
+        content_type: Option<&str>,
+    ) -> Result<Response<Body>> {
+        let mut builder = Request::builder().method(method).uri(url);
+        if let Some(token) = self.access_token().await? {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        if let Some(content_type) = content_type {
+            builder = builder.header(header::CONTENT_TYPE, content_type);
+        }
+
+        let response = self.client.request(builder.body(body)?).await?;
+        Self::check_status(response).await
+    }
+
+    async fn send_json<T: for<'de> Deserialize<'de>>(
+        &self,
+        method: Method,
+        url: &str,
+        payload: Option<serde_json::Value>,
+    ) -> Result<T> {
+        let (body, content_type) = match payload {
+            Some(payload) => (Body::from(payload.to_string()), Some("application/json")),
+            None => (Body::empty(), None),
+        };
+        let response = self.send(method, url, body, content_type).await?;
+        let bytes = body::to_bytes(response.into_body()).await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    async fn check_status(response: Response<Body>) -> Result<Response<Body>> {
+        let status = response.status();
+        if status.is_success() || status.is_redirection() {
+            return Ok(response);
+        }
+
+        let bytes = body::to_bytes(response.into_body()).await.unwrap_or_default();
+        Err(ApiError {
+            status,
+            message: String::from_utf8_lossy(&bytes).into_owned(),
+        }
+        .into())
+    }
+
+    /// Download the content behind a Graph `.../content` address
+    async fn download_content(&self, url: &str, remote_path: &str, local_path: &Path) -> Result<()> {
+        let mut response = self.send(Method::GET, url, Body::empty(), None).await?;
+
+        // Graph answers with a redirect to a pre-authenticated download URL
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| anyhow!("Download redirect without a location for {}", remote_path))?
+                .to_string();
+            let request = Request::get(location).body(Body::empty())?;
+            response = Self::check_status(self.client.request(request).await?).await?;
+        }
+
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut file = fs::File::create(local_path).await?;
+        let body = response.body_mut();
+        while let Some(chunk) = body.data().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+
+        Ok(())
+    }
+
+    /// Path of `item` if it lies below the folder `root_id` at `root_path`,
+    /// found by walking up its parents. `folders` holds the name and parent of
+    /// folders already known, and gets the ones looked up on the way.
+    async fn path_below(
+        &self,
+        item: &DriveItem,
+        root_id: &str,
+        root_path: &str,
+        folders: &mut HashMap<String, (String, Option<String>)>,
+    ) -> Result<Option<String>> {
+        if item.name.is_empty() {
+            return Ok(None);
+        }
+        let mut names = vec![item.name.clone()];
+        let mut parent = item.parent_reference.as_ref().and_then(|reference| reference.id.clone());
+
+        loop {
+            let Some(id) = parent else { return Ok(None) };
+            if id == root_id {
+                break;
+            }
+            if !folders.contains_key(&id) {
+                let url = format!(
+                    "{}/me/drive/items/{}?$select=id,name,parentReference",
+                    self.base_url,
+                    utf8_percent_encode(&id, PATH_SEGMENT)
+                );
+                let folder: DriveItem = match self.send_json(Method::GET, &url, None).await {
+                    Ok(folder) => folder,
+                    Err(e) if has_status(&e, StatusCode::NOT_FOUND) => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+                let grandparent = folder.parent_reference.and_then(|reference| reference.id);
+                folders.insert(id.clone(), (folder.name, grandparent));
+            }
+
+            let (name, grandparent) = folders[&id].clone();
+            names.push(name);
+            parent = grandparent;
+        }
+
+        names.reverse();
+        let root = root_path.trim_matches('/');
+        let prefix = if root.is_empty() { String::new() } else { format!("/{}", root) };
+        Ok(Some(format!("{}/{}", prefix, names.join("/"))))
+    }
+
+    async fn upload_with_session(&self, local_path: &Path, remote_path: &str, size: u64) -> Result<RemoteItem> {
+        let session: UploadSession = self
+            .send_json(
+                Method::POST,
+                &self.drive_url(remote_path, "createUploadSession"),
+                Some(serde_json::json!({
+                    "item": { "@microsoft.graph.conflictBehavior": "replace" }
+                })),
+            )
+            .await?;
+
+        let mut file = fs::File::open(local_path).await?;
+        let mut buffer = vec![0u8; UPLOAD_CHUNK_SIZE];
+        let mut offset = 0u64;
+
+        loop {
+            // A file that grew meanwhile is sent as long as it was, its later writes are synced next time
+            let wanted = buffer.len().min((size - offset) as usize);
+            let mut filled = 0;
+            while filled < wanted {
+                let read = file.read(&mut buffer[filled..wanted]).await?;
+                if read == 0 {
+                    break;
+                }
+                filled += read;
+            }
+            if filled == 0 {
+                return Err(anyhow!("File {:?} shrank while uploading", local_path));
+            }
+
+            let end = offset + filled as u64 - 1;
+            // The upload URL is pre-authenticated, sending a bearer token to it is rejected
+            let request = Request::builder()
+                .method(Method::PUT)
+                .uri(&session.upload_url)
+                .header(header::CONTENT_LENGTH, filled)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", offset, end, size))
+                .body(Body::from(buffer[..filled].to_vec()))?;
+            let response = Self::check_status(self.client.request(request).await?).await?;
+            offset = end + 1;
+
+            if offset >= size {
+                let bytes = body::to_bytes(response.into_body()).await?;
+                let item: DriveItem = serde_json::from_slice(&bytes)?;
+                return Ok(item.into());
+            }
         }
     }
 }
@@ -28,56 +399,257 @@ impl OneDriveProvider {
 #[async_trait]
 impl CloudProvider for OneDriveProvider {
     async fn initialize(&mut self) -> Result<()> {
-        // TODO: Implement OneDrive authentication
+        let secret = ApplicationSecret {
+            client_id: self.client_id.clone(),
+            client_secret: self.client_secret.clone(),
+            token_uri: TOKEN_URI.to_string(),
+            auth_uri: AUTH_URI.to_string(),
+            redirect_uris: vec!["http://localhost".to_string()],
+            ..Default::default()
+        };
+
+        let mut builder = InstalledFlowAuthenticator::builder(
+            secret,
+            InstalledFlowReturnMethod::HTTPRedirect,
+        );
+        if let Some(config_dir) = dirs::config_dir() {
+            let token_dir = config_dir.join("filesynchub");
+            fs::create_dir_all(&token_dir).await?;
+            builder = builder
+                .persist_tokens_to_disk(token_dir.join(format!("onedrive_{}.json", self.client_id)));
+        }
+        self.auth = Some(builder.build().await?);
+
+        // Fetch a token up front so an interactive login happens before syncing starts
+        self.access_token().await?;
         Ok(())
     }
 
-    async fn list_files(&self, _remote_path: &str) -> Result<Vec<RemoteItem>> {
-        // TODO: Implement file listing
-        Ok(Vec::new())
+    async fn list_files(&self, remote_path: &str) -> Result<Vec<RemoteItem>> {
+        let mut items = Vec::new();
+        let mut next = Some(self.drive_url(remote_path, "children"));
+
+        while let Some(url) = next {
+            let page: DriveItemPage = self.send_json(Method::GET, &url, None).await?;
+            items.extend(page.value.into_iter().map(RemoteItem::from));
+            next = page.next_link;
+        }
+
+        Ok(items)
     }
 
-    async fn upload_file(&self, _local_path: &Path, _remote_path: &str) -> Result<RemoteItem> {
-        // TODO: Implement file upload
-        unimplemented!()
+    async fn upload_file(&self, local_path: &Path, remote_path: &str) -> Result<RemoteItem> {
+        let size = fs::metadata(local_path).await?.len();
+        if size > SIMPLE_UPLOAD_LIMIT {
+            return self.upload_with_session(local_path, remote_path, size).await;
+        }
+
+        let content = fs::read(local_path).await?;
+        let response = self
+            .send(
+                Method::PUT,
+                &self.drive_url(remote_path, "content"),
+                Body::from(content),
+                Some("application/octet-stream"),
+            )
+            .await?;
+        let bytes = body::to_bytes(response.into_body()).await?;
+        let item: DriveItem = serde_json::from_slice(&bytes)?;
+        Ok(item.into())
     }
 
-    async fn download_file(&self, _remote_path: &str, _local_path: &Path) -> Result<()> {
-        // TODO: Implement file download
-        unimplemented!()
+    async fn download_file(&self, remote_path: &str, local_path: &Path) -> Result<()> {
+        self.download_content(&self.drive_url(remote_path, "content"), remote_path, local_path).await
     }
 
-    async fn create_directory(&self, _remote_path: &str) -> Result<RemoteItem> {
-        // TODO: Implement directory creation
-        unimplemented!()
+    async fn create_directory(&self, remote_path: &str) -> Result<RemoteItem> {
+        let trimmed = remote_path.trim_matches('/');
+        let (parent, name) = match trimmed.rsplit_once('/') {
+            Some((parent, name)) => (parent, name),
+            None => ("", trimmed),
+        };
+
+        let result = self
+            .send_json::<DriveItem>(
+                Method::POST,
+                &self.drive_url(parent, "children"),
+                Some(serde_json::json!({
+                    "name": name,
+                    "folder": {},
+                    "@microsoft.graph.conflictBehavior": "fail"
+                })),
+            )
+            .await;
+
+        match result {
+            Ok(item) => Ok(item.into()),
+            // The folder already exists, which is what the caller asked for
+            Err(e) if has_status(&e, StatusCode::CONFLICT) => self
+                .get_item(remote_path)
+                .await?
+                .ok_or(e),
+            Err(e) => Err(e),
+        }
     }
 
-    async fn delete(&self, _remote_path: &str) -> Result<()> {
-        // TODO: Implement deletion
-        unimplemented!()
+    async fn delete(&self, remote_path: &str) -> Result<()> {
+        self.send(Method::DELETE, &self.drive_url(remote_path, ""), Body::empty(), None)
+            .await?;
+        Ok(())
     }
 
-    async fn exists(&self, _remote_path: &str) -> Result<bool> {
-        // TODO: Implement existence check
-        unimplemented!()
+    async fn move_to_trash(&self, remote_path: &str) -> Result<bool> {
+        // Deleted items already go to the recycle bin
+        self.delete(remote_path).await?;
+        Ok(true)
     }
 
-    async fn get_item(&self, _remote_path: &str) -> Result<Option<RemoteItem>> {
-        // TODO: Implement item retrieval
-        unimplemented!()
+    async fn list_revisions(&self, remote_path: &str) -> Result<Vec<Revision>> {
+        let mut revisions = Vec::new();
+        let mut next = Some(self.drive_url(remote_path, "versions"));
+
+        // Graph lists the current version first
+        while let Some(url) = next {
+            let page: DriveItemVersionPage = self.send_json(Method::GET, &url, None).await?;
+            revisions.extend(page.value.into_iter().map(Revision::from));
+            next = page.next_link;
+        }
+
+        Ok(revisions)
     }
 
-    async fn watch_local_changes(&self, _local_path: &Path, _tx: mpsc::Sender<ChangeType>) -> Result<()> {
-        // TODO: Implement
-        Ok(())
+    async fn download_revision(&self, remote_path: &str, revision_id: &str, local_path: &Path) -> Result<()> {
+        let action = format!("versions/{}/content", utf8_percent_encode(revision_id, PATH_SEGMENT));
+        self.download_content(&self.drive_url(remote_path, &action), remote_path, local_path).await
     }
 
-    async fn watch_remote_changes(&self, _remote_path: &str, _tx: mpsc::Sender<RemoteItem>) -> Result<()> {
-        // TODO: Implement
-        Ok(())
+    async fn exists(&self, remote_path: &str) -> Result<bool> {
+        Ok(self.get_item(remote_path).await?.is_some())
+    }
+
+    async fn get_item(&self, remote_path: &str) -> Result<Option<RemoteItem>> {
+        match self
+            .send_json::<DriveItem>(Method::GET, &self.drive_url(remote_path, ""), None)
+            .await
+        {
+            Ok(item) => Ok(Some(item.into())),
+            Err(e) if has_status(&e, StatusCode::NOT_FOUND) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn move_item(&self, from: &str, to: &str) -> Result<RemoteItem> {
+        let trimmed = to.trim_matches('/');
+        let (parent, name) = match trimmed.rsplit_once('/') {
+            Some((parent, name)) => (parent, name),
+            None => ("", trimmed),
+        };
+
+        let parent_path = match parent {
+            "" => "/drive/root".to_string(),
+            parent => format!("/drive/root:/{}", parent),
+        };
+
+        // Graph only moves into existing folders
+        let mut current = String::new();
+        for segment in parent.split('/').filter(|s| !s.is_empty()) {
+            current.push('/');
+            current.push_str(segment);
+            self.create_directory(&current).await?;
+        }
+
+        let item = self
+            .send_json::<DriveItem>(
+                Method::PATCH,
+                &self.drive_url(from, ""),
+                Some(serde_json::json!({
+                    "parentReference": { "path": parent_path },
+                    "name": name
+                })),
+            )
+            .await?;
+        Ok(item.into())
+    }
+
+    /// Follows the `delta` of the mapped folder, whose delta link is the cursor.
+    /// OneDrive for Business and SharePoint only follow the whole drive, whose
+    /// changes outside the folder get no path and are left out by the caller.
+    async fn changes_since(&self, remote_path: &str, cursor: Option<&str>) -> Result<Option<ChangeFeed>> {
+        let Some(cursor) = cursor else {
+            // Only the link to continue from, not every item there is now
+            let latest = |path| self.drive_url(path, "delta?token=latest");
+            let page: DeltaPage = match self.send_json(Method::GET, &latest(remote_path), None).await {
+                Ok(page) => page,
+                Err(e) if [StatusCode::BAD_REQUEST, StatusCode::FORBIDDEN, StatusCode::NOT_IMPLEMENTED]
+                    .iter()
+                    .any(|status| has_status(&e, *status)) =>
+                {
+                    self.send_json(Method::GET, &latest("/"), None).await?
+                }
+                Err(e) => return Err(e),
+            };
+            let cursor = page
+                .delta_link
+                .ok_or_else(|| anyhow!("Graph returned no delta link for {}", remote_path))?;
+            return Ok(Some(ChangeFeed { changes: Vec::new(), cursor, reset: false }));
+        };
+
+        let mut items = Vec::new();
+        let mut next = cursor.to_string();
+        let cursor = loop {
+            let page: DeltaPage = match self.send_json(Method::GET, &next, None).await {
+                Ok(page) => page,
+                // The delta link expired, start over from now
+                Err(e) if has_status(&e, StatusCode::GONE) => {
+                    let feed = self.changes_since(remote_path, None).await?;
+                    return Ok(feed.map(|feed| ChangeFeed { reset: true, ..feed }));
+                }
+                Err(e) => return Err(e),
+            };
+            items.extend(page.value);
+
+            match (page.next_link, page.delta_link) {
+                (Some(link), _) => next = link,
+                (None, Some(link)) => break link,
+                (None, None) => return Err(anyhow!("Graph returned no link to continue the delta of {} from", remote_path)),
+            }
+        };
+
+        let Some(root) = self.get_item(remote_path).await? else {
+            return Ok(Some(ChangeFeed { changes: Vec::new(), cursor, reset: false }));
+        };
+        // Parents of changed items usually come along in the same delta
+        let mut folders: HashMap<String, (String, Option<String>)> = items
+            .iter()
+            .filter(|item| item.folder.is_some())
+            .map(|item| {
+                let parent = item.parent_reference.as_ref().and_then(|reference| reference.id.clone());
+                (item.id.clone(), (item.name.clone(), parent))
+            })
+            .collect();
+
+        let mut changes = Vec::with_capacity(items.len());
+        for item in items {
+            if item.id == root.id {
+                continue;
+            }
+            let path = self.path_below(&item, &root.id, remote_path, &mut folders).await?;
+            let deleted = item.deleted.is_some();
+            changes.push(RemoteChange {
+                id: item.id.clone(),
+                path,
+                item: (!deleted).then(|| item.into()),
+            });
+        }
+
+        Ok(Some(ChangeFeed { changes, cursor, reset: false }))
     }
 
     async fn get_mappings(&self) -> Vec<FolderMapping> {
         self.mappings.clone()
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+#[path = "onedrive_test.rs"]
+mod tests;
@@ -1,9 +1,7 @@
 use super::*;
-use std::time::Duration;
 use tempfile::tempdir;
-use tokio::sync::mpsc;
 use wiremock::{
-    matchers::{method, path},
+    matchers::{body_json, header, method, path, query_param},
     Mock, MockServer, ResponseTemplate,
 };
 
@@ -41,8 +39,10 @@ async fn test_onedrive_provider() -> Result<()> {
         vec![FolderMapping {
             local_path: tempdir()?.path().to_path_buf(),
             remote_path: "/test".to_string(),
+            ..Default::default()
         }],
-    );
+    )
+    .with_base_url(mock_server.uri());
 
     // Testar listagem de arquivos
     let files = provider.list_files("/test").await?;
@@ -77,7 +77,7 @@ async fn test_onedrive_provider() -> Result<()> {
 
     Mock::given(method("GET"))
         .and(path("/me/drive/root:/test/download.txt:/content"))
-        .respond_with(ResponseTemplate::new(200).set_body("test content"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("test content"))
         .mount(&mock_server)
         .await;
 
@@ -110,28 +110,6 @@ async fn test_onedrive_provider() -> Result<()> {
 
     provider.delete("/test/delete.txt").await?;
 
-    // Testar monitoramento de mudanças locais
-    let (tx, mut rx) = mpsc::channel(100);
-    provider.watch_local_changes(temp_dir.path(), tx).await?;
-
-    // Criar arquivo para testar o watcher
-    let watch_file = temp_dir.path().join("watch.txt");
-    tokio::fs::write(&watch_file, b"test").await?;
-
-    // Esperar pela notificação
-    let timeout = tokio::time::sleep(Duration::from_secs(1));
-    tokio::pin!(timeout);
-
-    tokio::select! {
-        Some(change) = rx.recv() => {
-            match change {
-                ChangeType::Created(path) => assert_eq!(path, watch_file),
-                _ => panic!("Unexpected change type"),
-            }
-        }
-        _ = &mut timeout => panic!("Timeout waiting for file change"),
-    }
-
     Ok(())
 }
 
@@ -157,8 +135,10 @@ async fn test_onedrive_error_handling() -> Result<()> {
         vec![FolderMapping {
             local_path: tempdir()?.path().to_path_buf(),
             remote_path: "/test".to_string(),
+            ..Default::default()
         }],
-    );
+    )
+    .with_base_url(mock_server.uri());
 
     // Verificar se o erro é propagado corretamente
     let result = provider.list_files("/test").await;
@@ -190,8 +170,10 @@ async fn test_onedrive_rate_limiting() -> Result<()> {
         vec![FolderMapping {
             local_path: tempdir()?.path().to_path_buf(),
             remote_path: "/test".to_string(),
+            ..Default::default()
         }],
-    );
+    )
+    .with_base_url(mock_server.uri());
 
     // Verificar se o erro de rate limiting é propagado
     let result = provider.list_files("/test").await;
@@ -199,4 +181,274 @@ async fn test_onedrive_rate_limiting() -> Result<()> {
     assert!(result.unwrap_err().to_string().contains("API request failed"));
 
     Ok(())
-} 
\ No newline at end of file
+} 
+#[tokio::test]
+async fn test_onedrive_revisions() -> Result<()> {
+    let mock_server = MockServer::start().await;
+
+    // Versões do arquivo, a atual primeiro
+    Mock::given(method("GET"))
+        .and(path("/me/drive/root:/docs/budget.xlsx:/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "value": [
+                {
+                    "id": "2.0",
+                    "size": 12,
+                    "lastModifiedDateTime": "2024-05-02T10:00:00Z",
+                    "lastModifiedBy": { "user": { "displayName": "Ana" } }
+                },
+                {
+                    "id": "1.0",
+                    "size": 10,
+                    "lastModifiedDateTime": "2024-05-01T10:00:00Z"
+                }
+            ]
+        })))
+        .mount(&mock_server)
+        .await;
+
+    // O conteúdo de uma versão vem por redirecionamento, como nos downloads
+    Mock::given(method("GET"))
+        .and(path("/me/drive/root:/docs/budget.xlsx:/versions/1.0/content"))
+        .respond_with(
+            ResponseTemplate::new(302).insert_header("Location", format!("{}/download/1.0", mock_server.uri())),
+        )
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/download/1.0"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(b"old budget".to_vec()))
+        .mount(&mock_server)
+        .await;
+
+    let provider = OneDriveProvider::new("client_id".to_string(), "client_secret".to_string(), Vec::new())
+        .with_base_url(mock_server.uri());
+
+    let revisions = provider.list_revisions("/docs/budget.xlsx").await?;
+    assert_eq!(revisions.len(), 2);
+    assert_eq!(revisions[0].id, "2.0");
+    assert_eq!(revisions[0].modified_by.as_deref(), Some("Ana"));
+    assert_eq!(revisions[1].size, 10);
+
+    let temp_dir = tempdir()?;
+    let target = temp_dir.path().join("budget.xlsx");
+    provider.download_revision("/docs/budget.xlsx", "1.0", &target).await?;
+    assert_eq!(tokio::fs::read(&target).await?, b"old budget");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_onedrive_delta() -> Result<()> {
+    let mock_server = MockServer::start().await;
+    let uri = mock_server.uri();
+
+    // Sem cursor, só o link para continuar a partir de agora
+    Mock::given(method("GET"))
+        .and(path("/me/drive/root:/docs:/delta"))
+        .and(query_param("token", "latest"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "value": [],
+            "@odata.deltaLink": format!("{}/delta/1", uri)
+        })))
+        .expect(2)
+        .mount(&mock_server)
+        .await;
+
+    // As mudanças vêm em duas páginas, a última com o próximo link
+    Mock::given(method("GET"))
+        .and(path("/delta/1"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "value": [
+                { "id": "docs-id", "name": "docs", "folder": {}, "parentReference": { "id": "root-id" } },
+                { "id": "reports-id", "name": "reports", "folder": {}, "parentReference": { "id": "docs-id" } },
+                { "id": "q1-id", "name": "q1.pdf", "size": 42, "parentReference": { "id": "reports-id" } },
+                { "id": "old-id", "name": "old.txt", "deleted": {}, "parentReference": { "id": "docs-id" } }
+            ],
+            "@odata.nextLink": format!("{}/delta/2", uri)
+        })))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/delta/2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "value": [
+                { "id": "notes-id", "name": "notes.md", "size": 7, "parentReference": { "id": "deep-id" } }
+            ],
+            "@odata.deltaLink": format!("{}/delta/3", uri)
+        })))
+        .mount(&mock_server)
+        .await;
+
+    // Pastas que não vieram no delta são buscadas pelo id
+    Mock::given(method("GET"))
+        .and(path("/me/drive/items/deep-id"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "id": "deep-id", "name": "deep", "folder": {}, "parentReference": { "id": "docs-id" }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/me/drive/root:/docs:"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "id": "docs-id", "name": "docs", "folder": {}
+        })))
+        .mount(&mock_server)
+        .await;
+
+    // Link expirado
+    Mock::given(method("GET"))
+        .and(path("/delta/expired"))
+        .respond_with(ResponseTemplate::new(410).set_body_json(serde_json::json!({
+            "error": { "code": "resyncRequired" }
+        })))
+        .mount(&mock_server)
+        .await;
+
+    let provider = OneDriveProvider::new("client_id".to_string(), "client_secret".to_string(), Vec::new())
+        .with_base_url(uri.clone());
+
+    let start = provider.changes_since("/docs", None).await?.unwrap();
+    assert!(start.changes.is_empty());
+    assert_eq!(start.cursor, format!("{}/delta/1", uri));
+
+    let feed = provider.changes_since("/docs", Some(&start.cursor)).await?.unwrap();
+    assert_eq!(feed.cursor, format!("{}/delta/3", uri));
+    assert!(!feed.reset);
+    let changes: Vec<_> = feed
+        .changes
+        .iter()
+        .map(|change| (change.id.as_str(), change.path.as_deref(), change.item.is_some()))
+        .collect();
+    assert_eq!(changes, [
+        ("reports-id", Some("/docs/reports"), true),
+        ("q1-id", Some("/docs/reports/q1.pdf"), true),
+        ("old-id", Some("/docs/old.txt"), false),
+        ("notes-id", Some("/docs/deep/notes.md"), true),
+    ]);
+
+    // Recomeça do estado atual e avisa que mudanças podem ter se perdido
+    let feed = provider.changes_since("/docs", Some(&format!("{}/delta/expired", uri))).await?.unwrap();
+    assert!(feed.reset);
+    assert_eq!(feed.cursor, format!("{}/delta/1", uri));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_onedrive_business_delta() -> Result<()> {
+    let mock_server = MockServer::start().await;
+    let uri = mock_server.uri();
+
+    // No OneDrive for Business só a raiz do drive tem delta
+    Mock::given(method("GET"))
+        .and(path("/me/drive/root:/docs:/delta"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+            "error": { "code": "invalidRequest" }
+        })))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/me/drive/root/delta"))
+        .and(query_param("token", "latest"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "value": [],
+            "@odata.deltaLink": format!("{}/delta/1", uri)
+        })))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/delta/1"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "value": [
+                { "id": "root-id", "name": "root", "folder": {} },
+                { "id": "docs-id", "name": "docs", "folder": {}, "parentReference": { "id": "root-id" } },
+                { "id": "a-id", "name": "a.txt", "size": 1, "parentReference": { "id": "docs-id" } },
+                { "id": "other-id", "name": "other.txt", "size": 1, "parentReference": { "id": "root-id" } }
+            ],
+            "@odata.deltaLink": format!("{}/delta/2", uri)
+        })))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/me/drive/root:/docs:"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "id": "docs-id", "name": "docs", "folder": {}
+        })))
+        .mount(&mock_server)
+        .await;
+
+    let provider = OneDriveProvider::new("client_id".to_string(), "client_secret".to_string(), Vec::new())
+        .with_base_url(uri.clone());
+
+    let start = provider.changes_since("/docs", None).await?.unwrap();
+    assert_eq!(start.cursor, format!("{}/delta/1", uri));
+
+    // Mudanças fora da pasta mapeada ficam sem caminho
+    let feed = provider.changes_since("/docs", Some(&start.cursor)).await?.unwrap();
+    assert_eq!(feed.cursor, format!("{}/delta/2", uri));
+    let changes: Vec<_> = feed.changes.iter().map(|change| (change.id.as_str(), change.path.as_deref())).collect();
+    assert_eq!(changes, [("root-id", None), ("a-id", Some("/docs/a.txt")), ("other-id", None)]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_onedrive_upload_session_keeps_declared_size() -> Result<()> {
+    let mock_server = MockServer::start().await;
+    let uri = mock_server.uri();
+    Mock::given(method("POST"))
+        .and(path("/me/drive/root:/big.bin:/createUploadSession"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "uploadUrl": format!("{}/session/1", uri)
+        })))
+        .mount(&mock_server)
+        .await;
+    // O arquivo cresceu depois de medido: só o tamanho declarado é enviado
+    Mock::given(method("PUT"))
+        .and(path("/session/1"))
+        .and(header("content-range", "bytes 0-3/4"))
+        .respond_with(ResponseTemplate::new(201).set_body_json(serde_json::json!({
+            "id": "big-id", "name": "big.bin", "size": 4
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let dir = tempdir()?;
+    let local = dir.path().join("big.bin");
+    std::fs::write(&local, b"grown meanwhile")?;
+
+    let provider = OneDriveProvider::new("client_id".to_string(), "client_secret".to_string(), Vec::new())
+        .with_base_url(uri);
+    let item = provider.upload_with_session(&local, "/big.bin", 4).await?;
+    assert_eq!(item.size, 4);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_onedrive_move_to_root() -> Result<()> {
+    let mock_server = MockServer::start().await;
+    // Mover para a raiz usa o caminho da raiz, sem "root:/"
+    Mock::given(method("PATCH"))
+        .and(path("/me/drive/root:/docs/a.txt:"))
+        .and(body_json(serde_json::json!({
+            "parentReference": { "path": "/drive/root" },
+            "name": "a.txt"
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "id": "a-id", "name": "a.txt", "size": 1
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let provider = OneDriveProvider::new("client_id".to_string(), "client_secret".to_string(), Vec::new())
+        .with_base_url(mock_server.uri());
+    let item = provider.move_item("/docs/a.txt", "/a.txt").await?;
+    assert_eq!(item.id, "a-id");
+
+    Ok(())
+}
@@ -0,0 +1,560 @@
+use std::{fmt, path::Path};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use hyper::{
+    body::{self, HttpBody},
+    client::HttpConnector,
+    header, Body, Client, Method, Request, Response, StatusCode,
+};
+use hyper_rustls::HttpsConnector;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::{events::Event, Reader};
+use tokio::{fs, io::AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+use super::{ChangeFeed, CloudProvider, RemoteItem, FolderMapping};
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getcontentlength/>
+    <d:getlastmodified/>
+    <d:getetag/>
+  </d:prop>
+</d:propfind>"#;
+
+/// Characters escaped in a single path segment of a WebDAV URL
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// How requests to the WebDAV server are authenticated
+#[derive(Debug, Clone)]
+pub enum WebDavAuth {
+    None,
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+/// A non-success answer from the server, kept typed so callers can react to specific statuses
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WebDAV request failed with status {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+fn has_status(error: &anyhow::Error, status: StatusCode) -> bool {
+    error
+        .downcast_ref::<ApiError>()
+        .is_some_and(|e| e.status == status)
+}
+
+/// One `<d:response>` of a PROPFIND multistatus answer
+#[derive(Debug, Default)]
+struct DavEntry {
+    href: String,
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+    etag: Option<String>,
+    is_collection: bool,
+}
+
+pub struct WebDavProvider {
+    base_url: String,
+    auth: WebDavAuth,
+    client: HttpsClient,
+    mappings: Vec<FolderMapping>,
+}
+
+impl WebDavProvider {
+    /// `base_url` is the collection acting as root, e.g.
+    /// `https://cloud.example.com/remote.php/dav/files/alice`
+    pub fn new(base_url: &str, auth: WebDavAuth, mappings: Vec<FolderMapping>) -> Self {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            auth,
+            client: Client::builder().build(connector),
+            mappings,
+        }
+    }
+
+    fn normalize(remote_path: &str) -> String {
+        let segments: Vec<&str> = remote_path.split('/').filter(|s| !s.is_empty()).collect();
+        format!("/{}", segments.join("/"))
+    }
+
+    fn url(&self, remote_path: &str) -> String {
+        let encoded: String = remote_path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|segment| format!("/{}", utf8_percent_encode(segment, PATH_SEGMENT)))
+            .collect();
+        format!("{}{}", self.base_url, encoded)
+    }
+
+    /// Path component of a URL or href, decoded and without trailing slash
+    fn decoded_path(url: &str) -> String {
+        let path = match url.find("://") {
+            Some(scheme_end) => {
+                let rest = &url[scheme_end + 3..];
+                rest.find('/').map_or("/", |i| &rest[i..])
+            }
+            None => url,
+        };
+        let decoded = percent_decode_str(path).decode_utf8_lossy();
+        Self::normalize(&decoded)
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &[(header::HeaderName, String)],
+        body: Body,
+    ) -> Result<Response<Body>> {
+        let mut builder = Request::builder().method(method).uri(url);
+        builder = match &self.auth {
+            WebDavAuth::None => builder,
+            WebDavAuth::Basic { username, password } => builder.header(
+                header::AUTHORIZATION,
+                format!("Basic {}", STANDARD.encode(format!("{}:{}", username, password))),
+            ),
+            WebDavAuth::Bearer(token) => {
+                builder.header(header::AUTHORIZATION, format!("Bearer {}", token))
+            }
+        };
+        for (name, value) in headers {
+            builder = builder.header(name, value);
+        }
+
+        let response = self.client.request(builder.body(body)?).await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let bytes = body::to_bytes(response.into_body()).await.unwrap_or_default();
+        Err(ApiError {
+            status,
+            message: String::from_utf8_lossy(&bytes).into_owned(),
+        }
+        .into())
+    }
+
+    async fn propfind(&self, remote_path: &str, depth: &str) -> Result<Vec<DavEntry>> {
+        let response = self
+            .send(
+                Method::from_bytes(b"PROPFIND")?,
+                &self.url(remote_path),
+                &[
+                    (header::HeaderName::from_static("depth"), depth.to_string()),
+                    (header::CONTENT_TYPE, "application/xml; charset=utf-8".to_string()),
+                ],
+                Body::from(PROPFIND_BODY),
+            )
+            .await?;
+        let bytes = body::to_bytes(response.into_body()).await?;
+        Self::parse_multistatus(&String::from_utf8_lossy(&bytes))
+    }
+
+    /// Parse a multistatus document, matching elements by local name since servers
+    /// disagree on the namespace prefix
+    fn parse_multistatus(xml: &str) -> Result<Vec<DavEntry>> {
+        let mut reader = Reader::from_str(xml);
+        let mut entries = Vec::new();
+        let mut current: Option<DavEntry> = None;
+        let mut element = String::new();
+
+        loop {
+            match reader.read_event()? {
+                Event::Start(e) => {
+                    element = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+                    match element.as_str() {
+                        "response" => current = Some(DavEntry::default()),
+                        "collection" => {
+                            if let Some(entry) = current.as_mut() {
+                                entry.is_collection = true;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Event::Empty(e) if e.local_name().as_ref() == b"collection" => {
+                    if let Some(entry) = current.as_mut() {
+                        entry.is_collection = true;
+                    }
+                }
+                Event::Text(text) => {
+                    let value = text.unescape()?.trim().to_string();
+                    if let Some(entry) = current.as_mut() {
+                        match element.as_str() {
+                            "href" => entry.href = value,
+                            "getcontentlength" => entry.size = value.parse().unwrap_or_default(),
+                            "getlastmodified" => {
+                                entry.modified = DateTime::parse_from_rfc2822(&value)
+                                    .ok()
+                                    .map(|t| t.with_timezone(&Utc));
+                            }
+                            "getetag" => entry.etag = Some(value.trim_matches('"').to_string()),
+                            _ => {}
+                        }
+                    }
+                }
+                Event::End(e) => {
+                    if e.local_name().as_ref() == b"response" {
+                        entries.extend(current.take());
+                    }
+                    element.clear();
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn to_remote_item(entry: DavEntry, remote_path: &str) -> RemoteItem {
+        let name = remote_path.rsplit('/').next().unwrap_or_default().to_string();
+        RemoteItem {
+            name,
+            id: remote_path.to_string(),
+            size: entry.size,
+            // A fixed stand-in, the current time would make every listing look like an edit
+            modified: entry.modified.unwrap_or(DateTime::<Utc>::UNIX_EPOCH),
+            is_folder: entry.is_collection,
+            etag: entry.etag,
+        }
+    }
+}
+
+#[async_trait]
+impl CloudProvider for WebDavProvider {
+    async fn initialize(&mut self) -> Result<()> {
+        // Make sure the root collection is reachable with the configured credentials
+        self.propfind("/", "0").await?;
+        Ok(())
+    }
+
+    async fn list_files(&self, remote_path: &str) -> Result<Vec<RemoteItem>> {
+        let parent = Self::normalize(remote_path);
+        let requested = Self::decoded_path(&self.url(&parent));
+        let base = Self::decoded_path(&self.base_url);
+
+        let items = self
+            .propfind(&parent, "1")
+            .await?
+            .into_iter()
+            .filter_map(|entry| {
+                let href = Self::decoded_path(&entry.href);
+                // The collection itself is part of a depth 1 answer
+                if href == requested {
+                    return None;
+                }
+                let relative = href.strip_prefix(&base).unwrap_or(&href).to_string();
+                Some(Self::to_remote_item(entry, &Self::normalize(&relative)))
+            })
+            .collect();
+
+        Ok(items)
+    }
+
+    async fn upload_file(&self, local_path: &Path, remote_path: &str) -> Result<RemoteItem> {
+        let path = Self::normalize(remote_path);
+
+        for attempt in 0..2 {
+            let file = fs::File::open(local_path).await?;
+            let size = file.metadata().await?.len();
+            let result = self
+                .send(
+                    Method::PUT,
+                    &self.url(&path),
+                    &[
+                        (header::CONTENT_LENGTH, size.to_string()),
+                        (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                    ],
+                    Body::wrap_stream(ReaderStream::new(file)),
+                )
+                .await;
+
+            match result {
+                Ok(_) => break,
+                // 409 means a parent collection is missing, create it and try once more
+                Err(e) if attempt == 0 && has_status(&e, StatusCode::CONFLICT) => {
+                    if let Some((parent, _)) = path.rsplit_once('/') {
+                        self.create_directory(parent).await?;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.get_item(&path)
+            .await?
+            .ok_or_else(|| anyhow!("Uploaded file not found: {}", path))
+    }
+
+    async fn download_file(&self, remote_path: &str, local_path: &Path) -> Result<()> {
+        let mut response = self
+            .send(Method::GET, &self.url(remote_path), &[], Body::empty())
+            .await?;
+
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut file = fs::File::create(local_path).await?;
+        let body = response.body_mut();
+        while let Some(chunk) = body.data().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+
+        Ok(())
+    }
+
+    async fn create_directory(&self, remote_path: &str) -> Result<RemoteItem> {
+        let path = Self::normalize(remote_path);
+        let mut current = String::new();
+
+        // MKCOL does not create intermediate collections, walk down from the root
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            current = format!("{}/{}", current, segment);
+            let result = self
+                .send(Method::from_bytes(b"MKCOL")?, &self.url(&current), &[], Body::empty())
+                .await;
+            match result {
+                Ok(_) => {}
+                // 405 is the answer for a collection that already exists
+                Err(e) if has_status(&e, StatusCode::METHOD_NOT_ALLOWED) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.get_item(&path)
+            .await?
+            .ok_or_else(|| anyhow!("Remote folder not found after creation: {}", path))
+    }
+
+    async fn delete(&self, remote_path: &str) -> Result<()> {
+        self.send(Method::DELETE, &self.url(remote_path), &[], Body::empty())
+            .await?;
+        Ok(())
+    }
+
+    async fn exists(&self, remote_path: &str) -> Result<bool> {
+        Ok(self.get_item(remote_path).await?.is_some())
+    }
+
+    async fn get_item(&self, remote_path: &str) -> Result<Option<RemoteItem>> {
+        let path = Self::normalize(remote_path);
+        match self.propfind(&path, "0").await {
+            Ok(entries) => Ok(entries
+                .into_iter()
+                .next()
+                .map(|entry| Self::to_remote_item(entry, &path))),
+            Err(e) if has_status(&e, StatusCode::NOT_FOUND) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn move_item(&self, from: &str, to: &str) -> Result<RemoteItem> {
+        let from = Self::normalize(from);
+        let to = Self::normalize(to);
+
+        for attempt in 0..2 {
+            let result = self
+                .send(
+                    Method::from_bytes(b"MOVE")?,
+                    &self.url(&from),
+                    &[
+                        (header::HeaderName::from_static("destination"), self.url(&to)),
+                        (header::HeaderName::from_static("overwrite"), "F".to_string()),
+                    ],
+                    Body::empty(),
+                )
+                .await;
+
+            match result {
+                Ok(_) => break,
+                // Same as PUT: 409 means the destination's parent is missing
+                Err(e) if attempt == 0 && has_status(&e, StatusCode::CONFLICT) => {
+                    if let Some((parent, _)) = to.rsplit_once('/') {
+                        self.create_directory(parent).await?;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.get_item(&to)
+            .await?
+            .ok_or_else(|| anyhow!("Moved item not found: {}", to))
+    }
+
+    /// WebDAV has no standard change feed, so remote changes are found by rescans
+    async fn changes_since(&self, _remote_path: &str, _cursor: Option<&str>) -> Result<Option<ChangeFeed>> {
+        Ok(None)
+    }
+
+    async fn get_mappings(&self) -> Vec<FolderMapping> {
+        self.mappings.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use wiremock::{
+        matchers::{header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    const LISTING: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/dav/files/alice/docs/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/dav/files/alice/docs/Annual%20Report.pdf</d:href>
+    <d:propstat><d:prop>
+      <d:resourcetype/>
+      <d:getcontentlength>2048</d:getcontentlength>
+      <d:getlastmodified>Mon, 01 Jan 2024 10:00:00 GMT</d:getlastmodified>
+      <d:getetag>"abc123"</d:getetag>
+    </d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/dav/files/alice/docs/archive/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+</d:multistatus>"#;
+
+    fn provider(server: &MockServer, auth: WebDavAuth) -> WebDavProvider {
+        WebDavProvider::new(&format!("{}/dav/files/alice/", server.uri()), auth, Vec::new())
+    }
+
+    #[tokio::test]
+    async fn test_list_files() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("PROPFIND"))
+            .and(path("/dav/files/alice/docs"))
+            .and(header("depth", "1"))
+            .and(header("authorization", "Basic YWxpY2U6c2VjcmV0"))
+            .respond_with(ResponseTemplate::new(207).set_body_string(LISTING))
+            .mount(&server)
+            .await;
+
+        let auth = WebDavAuth::Basic {
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        };
+        let files = provider(&server, auth).list_files("/docs").await?;
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].name, "Annual Report.pdf");
+        assert_eq!(files[0].id, "/docs/Annual Report.pdf");
+        assert_eq!(files[0].size, 2048);
+        assert_eq!(files[0].etag.as_deref(), Some("abc123"));
+        assert_eq!(files[0].modified.to_rfc3339(), "2024-01-01T10:00:00+00:00");
+        assert!(!files[0].is_folder);
+        assert_eq!(files[1].name, "archive");
+        assert!(files[1].is_folder);
+        assert_eq!(files[1].modified, DateTime::<Utc>::UNIX_EPOCH);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_creates_missing_collections() -> Result<()> {
+        let server = MockServer::start().await;
+        let dir = tempdir()?;
+        let source = dir.path().join("notes.txt");
+        fs::write(&source, b"hello").await?;
+
+        Mock::given(method("PUT"))
+            .and(path("/dav/files/alice/new/notes.txt"))
+            .and(header("authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(409))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/dav/files/alice/new/notes.txt"))
+            .respond_with(ResponseTemplate::new(201))
+            .mount(&server)
+            .await;
+        Mock::given(method("MKCOL"))
+            .and(path("/dav/files/alice/new"))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PROPFIND"))
+            .and(path("/dav/files/alice/new"))
+            .respond_with(ResponseTemplate::new(207).set_body_string(
+                r#"<multistatus xmlns="DAV:"><response><href>/dav/files/alice/new/</href>
+                <propstat><prop><resourcetype><collection/></resourcetype></prop></propstat>
+                </response></multistatus>"#,
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("PROPFIND"))
+            .and(path("/dav/files/alice/new/notes.txt"))
+            .and(header("depth", "0"))
+            .respond_with(ResponseTemplate::new(207).set_body_string(
+                r#"<multistatus xmlns="DAV:"><response><href>/dav/files/alice/new/notes.txt</href>
+                <propstat><prop><getcontentlength>5</getcontentlength><getetag>"v1"</getetag></prop></propstat>
+                </response></multistatus>"#,
+            ))
+            .mount(&server)
+            .await;
+
+        let provider = provider(&server, WebDavAuth::Bearer("token".to_string()));
+        let uploaded = provider.upload_file(&source, "/new/notes.txt").await?;
+
+        assert_eq!(uploaded.name, "notes.txt");
+        assert_eq!(uploaded.size, 5);
+        assert_eq!(uploaded.etag.as_deref(), Some("v1"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_missing_item() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("PROPFIND"))
+            .and(path("/dav/files/alice/missing.txt"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let provider = provider(&server, WebDavAuth::None);
+        assert!(provider.get_item("/missing.txt").await?.is_none());
+        assert!(provider.delete("/missing.txt").await.is_err());
+
+        Ok(())
+    }
+}
@@ -1,15 +1,31 @@
-use anyhow::Result;
-use tokio::sync::mpsc;
-use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use tokio::sync::{mpsc, Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 use crate::{
-    config::ProviderConfig,
-    provider::{factory, CloudProvider, ChangeType, RemoteItem},
-    sync::SyncOperation,
+    config::{FolderMapping, ProviderConfig, WatchMode},
+    provider::{factory, CloudProvider},
+    state::SyncState,
+    sync::{filter::is_ignore_file, MappingPlan, SafeSync, SyncOperation},
+    watcher::{is_network_filesystem, ChangeType, FileWatcher},
 };
 
+/// How long the local handler waits for more changes before syncing a batch
+const BATCH_WINDOW: Duration = Duration::from_millis(500);
+const MAX_BATCH: usize = 10_000;
+const PURGE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+/// How often a provider's change feed is asked for remote changes
+const REMOTE_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct SyncService {
     providers: Vec<ProviderConfig>,
     active_providers: HashMap<String, Box<dyn CloudProvider>>,
+    watchers: Vec<FileWatcher>,
+    dry_run: bool,
+    plans: Vec<MappingPlan>,
 }
 
 impl SyncService {
@@ -17,92 +33,156 @@ impl SyncService {
         Self {
             providers,
             active_providers: HashMap::new(),
+            watchers: Vec::new(),
+            dry_run: false,
+            plans: Vec::new(),
         }
     }
 
+    /// In a dry run, `start` only plans each mapping and changes nothing on either side
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// The plans computed by a dry run
+    pub fn plans(&self) -> &[MappingPlan] {
+        &self.plans
+    }
+
     pub async fn start(&mut self) -> Result<()> {
         // Initialize providers
         for provider in &self.providers {
             if provider.enabled {
-                println!("Starting sync for provider: {}", provider.name);
+                // A dry run is unattended, it must not stop to log in through the browser
+                if self.dry_run && factory::needs_login(&provider.credentials) {
+                    return Err(anyhow!(
+                        "Provider {} is not logged in yet, run a normal sync once to log in before a dry run",
+                        provider.name
+                    ));
+                }
                 let mut provider_instance = factory::create_provider(provider).await?;
                 provider_instance.initialize().await?;
-                
-                // Set up change monitoring channels
+
+                if self.dry_run {
+                    let mappings = provider_instance.get_mappings().await;
+                    let sync_op = SyncOperation::new(provider_instance);
+                    for mapping in &mappings {
+                        let state = SyncState::for_mapping(&provider.name, mapping).await?;
+                        let actions = sync_op.plan_mapping(mapping, &state).await?;
+                        self.plans.push(MappingPlan::new(&provider.name, mapping, actions));
+                    }
+                    continue;
+                }
+
+                println!("Starting sync for provider: {}", provider.name);
+                // Set up the local change channel
                 let (local_tx, mut local_rx) = mpsc::channel(100);
-                let (remote_tx, mut remote_rx) = mpsc::channel(100);
 
                 // Get provider mappings
                 let mappings = provider_instance.get_mappings().await;
 
                 // Create sync operation handler
-                let sync_op = SyncOperation::new(provider_instance);
-                let sync_op_clone = SyncOperation::new(factory::create_provider(provider).await?);
+                let sync_op = Arc::new(SyncOperation::new(provider_instance));
 
-                // Start monitoring for each mapping
+                // Catch up on everything that changed while we were not running
+                let mut states = Vec::with_capacity(mappings.len());
                 for mapping in &mappings {
-                    let local_tx = local_tx.clone();
-                    let remote_tx = remote_tx.clone();
-                    let mapping_clone = mapping.clone();
-                    let provider_instance = sync_op.provider.as_ref();
-
-                    // Monitor local changes
-                    tokio::spawn(async move {
-                        if let Err(e) = provider_instance.watch_local_changes(&mapping_clone.local_path, local_tx).await {
-                            eprintln!("Error watching local changes: {}", e);
+                    let state = Arc::new(SyncState::for_mapping(&provider.name, mapping).await?);
+                    // Take the feed's cursor first, so nothing that changes during the scan is missed
+                    if mapping.mode.downloads() && state.remote_cursor().await.is_none() {
+                        if let Err(e) = sync_op.pull_remote_changes(mapping, &state).await {
+                            eprintln!("Error following remote changes of {}: {}", mapping.remote_path, e);
                         }
-                    });
+                    }
+                    match sync_op.reconcile_mapping(mapping, &state).await {
+                        Ok(applied) => println!(
+                            "Reconciled {:?} <-> {}: {} change(s)",
+                            mapping.local_path, mapping.remote_path, applied.len()
+                        ),
+                        Err(e) => eprintln!("Error reconciling {:?}: {}", mapping.local_path, e),
+                    }
+                    states.push(state);
+                }
+                // The watcher, the change feed and the purge all work on the same files and
+                // state; one lock per mapping keeps an upload and a download of a path apart
+                let busy: Vec<Arc<Mutex<()>>> = mappings.iter().map(|_| Arc::new(Mutex::new(()))).collect();
 
-                    // Monitor remote changes
+                // Start monitoring for each mapping
+                for ((mapping, state), busy) in mappings.iter().zip(&states).zip(&busy) {
+                    // Monitor local changes, polling where events would miss what other machines do
+                    let rescan_interval = mapping.rescan_minutes.map(|minutes| Duration::from_secs(u64::from(minutes) * 60));
+                    let poll = match mapping.watch {
+                        WatchMode::Native => false,
+                        WatchMode::Poll => true,
+                        WatchMode::Auto => is_network_filesystem(&mapping.local_path),
+                    };
+                    if poll {
+                        println!("Polling {:?} every {}s", mapping.local_path, mapping.poll_interval().as_secs());
+                    }
+                    let mut watcher = FileWatcher::new(mapping.local_path.clone())
+                        .with_rescan_interval(rescan_interval)
+                        .with_poll_interval(poll.then(|| mapping.poll_interval()))
+                        .with_settle_time(mapping.settle_time());
+                    match watcher.start(local_tx.clone()).await {
+                        Ok(()) => self.watchers.push(watcher),
+                        Err(e) => eprintln!("Error watching {:?}: {}", mapping.local_path, e),
+                    }
+
+                    // Backups and archives never take anything from the remote side
+                    if !mapping.mode.downloads() {
+                        continue;
+                    }
+
+                    // Monitor remote changes, through the provider's change feed if it has one
+                    let feed_op = Arc::clone(&sync_op);
+                    let feed_mapping = mapping.clone();
+                    let feed_state = Arc::clone(state);
+                    let feed_busy = Arc::clone(busy);
                     tokio::spawn(async move {
-                        if let Err(e) = provider_instance.watch_remote_changes(&mapping_clone.remote_path, remote_tx).await {
-                            eprintln!("Error watching remote changes: {}", e);
-                        }
+                        follow_remote_changes(&feed_op, &feed_mapping, &feed_state, &feed_busy).await;
                     });
                 }
 
-                let mappings_clone = mappings.clone();
-
-                // Handle local changes
+                // Empty the trash folders and prune old versions now and then, starting right away
+                let purge_op = Arc::clone(&sync_op);
+                let purge_mappings = mappings.clone();
+                let purge_states = states.clone();
+                let purge_busy = busy.clone();
                 tokio::spawn(async move {
-                    while let Some(change) = local_rx.recv().await {
-                        for mapping in &mappings {
-                            match &change {
-                                ChangeType::Created(path) | ChangeType::Modified(path) => {
-                                    if let Some(remote_path) = sync_op.get_remote_path(path, mapping) {
-                                        let result = match change {
-                                            ChangeType::Created(_) => {
-                                                sync_op.handle_local_create(path, &remote_path).await
-                                            }
-                                            ChangeType::Modified(_) => {
-                                                sync_op.handle_local_modify(path, &remote_path).await
-                                            }
-                                            _ => Ok(()),
-                                        };
-
-                                        if let Err(e) = result {
-                                            eprintln!("Error handling local change: {}", e);
-                                        }
-                                    }
-                                }
-                                ChangeType::Deleted(path) => {
-                                    if let Some(remote_path) = sync_op.get_remote_path(path, mapping) {
-                                        if let Err(e) = sync_op.handle_local_delete(&remote_path).await {
-                                            eprintln!("Error handling local deletion: {}", e);
-                                        }
-                                    }
+                    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+                    loop {
+                        interval.tick().await;
+                        for ((mapping, state), busy) in purge_mappings.iter().zip(&purge_states).zip(&purge_busy) {
+                            let _busy = busy.lock().await;
+                            if let Err(e) = purge_op.purge_trash(mapping).await {
+                                eprintln!("Error purging the trash of {:?}: {}", mapping.local_path, e);
+                            }
+                            if let Some(retention) = mapping.versions {
+                                let store = SafeSync::new(state.versions_dir()).with_retention(retention);
+                                if let Err(e) = store.prune().await {
+                                    eprintln!("Error pruning the versions of {:?}: {}", mapping.local_path, e);
                                 }
                             }
                         }
                     }
                 });
 
-                // Handle remote changes
+                // Handle local changes in batches, so the deletion guard sees an `rm -rf` as a whole
                 tokio::spawn(async move {
-                    while let Some(item) = remote_rx.recv().await {
-                        for mapping in &mappings_clone {
-                            if let Err(e) = sync_op_clone.handle_remote_change(item.clone(), &mapping.local_path).await {
-                                eprintln!("Error handling remote change: {}", e);
+                    while let Some(first) = local_rx.recv().await {
+                        let mut batch = vec![first];
+                        while batch.len() < MAX_BATCH {
+                            match tokio::time::timeout(BATCH_WINDOW, local_rx.recv()).await {
+                                Ok(Some(change)) => batch.push(change),
+                                _ => break,
+                            }
+                        }
+
+                        for ((mapping, state), busy) in mappings.iter().zip(&states).zip(&busy) {
+                            let _busy = busy.lock().await;
+                            if let Err(e) = handle_local_batch(&sync_op, mapping, state, &batch).await {
+                                eprintln!("Error handling local change: {}", e);
                             }
                         }
                     }
@@ -116,9 +196,116 @@ impl SyncService {
 
     pub async fn stop(&mut self) -> Result<()> {
         // Clean up resources and stop sync
+        for watcher in &mut self.watchers {
+            watcher.stop().await?;
+        }
+        self.watchers.clear();
         self.active_providers.clear();
         Ok(())
     }
 }
 
+/// Apply remote changes from the provider's change feed until the service
+/// stops, or until it turns out the provider has no change feed. Holds
+/// `busy` while it works on the mapping.
+async fn follow_remote_changes(sync_op: &SyncOperation, mapping: &FolderMapping, state: &SyncState, busy: &Mutex<()>) {
+    let mut interval = tokio::time::interval(REMOTE_POLL_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let _busy = busy.lock().await;
+        if state.paused().await.is_some() {
+            // Confirmed in another process maybe, the stored cursor then covers what happened since
+            if let Err(e) = state.refresh().await {
+                eprintln!("Error refreshing the state of {:?}: {}", mapping.local_path, e);
+            }
+            if state.paused().await.is_some() {
+                continue;
+            }
+        }
+        match sync_op.pull_remote_changes(mapping, state).await {
+            Ok(Some(applied)) if !applied.is_empty() => {
+                println!("Applied {} remote change(s) to {:?}", applied.len(), mapping.local_path);
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                println!(
+                    "{} has no change feed, remote changes reach {:?} on rescans",
+                    mapping.remote_path, mapping.local_path
+                );
+                break;
+            }
+            Err(e) => eprintln!("Error following remote changes of {}: {}", mapping.remote_path, e),
+        }
+    }
+}
+
+/// Sync one batch of local changes into a mapping
+async fn handle_local_batch(
+    sync_op: &SyncOperation,
+    mapping: &FolderMapping,
+    state: &SyncState,
+    batch: &[ChangeType],
+) -> Result<()> {
+    if state.paused().await.is_some() {
+        // Confirmation happens in another process, so look at what it saved
+        state.refresh().await?;
+        if state.paused().await.is_some() {
+            return Ok(());
+        }
+        // Settled since, so catch up on everything that happened in the meantime
+        return sync_op.reconcile_mapping(mapping, state).await.map(|_| ());
+    }
+
+    let mut paths = Vec::new();
+    let mut rescan = false;
+    for change in batch {
+        match change {
+            ChangeType::Created(path) | ChangeType::Modified(path) | ChangeType::Deleted(path) => {
+                let Some(relative) = SyncOperation::relative_path(path, mapping) else { continue };
+                // New ignore rules can let in or leave out anything, so look at the whole mapping again
+                rescan |= is_ignore_file(&relative);
 
+                // A folder comes and goes with everything in it, often as a single event
+                let folder = match change {
+                    ChangeType::Created(_) => path.is_dir(),
+                    ChangeType::Deleted(_) => state.get(&relative).await.is_none(),
+                    _ => false,
+                };
+                if folder {
+                    paths.extend(sync_op.paths_under(mapping, state, &relative).await?);
+                } else {
+                    paths.push(relative);
+                }
+            }
+            ChangeType::Renamed { from, to } => {
+                match (SyncOperation::relative_path(from, mapping), SyncOperation::relative_path(to, mapping)) {
+                    (Some(from), Some(to)) => {
+                        if let Err(e) = sync_op.handle_local_rename(mapping, state, &from, &to).await {
+                            eprintln!("Error handling local rename: {}", e);
+                        }
+                    }
+                    // Moved out of or into the mapping, a deletion or a creation from its point of view
+                    (Some(relative), None) | (None, Some(relative)) => {
+                        paths.extend(sync_op.paths_under(mapping, state, &relative).await?);
+                    }
+                    (None, None) => {}
+                }
+            }
+            ChangeType::Rescan(path) => {
+                if mapping.local_path.starts_with(path) {
+                    rescan = true;
+                } else if let Some(relative) = SyncOperation::relative_path(path, mapping) {
+                    paths.extend(sync_op.paths_under(mapping, state, &relative).await?);
+                }
+            }
+        }
+    }
+
+    if rescan {
+        return sync_op.reconcile_mapping(mapping, state).await.map(|_| ());
+    }
+    let mut seen = HashSet::new();
+    paths.retain(|path| seen.insert(path.clone()));
+    sync_op.sync_batch(mapping, state, &paths).await.map(|_| ())
+}
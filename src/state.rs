@@ -0,0 +1,647 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+use tokio::{fs, io::AsyncReadExt, sync::RwLock};
+
+use crate::config::{ConflictStrategy, FolderMapping, SyncMode};
+use crate::provider::RemoteItem;
+
+const STATE_VERSION: u32 = 1;
+// Remote mtimes are often truncated to the second
+const MTIME_TOLERANCE_SECS: i64 = 2;
+
+/// A file as it was on the local disk when both sides last agreed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LocalVersion {
+    pub hash: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    /// Lets a scan recognize a renamed file even if it was edited too
+    #[serde(default)]
+    pub inode: Option<u64>,
+}
+
+/// The same file as the provider reported it at that point
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteVersion {
+    pub id: String,
+    pub etag: Option<String>,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+impl From<&RemoteItem> for RemoteVersion {
+    fn from(item: &RemoteItem) -> Self {
+        Self {
+            id: item.id.clone(),
+            etag: item.etag.clone(),
+            size: item.size,
+            modified: item.modified,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncEntry {
+    pub local: LocalVersion,
+    pub remote: RemoteVersion,
+    pub synced_at: DateTime<Utc>,
+}
+
+/// What the engine should do with one path after comparing both sides with the last synced state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Decision {
+    /// Nothing changed since the last sync
+    InSync,
+    /// Both sides already match but the stored state is stale or missing
+    Record,
+    Upload,
+    Download,
+    DeleteLocal,
+    DeleteRemote,
+    /// Renamed locally, move the remote item along
+    MoveRemote,
+    /// Renamed remotely, move the local file along
+    MoveLocal,
+    /// Gone from both sides, only the stored entry is left
+    Forget,
+    /// Changed on both sides
+    Conflict,
+    /// A change the mapping's mode or filters do not propagate
+    Ignore,
+}
+
+impl Decision {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Decision::InSync => "in-sync",
+            Decision::Record => "record",
+            Decision::Upload => "upload",
+            Decision::Download => "download",
+            Decision::DeleteLocal => "delete-local",
+            Decision::DeleteRemote => "delete-remote",
+            Decision::MoveRemote => "move-remote",
+            Decision::MoveLocal => "move-local",
+            Decision::Forget => "forget",
+            Decision::Conflict => "conflict",
+            Decision::Ignore => "ignore",
+        }
+    }
+}
+
+fn local_changed(base: &LocalVersion, current: &LocalVersion) -> bool {
+    base.size != current.size || base.hash != current.hash
+}
+
+fn remote_changed(base: &RemoteVersion, current: &RemoteVersion) -> bool {
+    if base.id != current.id {
+        return true;
+    }
+    match (&base.etag, &current.etag) {
+        (Some(base_etag), Some(current_etag)) => base_etag != current_etag,
+        _ => base.size != current.size || base.modified != current.modified,
+    }
+}
+
+fn looks_identical(local: &LocalVersion, remote: &RemoteVersion) -> bool {
+    local.size == remote.size
+        && (local.modified - remote.modified).num_seconds().abs() <= MTIME_TOLERANCE_SECS
+}
+
+/// Three-way comparison of the current local and remote versions against the stored base
+pub fn reconcile(
+    base: Option<&SyncEntry>,
+    local: Option<&LocalVersion>,
+    remote: Option<&RemoteVersion>,
+) -> Decision {
+    let Some(base) = base else {
+        return match (local, remote) {
+            (None, None) => Decision::InSync,
+            (Some(_), None) => Decision::Upload,
+            (None, Some(_)) => Decision::Download,
+            (Some(local), Some(remote)) if looks_identical(local, remote) => Decision::Record,
+            (Some(_), Some(_)) => Decision::Conflict,
+        };
+    };
+
+    let local_dirty = local.is_none_or(|local| local_changed(&base.local, local));
+    let remote_dirty = remote.is_none_or(|remote| remote_changed(&base.remote, remote));
+
+    match (local_dirty, remote_dirty, local, remote) {
+        (false, false, _, _) => Decision::InSync,
+        (true, false, Some(_), _) => Decision::Upload,
+        (true, false, None, _) => Decision::DeleteRemote,
+        (false, true, _, Some(_)) => Decision::Download,
+        (false, true, _, None) => Decision::DeleteLocal,
+        (true, true, None, None) => Decision::Forget,
+        // A deletion on one side loses against an edit on the other
+        (true, true, None, Some(_)) => Decision::Download,
+        (true, true, Some(_), None) => Decision::Upload,
+        (true, true, Some(_), Some(_)) => Decision::Conflict,
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Narrow a two-way decision to what the mapping's mode allows. One-way
+/// modes re-assert their source side instead of taking the other side's change.
+pub fn restrict(decision: Decision, mode: SyncMode, has_local: bool, has_remote: bool) -> Decision {
+    let push_local = if has_local { Decision::Upload } else { Decision::Ignore };
+    let pull_remote = if has_remote { Decision::Download } else { Decision::Ignore };
+
+    match (mode, decision) {
+        (SyncMode::TwoWay, decision) => decision,
+        (SyncMode::UploadOnly | SyncMode::Archive, Decision::Download | Decision::DeleteLocal | Decision::Conflict) => {
+            push_local
+        }
+        (SyncMode::Archive, Decision::DeleteRemote) => Decision::Forget,
+        (SyncMode::DownloadOnly, Decision::Upload | Decision::DeleteRemote | Decision::Conflict) => pull_remote,
+        (_, decision) => decision,
+    }
+}
+
+/// SHA-256 of a local file, hex encoded
+pub async fn hash_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// A path that changed on both sides, as seen when it was detected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConflictRecord {
+    pub relative: String,
+    pub detected_at: DateTime<Utc>,
+    pub strategy: ConflictStrategy,
+    /// How it was settled, `None` while it waits for manual resolution
+    pub resolution: Option<String>,
+}
+
+/// Deletions the deletion guard held back, waiting for confirmation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PausedDeletions {
+    pub detected_at: DateTime<Utc>,
+    /// Files gone remotely that would be deleted locally
+    pub delete_local: Vec<String>,
+    /// Files gone locally that would be deleted remotely
+    pub delete_remote: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    entries: BTreeMap<String, SyncEntry>,
+    #[serde(default)]
+    conflicts: BTreeMap<String, ConflictRecord>,
+    #[serde(default)]
+    paused: Option<PausedDeletions>,
+    /// Where the provider's change feed continues from
+    #[serde(default)]
+    remote_cursor: Option<String>,
+}
+
+/// One change to the entries, kept until the next flush so it can be
+/// replayed onto whatever another process saved in the meantime
+#[derive(Debug, Clone)]
+enum Edit {
+    Set(String, SyncEntry),
+    /// Drops the path and everything below it
+    Remove(String),
+    /// Re-keys the path and everything below it
+    Rename(String, String),
+}
+
+impl Edit {
+    fn apply(&self, inner: &mut StateFile) {
+        match self {
+            Edit::Set(relative, entry) => {
+                inner.entries.insert(relative.clone(), entry.clone());
+            }
+            Edit::Remove(relative) => {
+                let prefix = format!("{}/", relative);
+                inner.entries.retain(|key, _| key != relative && !key.starts_with(&prefix));
+            }
+            Edit::Rename(from, to) => {
+                let prefix = format!("{}/", from);
+                let moved: Vec<String> = inner
+                    .entries
+                    .keys()
+                    .filter(|key| *key == from || key.starts_with(&prefix))
+                    .cloned()
+                    .collect();
+
+                for key in moved {
+                    let entry = inner.entries.remove(&key).expect("key was just listed");
+                    inner.entries.insert(format!("{}{}", to, &key[from.len()..]), entry);
+                }
+            }
+        }
+    }
+}
+
+/// Last-agreed state of every file in one mapping, keyed by the path
+/// relative to the mapping root with `/` separators, plus the conflicts
+/// seen in it. Persisted as JSON so it survives restarts: changes to the
+/// entries pile up in memory until `flush`, everything else is saved at once.
+///
+/// The service and the CLI can have the same file open. Every change takes
+/// an advisory lock on the file and picks up what the other process saved
+/// before applying itself, so neither overwrites the other.
+pub struct SyncState {
+    path: PathBuf,
+    inner: RwLock<StateFile>,
+    /// Modification time and size of the file when it was last read or written
+    seen: Mutex<Option<(SystemTime, u64)>>,
+    /// Entry changes applied in memory but not saved yet
+    pending: Mutex<Vec<Edit>>,
+}
+
+impl SyncState {
+    pub async fn open(path: PathBuf) -> Result<Self> {
+        let seen = Self::stamp(&path).await;
+        let inner = Self::read(&path).await?;
+        Ok(Self { path, inner: RwLock::new(inner), seen: Mutex::new(seen), pending: Mutex::new(Vec::new()) })
+    }
+
+    async fn read(path: &Path) -> Result<StateFile> {
+        if fs::try_exists(path).await? {
+            let content = fs::read_to_string(path).await?;
+            let file = serde_json::from_str::<StateFile>(&content)?;
+            if file.version != STATE_VERSION {
+                bail!("{:?} is state version {}, this build only reads version {}", path, file.version, STATE_VERSION);
+            }
+            Ok(file)
+        } else {
+            Ok(StateFile { version: STATE_VERSION, ..Default::default() })
+        }
+    }
+
+    /// Where the version store of this mapping keeps its data, next to the state file
+    pub fn versions_dir(&self) -> PathBuf {
+        self.path.with_extension("versions")
+    }
+
+    async fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(path).await.ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    /// Pick up changes another process, such as the CLI, saved to the file
+    /// since it was last read or written here. Unsaved entry changes are kept.
+    pub async fn refresh(&self) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        self.reload_into(&mut inner).await
+    }
+
+    async fn reload_into(&self, inner: &mut StateFile) -> Result<()> {
+        let stamp = Self::stamp(&self.path).await;
+        if stamp != *self.seen.lock().unwrap() {
+            *inner = Self::read(&self.path).await?;
+            for edit in self.pending.lock().unwrap().iter() {
+                edit.apply(inner);
+            }
+            *self.seen.lock().unwrap() = stamp;
+        }
+        Ok(())
+    }
+
+    /// Exclusive advisory lock on `<name>.lock` next to the state file,
+    /// released when the returned file is dropped
+    async fn lock(&self) -> Result<std::fs::File> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let path = self.path.with_extension("lock");
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+            file.lock()?;
+            Ok(file)
+        })
+        .await?
+    }
+
+    /// Apply `change` to the latest saved state and write it back along with
+    /// any unsaved entry changes, holding the lock so no other process saves in between
+    async fn update(&self, change: impl FnOnce(&mut StateFile)) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        let _lock = self.lock().await?;
+        self.reload_into(&mut inner).await?;
+        change(&mut inner);
+        self.save(&inner).await?;
+        self.pending.lock().unwrap().clear();
+        Ok(())
+    }
+
+    async fn edit(&self, edit: Edit) {
+        let mut inner = self.inner.write().await;
+        edit.apply(&mut inner);
+        self.pending.lock().unwrap().push(edit);
+    }
+
+    /// Save the entry changes made since the last save, in one write
+    pub async fn flush(&self) -> Result<()> {
+        if self.pending.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+        self.update(|_| {}).await
+    }
+
+    /// Open the state file for a mapping under the user's data directory
+    pub async fn for_mapping(provider_name: &str, mapping: &FolderMapping) -> Result<Self> {
+        let dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("filesynchub")
+            .join("state");
+        Self::open(dir.join(Self::file_name(provider_name, mapping))).await
+    }
+
+    fn file_name(provider_name: &str, mapping: &FolderMapping) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(mapping.local_path.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(mapping.remote_path.as_bytes());
+        let digest = hex::encode(hasher.finalize());
+
+        let name: String = provider_name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        format!("{}-{}.json", name, &digest[..16])
+    }
+
+    pub async fn get(&self, relative: &str) -> Option<SyncEntry> {
+        self.inner.read().await.entries.get(relative).cloned()
+    }
+
+    pub async fn entries(&self) -> BTreeMap<String, SyncEntry> {
+        self.inner.read().await.entries.clone()
+    }
+
+    /// Paths of the entries for `relative` and everything below it
+    pub async fn paths_under(&self, relative: &str) -> Vec<String> {
+        let inner = self.inner.read().await;
+        let prefix = format!("{}/", relative);
+        let below = inner.entries.range(prefix.clone()..).take_while(|(key, _)| key.starts_with(&prefix));
+        inner.entries.get_key_value(relative).into_iter().chain(below).map(|(key, _)| key.clone()).collect()
+    }
+
+    /// Store the entry for `relative`, saved on the next `flush`
+    pub async fn set(&self, relative: &str, entry: SyncEntry) {
+        self.edit(Edit::Set(relative.to_string(), entry)).await
+    }
+
+    /// Drop the entry for `relative` and everything below it, saved on the next `flush`
+    pub async fn remove(&self, relative: &str) {
+        self.edit(Edit::Remove(relative.to_string())).await
+    }
+
+    /// Re-key the entry for `from`, and everything below it, to `to`, saved on the next `flush`
+    pub async fn rename(&self, from: &str, to: &str) {
+        self.edit(Edit::Rename(from.to_string(), to.to_string())).await
+    }
+
+    /// Store a conflict, replacing any earlier record for the same path. A
+    /// conflict that is still open keeps the time it was first detected.
+    pub async fn record_conflict(&self, mut record: ConflictRecord) -> Result<()> {
+        self.update(|inner| {
+            if let Some(open) = inner.conflicts.get(&record.relative).filter(|open| open.resolution.is_none()) {
+                record.detected_at = open.detected_at;
+            }
+            inner.conflicts.insert(record.relative.clone(), record);
+        })
+        .await
+    }
+
+    pub async fn conflicts(&self) -> Vec<ConflictRecord> {
+        self.inner.read().await.conflicts.values().cloned().collect()
+    }
+
+    pub async fn unresolved_conflicts(&self) -> Vec<ConflictRecord> {
+        let inner = self.inner.read().await;
+        inner.conflicts.values().filter(|record| record.resolution.is_none()).cloned().collect()
+    }
+
+    /// Hold the mapping until the deletions are confirmed. While paused, the
+    /// service leaves the mapping alone and waits for the CLI to confirm them.
+    pub async fn pause(&self, deletions: PausedDeletions) -> Result<()> {
+        self.update(|inner| inner.paused = Some(deletions)).await
+    }
+
+    pub async fn paused(&self) -> Option<PausedDeletions> {
+        self.inner.read().await.paused.clone()
+    }
+
+    pub async fn resume(&self) -> Result<()> {
+        self.update(|inner| inner.paused = None).await
+    }
+
+    /// Where the provider's change feed for this mapping continues from
+    pub async fn remote_cursor(&self) -> Option<String> {
+        self.inner.read().await.remote_cursor.clone()
+    }
+
+    pub async fn set_remote_cursor(&self, cursor: &str) -> Result<()> {
+        self.update(|inner| inner.remote_cursor = Some(cursor.to_string())).await
+    }
+
+    /// Current version of a local file, reusing the stored hash when size and mtime are unchanged
+    pub async fn local_version(&self, relative: &str, path: &Path) -> Result<Option<LocalVersion>> {
+        let metadata = match fs::metadata(path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return Ok(None),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let size = metadata.len();
+        let modified: DateTime<Utc> = metadata.modified()?.into();
+
+        if let Some(entry) = self.get(relative).await {
+            if entry.local.size == size && entry.local.modified == modified {
+                return Ok(Some(entry.local));
+            }
+        }
+
+        Ok(Some(LocalVersion { hash: hash_file(path).await?, size, modified, inode: inode(&metadata) }))
+    }
+
+    async fn save(&self, inner: &StateFile) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let content = serde_json::to_string_pretty(inner)?;
+
+        // Write then rename so a crash never leaves a truncated state file behind
+        let partial = self.path.with_extension("json.partial");
+        fs::write(&partial, content).await?;
+        fs::rename(&partial, &self.path).await?;
+        *self.seen.lock().unwrap() = Self::stamp(&self.path).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn local(hash: &str, size: u64) -> LocalVersion {
+        LocalVersion { hash: hash.to_string(), size, modified: DateTime::<Utc>::UNIX_EPOCH, inode: None }
+    }
+
+    fn remote(etag: &str, size: u64) -> RemoteVersion {
+        RemoteVersion {
+            id: "/a.txt".to_string(),
+            etag: Some(etag.to_string()),
+            size,
+            modified: DateTime::<Utc>::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn test_reconcile_three_way() {
+        let base = SyncEntry { local: local("h1", 1), remote: remote("e1", 1), synced_at: Utc::now() };
+        let base = Some(&base);
+
+        assert_eq!(reconcile(base, Some(&local("h1", 1)), Some(&remote("e1", 1))), Decision::InSync);
+        assert_eq!(reconcile(base, Some(&local("h2", 1)), Some(&remote("e1", 1))), Decision::Upload);
+        assert_eq!(reconcile(base, Some(&local("h1", 1)), Some(&remote("e2", 3))), Decision::Download);
+        assert_eq!(reconcile(base, Some(&local("h2", 2)), Some(&remote("e2", 3))), Decision::Conflict);
+        assert_eq!(reconcile(base, None, Some(&remote("e1", 1))), Decision::DeleteRemote);
+        assert_eq!(reconcile(base, Some(&local("h1", 1)), None), Decision::DeleteLocal);
+        assert_eq!(reconcile(base, None, Some(&remote("e2", 3))), Decision::Download);
+        assert_eq!(reconcile(base, None, None), Decision::Forget);
+
+        assert_eq!(reconcile(None, Some(&local("h1", 1)), None), Decision::Upload);
+        assert_eq!(reconcile(None, Some(&local("h1", 1)), Some(&remote("e1", 1))), Decision::Record);
+        assert_eq!(reconcile(None, Some(&local("h1", 1)), Some(&remote("e1", 5))), Decision::Conflict);
+    }
+
+    #[test]
+    fn test_restrict_to_mode() {
+        assert_eq!(restrict(Decision::DeleteLocal, SyncMode::UploadOnly, true, false), Decision::Upload);
+        assert_eq!(restrict(Decision::Download, SyncMode::UploadOnly, false, true), Decision::Ignore);
+        assert_eq!(restrict(Decision::DeleteRemote, SyncMode::UploadOnly, false, true), Decision::DeleteRemote);
+        assert_eq!(restrict(Decision::DeleteRemote, SyncMode::Archive, false, true), Decision::Forget);
+        assert_eq!(restrict(Decision::Upload, SyncMode::DownloadOnly, true, true), Decision::Download);
+        assert_eq!(restrict(Decision::Upload, SyncMode::DownloadOnly, true, false), Decision::Ignore);
+        assert_eq!(restrict(Decision::Conflict, SyncMode::TwoWay, true, true), Decision::Conflict);
+    }
+
+    #[tokio::test]
+    async fn test_state_survives_reopen() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("state.json");
+        let file = dir.path().join("a.txt");
+        fs::write(&file, b"abc").await?;
+
+        let state = SyncState::open(path.clone()).await?;
+        let version = state.local_version("a.txt", &file).await?.unwrap();
+        assert_eq!(version.hash, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let entry = SyncEntry { local: version, remote: remote("e1", 3), synced_at: Utc::now() };
+        state.set("docs/a.txt", entry.clone()).await;
+        state.set("docs/b.txt", entry.clone()).await;
+        state.set("other.txt", entry.clone()).await;
+        state.set("docs-old/c.txt", entry.clone()).await;
+        assert_eq!(state.paths_under("docs").await, ["docs/a.txt", "docs/b.txt"]);
+        state.remove("docs").await;
+        state.remove("docs-old").await;
+        state.set_remote_cursor("page-42").await?;
+
+        let reopened = SyncState::open(path).await?;
+        assert_eq!(reopened.entries().await.keys().collect::<Vec<_>>(), ["other.txt"]);
+        assert_eq!(reopened.get("other.txt").await, Some(entry));
+        assert_eq!(reopened.remote_cursor().await.as_deref(), Some("page-42"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_processes_do_not_overwrite_each_other() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("state.json");
+        let entry = SyncEntry { local: local("h1", 1), remote: remote("e1", 1), synced_at: Utc::now() };
+
+        // The service and the CLI, each with the file open
+        let service = SyncState::open(path.clone()).await?;
+        let cli = SyncState::open(path.clone()).await?;
+        cli.set("resolved.txt", entry.clone()).await;
+        cli.flush().await?;
+        service.set("synced.txt", entry.clone()).await;
+        service.flush().await?;
+        assert!(service.get("resolved.txt").await.is_some());
+
+        // Unsaved changes survive picking up what the other process saved
+        service.set("pending.txt", entry.clone()).await;
+        cli.remove("resolved.txt").await;
+        cli.flush().await?;
+        service.refresh().await?;
+        assert_eq!(service.entries().await.keys().collect::<Vec<_>>(), ["pending.txt", "synced.txt"]);
+        service.remove("pending.txt").await;
+        service.flush().await?;
+        assert_eq!(service.entries().await.keys().collect::<Vec<_>>(), ["synced.txt"]);
+        let reopened = SyncState::open(path).await?;
+        assert_eq!(reopened.entries().await.keys().collect::<Vec<_>>(), ["synced.txt"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_entries_are_saved_on_flush() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("state.json");
+        let entry = SyncEntry { local: local("h1", 1), remote: remote("e1", 1), synced_at: Utc::now() };
+
+        let state = SyncState::open(path.clone()).await?;
+        state.set("docs/a.txt", entry.clone()).await;
+        state.set("docs/b.txt", entry.clone()).await;
+        state.rename("docs", "papers").await;
+        assert!(!fs::try_exists(&path).await?);
+
+        state.flush().await?;
+        let reopened = SyncState::open(path).await?;
+        assert_eq!(reopened.entries().await.keys().collect::<Vec<_>>(), ["papers/a.txt", "papers/b.txt"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_version_is_rejected() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("state.json");
+        fs::write(&path, r#"{ "version": 2, "entries": {} }"#).await?;
+
+        let error = SyncState::open(path).await.err().unwrap();
+        assert!(error.to_string().contains("state version 2"));
+
+        Ok(())
+    }
+}
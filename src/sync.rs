@@ -1,68 +1,662 @@
-use anyhow::Result;
-use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Result};
+use chrono::{Local, Utc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tokio::fs;
 
+use crate::config::{ConflictStrategy, FolderMapping, SyncMode};
 use crate::provider::{CloudProvider, RemoteItem};
+use crate::state::{
+    ConflictRecord, Decision, LocalVersion, PausedDeletions, RemoteVersion, SyncEntry, SyncState,
+};
+
+pub mod feed;
+pub mod filter;
+pub mod plan;
+pub mod safety;
+pub mod scan;
+pub mod snapshot;
+pub mod trash;
+
+pub use filter::PathFilter;
+pub use safety::SafeSync;
+pub use plan::MappingPlan;
+pub use scan::SyncAction;
+pub use snapshot::RestorePlan;
 
 pub struct SyncOperation {
-    provider: Box<dyn CloudProvider>,
+    provider: Arc<dyn CloudProvider>,
 }
 
 impl SyncOperation {
     pub fn new(provider: Box<dyn CloudProvider>) -> Self {
-        Self { provider }
+        Self {
+            provider: Arc::from(provider),
+        }
     }
 
-    pub async fn handle_local_create(&self, local_path: &Path, remote_path: &str) -> Result<()> {
-        println!("Uploading new file: {:?} to {}", local_path, remote_path);
-        self.provider.upload_file(local_path, remote_path).await?;
-        Ok(())
+    pub fn provider(&self) -> Arc<dyn CloudProvider> {
+        Arc::clone(&self.provider)
     }
 
-    pub async fn handle_local_modify(&self, local_path: &Path, remote_path: &str) -> Result<()> {
-        println!("Uploading modified file: {:?} to {}", local_path, remote_path);
-        self.provider.upload_file(local_path, remote_path).await?;
-        Ok(())
+    /// Bring one file of a mapping in line with the other side, using the stored
+    /// state to tell which side changed. `relative` uses `/` separators.
+    pub async fn sync_path(&self, mapping: &FolderMapping, state: &SyncState, relative: &str) -> Result<Decision> {
+        let filter = PathFilter::for_mapping(mapping)?;
+        let action = self.inspect(mapping, state, &filter, relative).await?;
+        let applied = self.apply(mapping, state, &action).await;
+        state.flush().await?;
+        applied.map(|()| action.decision)
+    }
+
+    /// Look at both sides of one path and decide what to do, without touching
+    /// anything. `filter` is the mapping's, built once by the caller for all its paths.
+    pub async fn inspect(
+        &self,
+        mapping: &FolderMapping,
+        state: &SyncState,
+        filter: &PathFilter,
+        relative: &str,
+    ) -> Result<SyncAction> {
+        if !filter.allows_file(relative) {
+            // Filtered out after it was synced: stop tracking it, but leave both copies alone
+            let decision = if state.get(relative).await.is_some() { Decision::Forget } else { Decision::Ignore };
+            return Ok(SyncAction {
+                relative: relative.to_string(),
+                decision,
+                local: None,
+                remote: None,
+                moved_from: None,
+            });
+        }
+
+        let local_path = mapping.local_path.join(relative);
+        let remote_path = Self::remote_path_for(mapping, relative);
+
+        let base = state.get(relative).await;
+        let local = state.local_version(relative, &local_path).await?;
+        let remote = self
+            .provider
+            .get_item(&remote_path)
+            .await?
+            .filter(|item| !item.is_folder)
+            .map(|item| RemoteVersion::from(&item));
+
+        let decision = scan::decide(self.provider.as_ref(), mapping, relative, base.as_ref(), local.as_ref(), remote.as_ref()).await?;
+        Ok(SyncAction { relative: relative.to_string(), decision, local, remote, moved_from: None })
+    }
+
+    /// Everything `reconcile_mapping` would do, without touching either side
+    pub async fn plan_mapping(&self, mapping: &FolderMapping, state: &SyncState) -> Result<Vec<SyncAction>> {
+        scan::plan_mapping(self.provider.as_ref(), mapping, state).await
+    }
+
+    /// Startup pass over a whole mapping: diff both trees against the stored
+    /// state and apply the result. Failed actions are reported and skipped so
+    /// one bad file does not hold up the rest, and make the pass fail once
+    /// everything else is done.
+    pub async fn reconcile_mapping(&self, mapping: &FolderMapping, state: &SyncState) -> Result<Vec<SyncAction>> {
+        self.reconcile(mapping, state, true).await
+    }
+
+    async fn reconcile(&self, mapping: &FolderMapping, state: &SyncState, guarded: bool) -> Result<Vec<SyncAction>> {
+        Self::ensure_running(mapping, state).await?;
+        self.ensure_roots(mapping, state).await?;
+        let plan = self.plan_mapping(mapping, state).await?;
+        if guarded {
+            Self::guard_deletions(mapping, state, &plan).await?;
+        }
+        self.apply_all(mapping, state, plan, 0).await
     }
 
-    pub async fn handle_local_delete(&self, remote_path: &str) -> Result<()> {
-        println!("Deleting remote file: {}", remote_path);
-        self.provider.delete(remote_path).await?;
+    /// Sync a batch of changed paths at once, so the deletion guard sees an
+    /// `rm -rf` as a whole instead of one file at a time. Like
+    /// `reconcile_mapping`, it fails after the rest if any path failed.
+    pub async fn sync_batch(&self, mapping: &FolderMapping, state: &SyncState, relatives: &[String]) -> Result<Vec<SyncAction>> {
+        Self::ensure_running(mapping, state).await?;
+
+        let filter = PathFilter::for_mapping(mapping)?;
+        let mut plan = Vec::with_capacity(relatives.len());
+        let mut failed = 0;
+        for relative in relatives {
+            // A file that cannot be read, or vanished while hashed, must not hold up the rest
+            match self.inspect(mapping, state, &filter, relative).await {
+                Ok(action) if matches!(action.decision, Decision::InSync | Decision::Ignore) => {}
+                Ok(action) => plan.push(action),
+                Err(e) => {
+                    eprintln!("Error syncing {}: {}", relative, e);
+                    failed += 1;
+                }
+            }
+        }
+
+        if plan.iter().any(|action| matches!(action.decision, Decision::DeleteLocal | Decision::DeleteRemote)) {
+            self.ensure_roots(mapping, state).await?;
+            Self::guard_deletions(mapping, state, &plan).await?;
+        }
+        self.apply_all(mapping, state, plan, failed).await
+    }
+
+    /// Apply every action of `plan`, going on past the ones that fail. Fails
+    /// at the end if any did, counting the `failed` paths that never made it
+    /// into the plan, so callers that keep track of progress do not move past them.
+    async fn apply_all(
+        &self,
+        mapping: &FolderMapping,
+        state: &SyncState,
+        plan: Vec<SyncAction>,
+        mut failed: usize,
+    ) -> Result<Vec<SyncAction>> {
+        let total = plan.len() + failed;
+        let mut applied = Vec::with_capacity(plan.len());
+        for action in plan {
+            match self.apply(mapping, state, &action).await {
+                Ok(()) => applied.push(action),
+                Err(e) => {
+                    eprintln!("Error syncing {}: {}", action.relative, e);
+                    failed += 1;
+                }
+            }
+        }
+        state.flush().await?;
+
+        if failed > 0 {
+            bail!("{} of {} change(s) in {:?} failed", failed, total, mapping.local_path);
+        }
+        Ok(applied)
+    }
+
+    async fn ensure_running(mapping: &FolderMapping, state: &SyncState) -> Result<()> {
+        // The CLI may have resolved, confirmed or restored something meanwhile
+        state.refresh().await?;
+        match state.paused().await {
+            Some(paused) => bail!(
+                "{:?} is paused since {} with {} deletion(s) waiting for confirmation",
+                mapping.local_path,
+                paused.detected_at.format("%Y-%m-%d %H:%M:%S"),
+                paused.delete_local.len() + paused.delete_remote.len(),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Refuse to sync a mapping whose root is gone. An unmounted disk or a
+    /// removed remote folder would otherwise look like every file was deleted.
+    async fn ensure_roots(&self, mapping: &FolderMapping, state: &SyncState) -> Result<()> {
+        if state.entries().await.is_empty() {
+            return Ok(());
+        }
+
+        if !fs::try_exists(&mapping.local_path).await? {
+            bail!("Local folder {:?} has vanished, refusing to treat it as deleted", mapping.local_path);
+        }
+        let remote_root = mapping.remote_path.trim_end_matches('/');
+        if self.provider.get_item(if remote_root.is_empty() { "/" } else { remote_root }).await?.is_none() {
+            bail!("Remote folder {} has vanished, refusing to treat it as deleted", mapping.remote_path);
+        }
         Ok(())
     }
 
-    pub async fn handle_remote_change(&self, item: RemoteItem, local_base_path: &Path) -> Result<()> {
-        let local_path = local_base_path.join(&item.name);
+    /// Pause the mapping if `plan` deletes more than its deletion guard allows
+    async fn guard_deletions(mapping: &FolderMapping, state: &SyncState, plan: &[SyncAction]) -> Result<()> {
+        let paths = |decision| -> Vec<String> {
+            plan.iter().filter(|a| a.decision == decision).map(|a| a.relative.clone()).collect()
+        };
+        let delete_local = paths(Decision::DeleteLocal);
+        let delete_remote = paths(Decision::DeleteRemote);
+        let deletes = delete_local.len() + delete_remote.len();
 
-        // Check if the file exists locally
-        let exists = local_path.exists();
-        
-        if item.is_folder {
-            if !exists {
-                println!("Creating local directory: {:?}", local_path);
-                fs::create_dir_all(&local_path).await?;
+        if !mapping.deletion_guard.trips(deletes, state.entries().await.len()) {
+            return Ok(());
+        }
+
+        state.pause(PausedDeletions { detected_at: Utc::now(), delete_local, delete_remote }).await?;
+        bail!(
+            "{} deletion(s) in {:?} exceed the deletion guard; paused until confirmed with `filesync confirm`",
+            deletes,
+            mapping.local_path
+        )
+    }
+
+    /// Settle the deletions the guard held back. Either carry them out, or
+    /// undo them by copying each file back from the side that still has it.
+    /// The mapping then resumes with a normal reconcile, so deletions made
+    /// since the pause still go through the guard.
+    pub async fn confirm_deletions(
+        &self,
+        mapping: &FolderMapping,
+        state: &SyncState,
+        restore: bool,
+    ) -> Result<Vec<SyncAction>> {
+        let Some(paused) = state.paused().await else {
+            bail!("{:?} has no deletions waiting for confirmation", mapping.local_path);
+        };
+        self.ensure_roots(mapping, state).await?;
+
+        // Only what was held back skips the guard. Paths that changed again
+        // since the pause are left to the guarded reconcile below.
+        let filter = PathFilter::for_mapping(mapping)?;
+        let held = paused
+            .delete_remote
+            .iter()
+            .map(|relative| (relative, Decision::DeleteRemote, Decision::Download))
+            .chain(paused.delete_local.iter().map(|relative| (relative, Decision::DeleteLocal, Decision::Upload)));
+        let mut plan = Vec::new();
+        for (relative, deletion, undo) in held {
+            let mut action = self.inspect(mapping, state, &filter, relative).await?;
+            if action.decision == deletion {
+                if restore {
+                    action.decision = undo;
+                }
+                plan.push(action);
+            }
+        }
+        let mut applied = self.apply_all(mapping, state, plan, 0).await?;
+
+        state.resume().await?;
+        applied.extend(self.reconcile(mapping, state, true).await?);
+        Ok(applied)
+    }
+
+    /// `relative` plus every file below it that is synced or present locally,
+    /// for when a whole folder moves into or out of the mapping
+    pub async fn paths_under(&self, mapping: &FolderMapping, state: &SyncState, relative: &str) -> Result<Vec<String>> {
+        let prefix = format!("{}/", relative);
+        let mut paths = state.paths_under(relative).await;
+
+        let local_path = mapping.local_path.join(relative);
+        if fs::metadata(&local_path).await.is_ok_and(|m| m.is_dir()) {
+            let filter = PathFilter::for_mapping(mapping)?;
+            let descend = |folder: &str| filter.allows_folder(&format!("{}{}", prefix, folder));
+            for child in scan::scan_local(&local_path, descend).await?.into_keys() {
+                paths.push(format!("{}{}", prefix, child));
             }
+        }
+        if !paths.iter().any(|path| path == relative) {
+            paths.push(relative.to_string());
+        }
+        paths.sort();
+        paths.dedup();
+        Ok(paths)
+    }
+
+    /// Carry out a decision made by `reconcile` and record the new agreed
+    /// state, which is saved by the caller's `SyncState::flush`
+    async fn apply(&self, mapping: &FolderMapping, state: &SyncState, action: &SyncAction) -> Result<()> {
+        let relative = action.relative.as_str();
+
+        match action.decision {
+            Decision::InSync | Decision::Ignore => {}
+            Decision::Record => {
+                if let (Some(local), Some(remote)) = (&action.local, &action.remote) {
+                    let entry = SyncEntry { local: local.clone(), remote: remote.clone(), synced_at: Utc::now() };
+                    state.set(relative, entry).await;
+                }
+            }
+            Decision::Upload => self.upload(mapping, state, relative, action.local.clone()).await?,
+            Decision::Download => self.download(mapping, state, relative, action.remote.clone()).await?,
+            Decision::DeleteLocal => {
+                Self::trash_local(mapping, relative).await?;
+                state.remove(relative).await;
+                Self::version_deleted(mapping, state, relative).await?;
+            }
+            Decision::DeleteRemote => {
+                self.trash_remote(mapping, relative).await?;
+                state.remove(relative).await;
+                Self::version_deleted(mapping, state, relative).await?;
+            }
+            Decision::MoveRemote | Decision::MoveLocal => {
+                let from = action
+                    .moved_from
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("Move of {} has no source", relative))?;
+                if action.decision == Decision::MoveRemote {
+                    self.move_remote(mapping, state, from, relative).await?;
+                } else {
+                    self.move_local(mapping, state, from, relative, action.remote.clone()).await?;
+                }
+            }
+            Decision::Forget => state.remove(relative).await,
+            Decision::Conflict => self.resolve_conflict(mapping, state, action, mapping.conflict_strategy).await?,
+        }
+
+        Ok(())
+    }
+
+    /// A file or folder was renamed locally: move it on the server instead of
+    /// deleting and re-uploading it. Paths are relative to the mapping root.
+    pub async fn handle_local_rename(&self, mapping: &FolderMapping, state: &SyncState, from: &str, to: &str) -> Result<()> {
+        let renamed = self.follow_local_rename(mapping, state, from, to).await;
+        state.flush().await?;
+        renamed
+    }
+
+    async fn follow_local_rename(&self, mapping: &FolderMapping, state: &SyncState, from: &str, to: &str) -> Result<()> {
+        let prefix = format!("{}/", from);
+        let known: Vec<String> = state
+            .entries()
+            .await
+            .into_keys()
+            .filter(|key| key == from || key.starts_with(&prefix))
+            .collect();
+
+        // Renamed to something the filters leave out, so it is gone as far as the mapping is concerned
+        let filter = PathFilter::for_mapping(mapping)?;
+        let leaves = if fs::metadata(mapping.local_path.join(to)).await.is_ok_and(|m| m.is_dir()) {
+            !filter.allows_folder(to)
         } else {
-            if exists {
-                // Compare modification times and sizes
-                let metadata = fs::metadata(&local_path).await?;
-                let local_modified = metadata.modified()?.into();
-                let local_size = metadata.len();
-
-                if item.modified > local_modified || item.size != local_size {
-                    println!("Downloading updated file: {} to {:?}", item.id, local_path);
-                    self.provider.download_file(&item.id, &local_path).await?;
+            !filter.allows_file(to)
+        };
+        if leaves {
+            return self.sync_batch(mapping, state, &known).await.map(|_| ());
+        }
+
+        match mapping.mode {
+            // A mirror puts the old name back and ignores the new one
+            SyncMode::DownloadOnly => {
+                for relative in known {
+                    self.sync_path(mapping, state, &relative).await?;
                 }
-            } else {
-                println!("Downloading new file: {} to {:?}", item.id, local_path);
-                self.provider.download_file(&item.id, &local_path).await?;
+                return Ok(());
+            }
+            // The archive keeps the old name remotely and adds the new one
+            SyncMode::Archive => {
+                state.remove(from).await;
+                return self.upload_new(mapping, state, to).await;
+            }
+            SyncMode::TwoWay | SyncMode::UploadOnly => {}
+        }
+
+        let remote_from = Self::remote_path_for(mapping, from);
+        if !known.is_empty() && self.provider.exists(&remote_from).await? {
+            return self.move_remote(mapping, state, from, to).await;
+        }
+
+        // Nothing to move on the server, so whatever arrived at `to` is new
+        state.remove(from).await;
+        self.upload_new(mapping, state, to).await
+    }
+
+    async fn move_remote(&self, mapping: &FolderMapping, state: &SyncState, from: &str, to: &str) -> Result<()> {
+        let remote_from = Self::remote_path_for(mapping, from);
+        let remote_to = Self::remote_path_for(mapping, to);
+
+        println!("Moving remote {} to {}", remote_from, remote_to);
+        let item = self.provider.move_item(&remote_from, &remote_to).await?;
+        state.rename(from, to).await;
+        self.refresh_remote(mapping, state, to, &item).await?;
+        Self::version_moved(mapping, state, from, to).await?;
+
+        // The file may have been edited as well as renamed
+        self.upload_new(mapping, state, to).await
+    }
+
+    async fn move_local(
+        &self,
+        mapping: &FolderMapping,
+        state: &SyncState,
+        from: &str,
+        to: &str,
+        remote: Option<RemoteVersion>,
+    ) -> Result<()> {
+        let local_from = mapping.local_path.join(from);
+        let local_to = mapping.local_path.join(to);
+
+        println!("Moving local {:?} to {:?}", local_from, local_to);
+        if let Some(parent) = local_to.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&local_from, &local_to).await?;
+        state.rename(from, to).await;
+
+        if let (Some(mut entry), Some(remote)) = (state.get(to).await, remote) {
+            entry.remote = remote;
+            state.set(to, entry).await;
+        }
+        Self::version_moved(mapping, state, from, to).await
+    }
+
+    /// Update the stored remote versions below `relative` after a move, since
+    /// path-addressed backends give moved items new ids
+    async fn refresh_remote(&self, mapping: &FolderMapping, state: &SyncState, relative: &str, item: &RemoteItem) -> Result<()> {
+        if !item.is_folder {
+            if let Some(mut entry) = state.get(relative).await {
+                entry.remote = RemoteVersion::from(item);
+                state.set(relative, entry).await;
+            }
+            return Ok(());
+        }
+
+        let remote_files = scan::scan_remote(self.provider.as_ref(), &Self::remote_path_for(mapping, relative)).await?;
+        for (child, child_item) in remote_files {
+            let key = format!("{}/{}", relative, child);
+            if let Some(mut entry) = state.get(&key).await {
+                entry.remote = RemoteVersion::from(&child_item);
+                state.set(&key, entry).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Upload whatever under `relative` changed locally, without touching anything else
+    async fn upload_new(&self, mapping: &FolderMapping, state: &SyncState, relative: &str) -> Result<()> {
+        let filter = PathFilter::for_mapping(mapping)?;
+        let local_path = mapping.local_path.join(relative);
+        let files: Vec<String> = if fs::metadata(&local_path).await.is_ok_and(|m| m.is_dir()) {
+            scan::scan_local(&local_path, |folder| filter.allows_folder(&format!("{}/{}", relative, folder)))
+                .await?
+                .into_keys()
+                .map(|child| format!("{}/{}", relative, child))
+                .collect()
+        } else {
+            vec![relative.to_string()]
+        };
+
+        for file in files {
+            let action = self.inspect(mapping, state, &filter, &file).await?;
+            if action.decision == Decision::Upload {
+                self.upload(mapping, state, &file, action.local).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Settle a path that changed on both sides. Every conflict is recorded in
+    /// the sync state; `Manual` leaves both sides alone until `resolve` is called.
+    pub async fn resolve_conflict(
+        &self,
+        mapping: &FolderMapping,
+        state: &SyncState,
+        action: &SyncAction,
+        strategy: ConflictStrategy,
+    ) -> Result<()> {
+        let relative = action.relative.as_str();
+        let (local, remote) = match (&action.local, &action.remote) {
+            (Some(local), Some(remote)) => (local, remote),
+            _ => return Err(anyhow!("{} is not in conflict", relative)),
+        };
+
+        let strategy = match strategy {
+            ConflictStrategy::PreferNewest if local.modified >= remote.modified => ConflictStrategy::PreferLocal,
+            ConflictStrategy::PreferNewest => ConflictStrategy::PreferRemote,
+            other => other,
+        };
+
+        let resolution = match strategy {
+            ConflictStrategy::Manual => {
+                eprintln!("Conflict: {} changed both locally and remotely, waiting for manual resolution", relative);
+                None
+            }
+            ConflictStrategy::PreferLocal => {
+                self.upload(mapping, state, relative, Some(local.clone())).await?;
+                Some("kept the local version".to_string())
+            }
+            ConflictStrategy::PreferRemote => {
+                self.download(mapping, state, relative, Some(remote.clone())).await?;
+                Some("kept the remote version".to_string())
             }
+            ConflictStrategy::KeepBoth => {
+                let copy = self.conflict_copy_name(mapping, relative).await?;
+                println!("Conflict: keeping the local version of {} as {}", relative, copy);
+                fs::rename(mapping.local_path.join(relative), mapping.local_path.join(&copy)).await?;
+                self.download(mapping, state, relative, Some(remote.clone())).await?;
+
+                let copy_version = state.local_version(&copy, &mapping.local_path.join(&copy)).await?;
+                self.upload(mapping, state, &copy, copy_version).await?;
+                Some(format!("kept both, local version saved as {}", copy))
+            }
+            ConflictStrategy::PreferNewest => unreachable!("prefer-newest was narrowed above"),
+        };
+
+        state
+            .record_conflict(ConflictRecord {
+                relative: relative.to_string(),
+                detected_at: Utc::now(),
+                strategy,
+                resolution,
+            })
+            .await
+    }
+
+    /// Settle a recorded conflict by hand with the given strategy
+    pub async fn resolve(
+        &self,
+        mapping: &FolderMapping,
+        state: &SyncState,
+        relative: &str,
+        strategy: ConflictStrategy,
+    ) -> Result<()> {
+        let action = self.inspect(mapping, state, &PathFilter::for_mapping(mapping)?, relative).await?;
+        if action.decision != Decision::Conflict {
+            return Err(anyhow!("{} is not in conflict", relative));
+        }
+        let resolved = self.resolve_conflict(mapping, state, &action, strategy).await;
+        state.flush().await?;
+        resolved
+    }
+
+    async fn upload(
+        &self,
+        mapping: &FolderMapping,
+        state: &SyncState,
+        relative: &str,
+        local: Option<LocalVersion>,
+    ) -> Result<()> {
+        let local_path = mapping.local_path.join(relative);
+        let remote_path = Self::remote_path_for(mapping, relative);
+
+        println!("Uploading {:?} to {}", local_path, remote_path);
+        let item = self.provider.upload_file(&local_path, &remote_path).await?;
+        if let Some(local) = local {
+            let entry = SyncEntry { local, remote: RemoteVersion::from(&item), synced_at: Utc::now() };
+            state.set(relative, entry).await;
+        }
+        Self::keep_version(mapping, state, relative).await
+    }
+
+    async fn download(
+        &self,
+        mapping: &FolderMapping,
+        state: &SyncState,
+        relative: &str,
+        remote: Option<RemoteVersion>,
+    ) -> Result<()> {
+        let local_path = mapping.local_path.join(relative);
+        let remote_path = Self::remote_path_for(mapping, relative);
+
+        // Whatever the download replaces can still be restored
+        Self::keep_version(mapping, state, relative).await?;
+
+        // Into a sibling first: a download that breaks off must not leave a
+        // truncated file behind, which would look like a local edit
+        println!("Downloading {} to {:?}", remote_path, local_path);
+        let partial = partial_path(&local_path);
+        if let Err(e) = self.provider.download_file(&remote_path, &partial).await {
+            let _ = fs::remove_file(&partial).await;
+            return Err(e);
+        }
+        fs::rename(&partial, &local_path).await?;
+        let local = state.local_version(relative, &local_path).await?;
+        if let (Some(local), Some(remote)) = (local, remote) {
+            let entry = SyncEntry { local, remote, synced_at: Utc::now() };
+            state.set(relative, entry).await;
         }
+        Self::keep_version(mapping, state, relative).await
+    }
 
+    /// Save the local copy of `relative` in the mapping's version store, if it keeps versions
+    async fn keep_version(mapping: &FolderMapping, state: &SyncState, relative: &str) -> Result<()> {
+        let Some(retention) = mapping.versions else { return Ok(()) };
+        let local_path = mapping.local_path.join(relative);
+        if fs::try_exists(&local_path).await? {
+            SafeSync::new(state.versions_dir()).with_retention(retention).save(relative, &local_path).await?;
+        }
         Ok(())
     }
 
-    pub fn get_remote_path(&self, local_path: &Path, mapping: &crate::config::FolderMapping) -> Option<String> {
+    /// Note in the mapping's version store that `relative` is gone
+    async fn version_deleted(mapping: &FolderMapping, state: &SyncState, relative: &str) -> Result<()> {
+        let Some(retention) = mapping.versions else { return Ok(()) };
+        SafeSync::new(state.versions_dir()).with_retention(retention).record_deletion(relative).await
+    }
+
+    /// Carry the history of a moved file or folder over to its new path: the
+    /// old paths end with a deletion, the new ones start with their content
+    async fn version_moved(mapping: &FolderMapping, state: &SyncState, from: &str, to: &str) -> Result<()> {
+        let Some(retention) = mapping.versions else { return Ok(()) };
+        let store = SafeSync::new(state.versions_dir()).with_retention(retention);
+        let (from_prefix, to_prefix) = (format!("{}/", from), format!("{}/", to));
+
+        for relative in store.paths().await? {
+            if relative == from || relative.starts_with(&from_prefix) {
+                store.record_deletion(&relative).await?;
+            }
+        }
+        for relative in state.entries().await.into_keys() {
+            if relative == to || relative.starts_with(&to_prefix) {
+                Self::keep_version(mapping, state, &relative).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `dir/report (conflict <host> <date>).pdf`, numbered if that name is taken on either side
+    async fn conflict_copy_name(&self, mapping: &FolderMapping, relative: &str) -> Result<String> {
+        let host = gethostname::gethostname().to_string_lossy().into_owned();
+        let date = Local::now().format("%Y-%m-%d").to_string();
+
+        for attempt in 1.. {
+            let label = match attempt {
+                1 => format!("conflict {} {}", host, date),
+                n => format!("conflict {} {} {}", host, date, n),
+            };
+            let candidate = conflict_copy_path(relative, &label);
+            let taken = fs::try_exists(mapping.local_path.join(&candidate)).await?
+                || self.provider.exists(&Self::remote_path_for(mapping, &candidate)).await?;
+            if !taken {
+                return Ok(candidate);
+            }
+        }
+        unreachable!("the attempt counter is unbounded")
+    }
+
+    fn remote_path_for(mapping: &FolderMapping, relative: &str) -> String {
+        format!("{}/{}", mapping.remote_path.trim_end_matches('/'), relative)
+    }
+
+    /// Path of `local_path` relative to the mapping root, as used for the sync state
+    pub fn relative_path(local_path: &Path, mapping: &FolderMapping) -> Option<String> {
+        let relative = local_path.strip_prefix(&mapping.local_path).ok()?;
+        let segments: Vec<String> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        (!segments.is_empty()).then(|| segments.join("/"))
+    }
+
+    pub fn get_remote_path(&self, local_path: &Path, mapping: &FolderMapping) -> Option<String> {
         local_path
             .strip_prefix(&mapping.local_path)
             .ok()
@@ -76,23 +670,73 @@ impl SyncOperation {
     }
 }
 
+/// `dir/.name.partial`, where a download goes until it is complete
+pub(crate) fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.partial", name))
+}
+
+/// Insert `(label)` before the extension of the file name in `relative`
+fn conflict_copy_path(relative: &str, label: &str) -> String {
+    let (dir, file_name) = match relative.rsplit_once('/') {
+        Some((dir, file_name)) => (Some(dir), file_name),
+        None => (None, relative),
+    };
+    let name = Path::new(file_name);
+    let stem = name.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let copy = match name.extension() {
+        Some(extension) => format!("{} ({}).{}", stem, label, extension.to_string_lossy()),
+        None => format!("{} ({})", stem, label),
+    };
+
+    match dir {
+        Some(dir) => format!("{}/{}", dir, copy),
+        None => copy,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::FolderMapping;
+    use crate::config::DeletionGuard;
+    use crate::sync::trash::TRASH_DIR;
+    use crate::provider::memory::{MemoryProvider, Operation};
     use std::path::PathBuf;
-    use async_trait::async_trait;
-    use chrono::Utc;
-    use tokio::sync::mpsc::Sender;
+    use tempfile::{tempdir, TempDir};
+
+    /// An empty in-memory provider and a mapping of `<dir>/sync` to `/backup`,
+    /// whose state is kept in `<dir>/state.json`
+    pub(super) struct Fixture {
+        pub(super) provider: MemoryProvider,
+        pub(super) sync_op: SyncOperation,
+        pub(super) state: SyncState,
+        pub(super) mapping: FolderMapping,
+        pub(super) dir: TempDir,
+    }
+
+    pub(super) async fn fixture() -> Result<Fixture> {
+        let provider = MemoryProvider::new(Vec::new());
+        let sync_op = SyncOperation::new(Box::new(provider.clone()));
+        let dir = tempdir()?;
+        let state = SyncState::open(dir.path().join("state.json")).await?;
+        let mapping = FolderMapping {
+            local_path: dir.path().join("sync"),
+            remote_path: String::from("/backup"),
+            ..Default::default()
+        };
+        fs::create_dir_all(&mapping.local_path).await?;
+        Ok(Fixture { provider, sync_op, state, mapping, dir })
+    }
 
     #[test]
     fn test_get_remote_path() {
-        let provider = MockProvider::new();
+        let provider = MemoryProvider::new(Vec::new());
         let sync_op = SyncOperation::new(Box::new(provider));
 
         let mapping = FolderMapping {
             local_path: PathBuf::from("/local/sync"),
             remote_path: String::from("/remote/sync"),
+            ..Default::default()
         };
 
         let local_path = PathBuf::from("/local/sync/docs/file.txt");
@@ -100,78 +744,299 @@ mod tests {
 
         assert_eq!(remote_path, Some(String::from("/remote/sync/docs/file.txt")));
     }
-}
-
-#[cfg(test)]
-struct MockProvider {
-    mappings: Vec<FolderMapping>,
-}
 
-#[cfg(test)]
-impl MockProvider {
-    fn new() -> Self {
-        Self {
-            mappings: vec![],
+    #[tokio::test]
+    async fn test_existing_copies_are_adopted_not_duplicated() -> Result<()> {
+        let Fixture { provider, sync_op, state, mut mapping, dir: _dir } = fixture().await?;
+        mapping.conflict_strategy = ConflictStrategy::KeepBoth;
+        let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        for name in ["a.txt", "b.txt"] {
+            let path = mapping.local_path.join(name);
+            fs::write(&path, name).await?;
+            std::fs::File::options().write(true).open(&path)?.set_modified(an_hour_ago)?;
+            provider.put_file(&format!("/backup/{}", name), name)?;
         }
+
+        let applied = sync_op.reconcile_mapping(&mapping, &state).await?;
+        assert_eq!(applied.iter().map(|action| action.decision).collect::<Vec<_>>(), [Decision::Record, Decision::Record]);
+        assert_eq!(scan::scan_local(&mapping.local_path, |_| true).await?.into_keys().collect::<Vec<_>>(), ["a.txt", "b.txt"]);
+        assert_eq!(provider.paths().iter().filter(|p| p.ends_with(".txt")).count(), 2);
+        assert!(sync_op.reconcile_mapping(&mapping, &state).await?.is_empty());
+
+        Ok(())
     }
-}
 
-#[cfg(test)]
-#[async_trait]
-impl CloudProvider for MockProvider {
-    async fn initialize(&mut self) -> Result<()> {
+    #[tokio::test]
+    async fn test_sync_path_uses_stored_state() -> Result<()> {
+        let Fixture { provider, sync_op, state, mapping, dir: _dir } = fixture().await?;
+        fs::write(mapping.local_path.join("a.txt"), b"v1").await?;
+
+        assert_eq!(sync_op.sync_path(&mapping, &state, "a.txt").await?, Decision::Upload);
+        assert_eq!(sync_op.sync_path(&mapping, &state, "a.txt").await?, Decision::InSync);
+
+        provider.put_file("/backup/a.txt", "v2 from another device")?;
+        assert_eq!(sync_op.sync_path(&mapping, &state, "a.txt").await?, Decision::Download);
+        assert_eq!(fs::read(mapping.local_path.join("a.txt")).await?, b"v2 from another device");
+
+        fs::remove_file(mapping.local_path.join("a.txt")).await?;
+        assert_eq!(sync_op.sync_path(&mapping, &state, "a.txt").await?, Decision::DeleteRemote);
+        assert!(provider.read("/backup/a.txt").is_none());
+        assert!(state.get("a.txt").await.is_none());
+
         Ok(())
     }
 
-    async fn list_files(&self, _remote_path: &str) -> Result<Vec<RemoteItem>> {
-        Ok(vec![])
+    #[tokio::test]
+    async fn test_broken_download_is_not_a_local_edit() -> Result<()> {
+        let Fixture { provider, sync_op, state, mapping, dir: _dir } = fixture().await?;
+        let file = mapping.local_path.join("a.txt");
+        fs::write(&file, b"v1").await?;
+        sync_op.sync_path(&mapping, &state, "a.txt").await?;
+
+        provider.put_file("/backup/a.txt", "v2 from another device")?;
+        provider.interrupt_next_download(1);
+        assert!(sync_op.sync_path(&mapping, &state, "a.txt").await.is_err());
+        assert_eq!(fs::read(&file).await?, b"v1");
+        assert_eq!(scan::scan_local(&mapping.local_path, |_| true).await?.into_keys().collect::<Vec<_>>(), ["a.txt"]);
+
+        // The next pass downloads it again instead of uploading half of it
+        sync_op.reconcile_mapping(&mapping, &state).await?;
+        assert_eq!(provider.read("/backup/a.txt").as_deref(), Some(&b"v2 from another device"[..]));
+        assert_eq!(fs::read(&file).await?, b"v2 from another device");
+
+        Ok(())
     }
 
-    async fn upload_file(&self, _local_path: &Path, _remote_path: &str) -> Result<RemoteItem> {
-        Ok(RemoteItem {
-            name: "test.txt".to_string(),
-            id: "test-id".to_string(),
-            size: 0,
-            modified: Utc::now(),
-            is_folder: false,
-        })
+    #[tokio::test]
+    async fn test_batch_goes_on_past_a_failed_path() -> Result<()> {
+        let Fixture { provider, sync_op, state, mapping, dir: _dir } = fixture().await?;
+        fs::write(mapping.local_path.join("a.txt"), b"a").await?;
+        fs::write(mapping.local_path.join("b.txt"), b"b").await?;
+
+        provider.fail_next(Operation::GetItem, 1);
+        assert!(sync_op.sync_batch(&mapping, &state, &["a.txt".to_string(), "b.txt".to_string()]).await.is_err());
+        assert_eq!(provider.read("/backup/a.txt"), None);
+        assert_eq!(provider.read("/backup/b.txt").as_deref(), Some(&b"b"[..]));
+
+        Ok(())
     }
 
-    async fn download_file(&self, _remote_path: &str, _local_path: &Path) -> Result<()> {
+    #[test]
+    fn test_conflict_copy_path() {
+        assert_eq!(conflict_copy_path("docs/report.final.pdf", "conflict laptop 2024-05-01"),
+            "docs/report.final (conflict laptop 2024-05-01).pdf");
+        assert_eq!(conflict_copy_path(".bashrc", "conflict laptop 2024-05-01"),
+            ".bashrc (conflict laptop 2024-05-01)");
+    }
+
+    #[tokio::test]
+    async fn test_conflict_strategies() -> Result<()> {
+        let Fixture { provider, sync_op, state, mut mapping, dir: _dir } = fixture().await?;
+        mapping.conflict_strategy = ConflictStrategy::Manual;
+        let file = mapping.local_path.join("a.txt");
+        fs::write(&file, b"base").await?;
+        sync_op.sync_path(&mapping, &state, "a.txt").await?;
+
+        fs::write(&file, b"local edit").await?;
+        provider.put_file("/backup/a.txt", "remote edit")?;
+
+        assert_eq!(sync_op.sync_path(&mapping, &state, "a.txt").await?, Decision::Conflict);
+        assert_eq!(fs::read(&file).await?, b"local edit");
+        assert_eq!(state.unresolved_conflicts().await.len(), 1);
+
+        mapping.conflict_strategy = ConflictStrategy::KeepBoth;
+        sync_op.sync_path(&mapping, &state, "a.txt").await?;
+        assert_eq!(fs::read(&file).await?, b"remote edit");
+        let copies: Vec<String> = provider.paths().into_iter().filter(|p| p.contains("(conflict ")).collect();
+        assert_eq!(copies.len(), 1);
+        assert_eq!(provider.read(&copies[0]).as_deref(), Some(&b"local edit"[..]));
+        assert!(state.unresolved_conflicts().await.is_empty());
+        assert_eq!(sync_op.sync_path(&mapping, &state, "a.txt").await?, Decision::InSync);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_rename_moves_remote_folder() -> Result<()> {
+        let Fixture { provider, sync_op, state, mapping, dir: _dir } = fixture().await?;
+        fs::create_dir_all(mapping.local_path.join("videos")).await?;
+        fs::write(mapping.local_path.join("videos/trip.mp4"), b"frames").await?;
+        sync_op.reconcile_mapping(&mapping, &state).await?;
+
+        fs::rename(mapping.local_path.join("videos"), mapping.local_path.join("movies")).await?;
+        sync_op.handle_local_rename(&mapping, &state, "videos", "movies").await?;
+
+        assert_eq!(provider.read("/backup/movies/trip.mp4").as_deref(), Some(&b"frames"[..]));
+        assert!(!provider.paths().contains(&"/backup/videos".to_string()));
+        let uploads = provider.calls().iter().filter(|(operation, _)| *operation == Operation::Upload).count();
+        assert_eq!(uploads, 1, "the rename must not re-upload the file");
+        assert_eq!(sync_op.sync_path(&mapping, &state, "movies/trip.mp4").await?, Decision::InSync);
+
         Ok(())
     }
 
-    async fn create_directory(&self, _remote_path: &str) -> Result<RemoteItem> {
-        Ok(RemoteItem {
-            name: "test-dir".to_string(),
-            id: "test-dir-id".to_string(),
-            size: 0,
-            modified: Utc::now(),
-            is_folder: true,
-        })
+    #[tokio::test]
+    async fn test_filters_apply_to_changes_and_renames() -> Result<()> {
+        let Fixture { provider, sync_op, state, mut mapping, dir: _dir } = fixture().await?;
+        mapping.exclude = vec!["*.tmp".to_string()];
+        fs::write(mapping.local_path.join("a.txt.tmp"), b"half written").await?;
+        assert_eq!(sync_op.sync_path(&mapping, &state, "a.txt.tmp").await?, Decision::Ignore);
+        assert!(provider.paths().is_empty());
+
+        // Editors save to a temporary file and rename it over the real one
+        fs::rename(mapping.local_path.join("a.txt.tmp"), mapping.local_path.join("a.txt")).await?;
+        sync_op.handle_local_rename(&mapping, &state, "a.txt.tmp", "a.txt").await?;
+        assert_eq!(provider.read("/backup/a.txt").as_deref(), Some(&b"half written"[..]));
+
+        // Renaming a synced file to an excluded name removes it remotely
+        fs::rename(mapping.local_path.join("a.txt"), mapping.local_path.join("a.txt.tmp")).await?;
+        sync_op.handle_local_rename(&mapping, &state, "a.txt", "a.txt.tmp").await?;
+        assert!(provider.read("/backup/a.txt").is_none());
+        assert!(provider.read("/backup/a.txt.tmp").is_none());
+
+        Ok(())
     }
 
-    async fn delete(&self, _remote_path: &str) -> Result<()> {
+    #[tokio::test]
+    async fn test_deletion_guard_pauses_mass_delete() -> Result<()> {
+        let Fixture { provider, sync_op, state, mut mapping, dir: _dir } = fixture().await?;
+        mapping.deletion_guard = DeletionGuard { max_count: 3, max_percent: 100 };
+        let files: Vec<String> = (0..5).map(|i| format!("{}.txt", i)).collect();
+        for file in &files {
+            fs::write(mapping.local_path.join(file), file.as_bytes()).await?;
+        }
+        sync_op.reconcile_mapping(&mapping, &state).await?;
+
+        for file in &files[..4] {
+            fs::remove_file(mapping.local_path.join(file)).await?;
+        }
+        assert!(sync_op.sync_batch(&mapping, &state, &files).await.is_err());
+        assert_eq!(provider.paths().iter().filter(|p| p.ends_with(".txt")).count(), 5);
+        assert_eq!(state.paused().await.map(|p| p.delete_remote.len()), Some(4));
+
+        // Nothing moves while paused, not even changes the guard would allow
+        fs::write(mapping.local_path.join("4.txt"), b"edited").await?;
+        assert!(sync_op.sync_batch(&mapping, &state, &files[4..]).await.is_err());
+
+        sync_op.confirm_deletions(&mapping, &state, true).await?;
+        assert!(state.paused().await.is_none());
+        assert_eq!(fs::read(mapping.local_path.join("0.txt")).await?, b"0.txt");
+        assert_eq!(provider.read("/backup/4.txt").as_deref(), Some(&b"edited"[..]));
+
         Ok(())
     }
 
-    async fn exists(&self, _remote_path: &str) -> Result<bool> {
-        Ok(true)
+    #[tokio::test]
+    async fn test_confirming_does_not_wave_through_later_deletions() -> Result<()> {
+        let Fixture { provider, sync_op, state, mut mapping, dir: _dir } = fixture().await?;
+        mapping.deletion_guard = DeletionGuard { max_count: 3, max_percent: 100 };
+        let files: Vec<String> = (0..8).map(|i| format!("{}.txt", i)).collect();
+        for file in &files {
+            fs::write(mapping.local_path.join(file), file.as_bytes()).await?;
+        }
+        sync_op.reconcile_mapping(&mapping, &state).await?;
+
+        for file in &files[..4] {
+            fs::remove_file(mapping.local_path.join(file)).await?;
+        }
+        assert!(sync_op.sync_batch(&mapping, &state, &files).await.is_err());
+
+        // More files go while the first deletions wait for confirmation
+        for file in &files[4..] {
+            fs::remove_file(mapping.local_path.join(file)).await?;
+        }
+        assert!(sync_op.confirm_deletions(&mapping, &state, false).await.is_err());
+        let synced = provider.paths().into_iter().filter(|p| p.ends_with(".txt") && !p.contains(TRASH_DIR)).count();
+        assert_eq!(synced, 4);
+        assert_eq!(state.paused().await.map(|p| p.delete_remote), Some(files[4..].to_vec()));
+
+        Ok(())
     }
 
-    async fn get_item(&self, _remote_path: &str) -> Result<Option<RemoteItem>> {
-        Ok(None)
+    #[tokio::test]
+    async fn test_vanished_root_is_not_a_deletion() -> Result<()> {
+        let Fixture { provider, sync_op, state, mapping, dir: _dir } = fixture().await?;
+        fs::write(mapping.local_path.join("a.txt"), b"a").await?;
+        sync_op.reconcile_mapping(&mapping, &state).await?;
+
+        // The disk was unmounted
+        fs::remove_dir_all(&mapping.local_path).await?;
+        assert!(sync_op.reconcile_mapping(&mapping, &state).await.is_err());
+        assert!(sync_op.sync_batch(&mapping, &state, &["a.txt".to_string()]).await.is_err());
+        assert_eq!(provider.read("/backup/a.txt").as_deref(), Some(&b"a"[..]));
+
+        Ok(())
     }
 
-    async fn watch_local_changes(&self, _local_path: &Path, _tx: Sender<ChangeType>) -> Result<()> {
+    #[tokio::test]
+    async fn test_deletes_go_to_the_trash() -> Result<()> {
+        let Fixture { provider, sync_op, state, mapping, dir: _dir } = fixture().await?;
+        fs::create_dir_all(mapping.local_path.join("docs")).await?;
+        fs::write(mapping.local_path.join("docs/a.txt"), b"a").await?;
+        fs::write(mapping.local_path.join("b.txt"), b"b").await?;
+        sync_op.reconcile_mapping(&mapping, &state).await?;
+
+        fs::remove_file(mapping.local_path.join("docs/a.txt")).await?;
+        provider.remove("/backup/b.txt")?;
+        let applied = sync_op.reconcile_mapping(&mapping, &state).await?;
+        assert_eq!(applied.len(), 2);
+
+        let date = Local::now().format("%Y-%m-%d").to_string();
+        let remote_copy = format!("/backup/.filesynchub-trash/{}/docs/a.txt", date);
+        assert_eq!(provider.read(&remote_copy).as_deref(), Some(&b"a"[..]));
+        let local_copy = mapping.local_path.join(format!(".filesynchub-trash/{}/b.txt", date));
+        assert_eq!(fs::read(&local_copy).await?, b"b");
+
+        // The trash itself is never synced
+        assert!(sync_op.reconcile_mapping(&mapping, &state).await?.is_empty());
+
+        // A day folder past the retention is purged
+        fs::create_dir_all(mapping.local_path.join(".filesynchub-trash/2000-01-01")).await?;
+        provider.put_folder("/backup/.filesynchub-trash/2000-01-01")?;
+        assert_eq!(sync_op.purge_trash(&mapping).await?, 2);
+        assert!(local_copy.exists());
+
         Ok(())
     }
 
-    async fn watch_remote_changes(&self, _remote_path: &str, _tx: Sender<RemoteItem>) -> Result<()> {
+    #[tokio::test]
+    async fn test_versions_are_kept_when_enabled() -> Result<()> {
+        let Fixture { provider, sync_op, state, mut mapping, dir: _dir } = fixture().await?;
+        mapping.conflict_strategy = ConflictStrategy::PreferRemote;
+        mapping.versions = Some(Default::default());
+        let file = mapping.local_path.join("a.txt");
+        fs::write(&file, b"v1").await?;
+        sync_op.sync_path(&mapping, &state, "a.txt").await?;
+
+        // The remote side wins a conflict, but the local edit is not lost
+        fs::write(&file, b"local edit").await?;
+        provider.put_file("/backup/a.txt", "remote edit")?;
+        sync_op.sync_path(&mapping, &state, "a.txt").await?;
+        assert_eq!(fs::read(&file).await?, b"remote edit");
+
+        let store = SafeSync::new(state.versions_dir());
+        let versions = store.versions("a.txt").await?;
+        assert_eq!(versions.len(), 3);
+        store.restore(&versions[1], &file).await?;
+        assert_eq!(fs::read(&file).await?, b"local edit");
+
         Ok(())
     }
 
-    async fn get_mappings(&self) -> Vec<FolderMapping> {
-        self.mappings.clone()
+    #[tokio::test]
+    async fn test_backup_mode_ignores_remote_deletes() -> Result<()> {
+        let Fixture { provider, sync_op, state, mut mapping, dir: _dir } = fixture().await?;
+        mapping.mode = SyncMode::UploadOnly;
+        fs::write(mapping.local_path.join("a.txt"), b"keep me").await?;
+        provider.put_file("/backup/remote-only.txt", "not ours")?;
+        sync_op.reconcile_mapping(&mapping, &state).await?;
+        assert!(!mapping.local_path.join("remote-only.txt").exists());
+
+        provider.remove("/backup/a.txt")?;
+        assert_eq!(sync_op.sync_path(&mapping, &state, "a.txt").await?, Decision::Upload);
+        assert_eq!(fs::read(mapping.local_path.join("a.txt")).await?, b"keep me");
+        assert_eq!(provider.read("/backup/a.txt").as_deref(), Some(&b"keep me"[..]));
+
+        Ok(())
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,162 @@
+use anyhow::Result;
+use std::collections::HashSet;
+
+use super::{SyncAction, SyncOperation};
+use crate::config::FolderMapping;
+use crate::provider::RemoteChange;
+use crate::state::SyncState;
+
+impl SyncOperation {
+    /// Apply what changed remotely since the cursor stored in the state, then
+    /// store the cursor to continue from. Without a stored cursor, only the
+    /// current one is stored: the caller is expected to reconcile the mapping
+    /// anyway. Returns the applied actions, or `None` if the provider has no
+    /// change feed.
+    pub async fn pull_remote_changes(&self, mapping: &FolderMapping, state: &SyncState) -> Result<Option<Vec<SyncAction>>> {
+        state.refresh().await?;
+        let cursor = state.remote_cursor().await;
+        let Some(feed) = self.provider.changes_since(&mapping.remote_path, cursor.as_deref()).await? else {
+            return Ok(None);
+        };
+
+        let applied = if cursor.is_none() {
+            Vec::new()
+        } else if feed.reset {
+            println!("Change feed of {} restarted, comparing everything", mapping.remote_path);
+            self.reconcile_mapping(mapping, state).await?
+        } else {
+            match Self::changed_paths(mapping, state, &feed.changes).await {
+                Some(relatives) if relatives.is_empty() => Vec::new(),
+                Some(relatives) => self.sync_batch(mapping, state, &relatives).await?,
+                None => self.reconcile_mapping(mapping, state).await?,
+            }
+        };
+
+        // Only moved on once applied, so a failed round is fetched again
+        state.set_remote_cursor(&feed.cursor).await?;
+        Ok(Some(applied))
+    }
+
+    /// The paths of the mapping a batch of remote changes touches, where the
+    /// items were before as well as where they are now. `None` when a folder
+    /// showed up, maybe moved in or renamed with everything in it, which only
+    /// comparing the whole mapping can sort out.
+    async fn changed_paths(mapping: &FolderMapping, state: &SyncState, changes: &[RemoteChange]) -> Option<Vec<String>> {
+        let entries = state.entries().await;
+        let mut relatives = Vec::new();
+        for change in changes {
+            relatives.extend(entries.iter().filter(|(_, entry)| entry.remote.id == change.id).map(|(relative, _)| relative.clone()));
+
+            let Some(relative) = change.path.as_deref().and_then(|path| remote_relative(path, mapping)) else { continue };
+            match &change.item {
+                Some(item) if item.is_folder => {
+                    if state.paths_under(&relative).await.is_empty() {
+                        return None;
+                    }
+                }
+                Some(_) => relatives.push(relative),
+                None => relatives.extend(state.paths_under(&relative).await),
+            }
+        }
+
+        let mut seen = HashSet::new();
+        relatives.retain(|relative| seen.insert(relative.clone()));
+        Some(relatives)
+    }
+}
+
+/// Path of `remote_path` relative to the mapping root, `None` outside of it or for the root itself
+fn remote_relative(remote_path: &str, mapping: &FolderMapping) -> Option<String> {
+    let segments = |path: &str| path.split('/').filter(|segment| !segment.is_empty()).map(str::to_string).collect::<Vec<_>>();
+    let root = segments(&mapping.remote_path);
+    let path = segments(remote_path);
+    let rest = path.strip_prefix(root.as_slice())?;
+    (!rest.is_empty()).then(|| rest.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::memory::Operation;
+    use crate::sync::tests::{fixture, Fixture};
+    use tokio::fs;
+
+    #[test]
+    fn test_remote_relative() {
+        let mapping = FolderMapping { remote_path: String::from("/shared/"), ..Default::default() };
+        assert_eq!(remote_relative("/shared/docs/a.txt", &mapping).as_deref(), Some("docs/a.txt"));
+        assert_eq!(remote_relative("/shared", &mapping), None);
+        assert_eq!(remote_relative("/shared-old/a.txt", &mapping), None);
+    }
+
+    #[tokio::test]
+    async fn test_remote_changes_resume_from_stored_cursor() -> Result<()> {
+        let Fixture { provider, sync_op, state, mapping, dir } = fixture().await?;
+        let state_path = dir.path().join("state.json");
+        let local = |relative: &str| mapping.local_path.join(relative);
+
+        for name in ["a.txt", "b.txt", "c.txt", "old.txt"] {
+            provider.put_file(&format!("/backup/{}", name), name)?;
+        }
+        sync_op.reconcile_mapping(&mapping, &state).await?;
+
+        // The first round only remembers where the feed is
+        assert!(sync_op.pull_remote_changes(&mapping, &state).await?.unwrap().is_empty());
+        assert!(state.remote_cursor().await.is_some());
+
+        provider.put_file("/backup/a.txt", "edited on the web")?;
+        provider.put_file("/backup/new.txt", "created on the phone")?;
+        provider.remove("/backup/old.txt")?;
+        provider.put_file("/private/x.txt", "not ours")?;
+
+        // Picked up after a restart
+        let state = SyncState::open(state_path.clone()).await?;
+        let applied = sync_op.pull_remote_changes(&mapping, &state).await?.unwrap();
+        assert_eq!(applied.len(), 3);
+        assert_eq!(fs::read(local("a.txt")).await?, b"edited on the web");
+        assert_eq!(fs::read(local("new.txt")).await?, b"created on the phone");
+        assert!(!local("old.txt").exists());
+        assert!(!local("x.txt").exists());
+        assert!(sync_op.pull_remote_changes(&mapping, &state).await?.unwrap().is_empty());
+
+        // A new folder may have been moved in with everything in it
+        provider.put_folder("/backup/photos")?;
+        provider.put_file("/backup/photos/beach.jpg", "sand")?;
+        let state = SyncState::open(state_path).await?;
+        sync_op.pull_remote_changes(&mapping, &state).await?;
+        assert_eq!(fs::read(local("photos/beach.jpg")).await?, b"sand");
+
+        // An expired cursor falls back to comparing everything
+        state.set_remote_cursor("999").await?;
+        fs::remove_file(local("b.txt")).await?;
+        sync_op.pull_remote_changes(&mapping, &state).await?;
+        assert_eq!(provider.read("/backup/b.txt"), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_download_is_fetched_again() -> Result<()> {
+        let Fixture { provider, sync_op, state, mapping, dir: _dir } = fixture().await?;
+        provider.put_file("/backup/a.txt", "a")?;
+        provider.put_file("/backup/b.txt", "b")?;
+        sync_op.reconcile_mapping(&mapping, &state).await?;
+        sync_op.pull_remote_changes(&mapping, &state).await?;
+        state.refresh().await?;
+        let cursor = state.remote_cursor().await;
+
+        provider.put_file("/backup/a.txt", "a edited")?;
+        provider.put_file("/backup/b.txt", "b edited")?;
+        provider.fail_next(Operation::Download, 1);
+        assert!(sync_op.pull_remote_changes(&mapping, &state).await.is_err());
+        assert_eq!(state.remote_cursor().await, cursor);
+
+        let applied = sync_op.pull_remote_changes(&mapping, &state).await?.unwrap();
+        assert_eq!(applied.len(), 1);
+        assert_eq!(fs::read(mapping.local_path.join("a.txt")).await?, b"a edited");
+        assert_eq!(fs::read(mapping.local_path.join("b.txt")).await?, b"b edited");
+        assert_ne!(state.remote_cursor().await, cursor);
+
+        Ok(())
+    }
+}
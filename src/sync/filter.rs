@@ -0,0 +1,266 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::trash::TRASH_DIR;
+use crate::config::FolderMapping;
+
+/// Per-folder ignore file with gitignore syntax
+pub const IGNORE_FILE: &str = ".filesyncignore";
+
+/// The `include`/`exclude` patterns of a mapping, compiled.
+///
+/// Paths are relative to the mapping root with `/` separators, and the same
+/// rules apply to local and remote paths:
+///
+/// - a pattern without a `/` matches a name at any depth (`*.tmp`), one with
+///   a `/` matches the whole path from the root (`build/**`, `/notes.txt`)
+/// - a folder that matches `exclude` excludes everything below it
+/// - `include`, when not empty, only lets matching files through; folders are
+///   always walked so `*.pdf` finds PDFs at any depth
+/// - `exclude` wins over `include`
+///
+/// On top of that, `.filesyncignore` files anywhere in the local tree exclude
+/// paths below their folder, with the same rules as `.gitignore`. They are
+/// read when the filter is built, so build a new one to pick up edits. The
+/// trash folder at the root and unfinished downloads are always excluded.
+#[derive(Debug)]
+pub struct PathFilter {
+    include: Patterns,
+    exclude: Patterns,
+    ignore_files: IgnoreFiles,
+}
+
+#[derive(Debug, Clone)]
+struct Patterns {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Patterns {
+    fn new(patterns: &[String]) -> Result<Self> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            let trimmed = pattern.trim_end_matches('/');
+            let (builder, glob) = match trimmed.strip_prefix('/') {
+                Some(anchored) => (&mut paths, anchored),
+                None if trimmed.contains('/') => (&mut paths, trimmed),
+                None => (&mut names, trimmed),
+            };
+            let glob = GlobBuilder::new(glob)
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("Invalid pattern {:?}", pattern))?;
+            builder.add(glob);
+        }
+
+        Ok(Self { names: names.build()?, paths: paths.build()? })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.paths.is_empty()
+    }
+
+    fn matches(&self, relative: &str) -> bool {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        self.names.is_match(name) || self.paths.is_match(relative)
+    }
+}
+
+/// The `.filesyncignore` files of a local tree, loaded as they are needed
+#[derive(Debug)]
+struct IgnoreFiles {
+    root: PathBuf,
+    // Keyed by folder relative to the root, `None` when it has no ignore file
+    loaded: Mutex<HashMap<String, Option<Gitignore>>>,
+}
+
+impl IgnoreFiles {
+    fn new(root: &Path) -> Self {
+        Self { root: root.to_path_buf(), loaded: Mutex::new(HashMap::new()) }
+    }
+
+    fn load(&self, dir: &str) -> Option<Gitignore> {
+        let mut loaded = self.loaded.lock().unwrap();
+        loaded
+            .entry(dir.to_string())
+            .or_insert_with(|| {
+                let dir_path = self.root.join(dir);
+                let file = dir_path.join(IGNORE_FILE);
+                if !file.is_file() {
+                    return None;
+                }
+
+                let mut builder = GitignoreBuilder::new(&dir_path);
+                // A bad line only loses that line, the rest of the file still applies
+                if let Some(e) = builder.add(&file) {
+                    eprintln!("Error in {:?}: {}", file, e);
+                }
+                match builder.build() {
+                    Ok(rules) => Some(rules),
+                    Err(e) => {
+                        eprintln!("Error in {:?}: {}", file, e);
+                        None
+                    }
+                }
+            })
+            .clone()
+    }
+
+    /// Whether `relative` itself is ignored, not looking at the folders above
+    /// it. The ignore file closest to the path decides, like in git.
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        let path = self.root.join(relative);
+        let mut dirs: Vec<&str> = relative.match_indices('/').map(|(index, _)| &relative[..index]).collect();
+        dirs.insert(0, "");
+
+        for dir in dirs.into_iter().rev() {
+            if let Some(rules) = self.load(dir) {
+                let matched = rules.matched(&path, is_dir);
+                if matched.is_ignore() {
+                    return true;
+                }
+                if matched.is_whitelist() {
+                    return false;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl PathFilter {
+    pub fn for_mapping(mapping: &FolderMapping) -> Result<Self> {
+        Ok(Self {
+            include: Patterns::new(&mapping.include)?,
+            exclude: Patterns::new(&mapping.exclude)?,
+            ignore_files: IgnoreFiles::new(&mapping.local_path),
+        })
+    }
+
+    /// Whether `relative` or one of the folders above it is excluded. Like in
+    /// git, nothing below an excluded folder can be brought back.
+    fn is_excluded(&self, relative: &str, is_dir: bool) -> bool {
+        if relative.split('/').next() == Some(TRASH_DIR) || is_partial(relative) {
+            return true;
+        }
+
+        relative
+            .match_indices('/')
+            .map(|(index, _)| (&relative[..index], true))
+            .chain(std::iter::once((relative, is_dir)))
+            .any(|(path, is_dir)| self.exclude.matches(path) || self.ignore_files.matches(path, is_dir))
+    }
+
+    /// Whether the file at `relative` takes part in the sync
+    pub fn allows_file(&self, relative: &str) -> bool {
+        !self.is_excluded(relative, false) && (self.include.is_empty() || self.include.matches(relative))
+    }
+
+    /// Whether anything below the folder at `relative` can take part in the sync
+    pub fn allows_folder(&self, relative: &str) -> bool {
+        !self.is_excluded(relative, true)
+    }
+}
+
+/// Whether `relative` names a download still in progress, see `partial_path`
+fn is_partial(relative: &str) -> bool {
+    let name = relative.rsplit('/').next().unwrap_or(relative);
+    name.starts_with('.') && name.ends_with(".partial")
+}
+
+/// Whether `relative` names an ignore file, whose edits change what a mapping syncs
+pub fn is_ignore_file(relative: &str) -> bool {
+    relative.rsplit('/').next() == Some(IGNORE_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let mapping = FolderMapping {
+            include: include.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        PathFilter::for_mapping(&mapping).unwrap()
+    }
+
+    #[test]
+    fn test_filter_precedence() {
+        let everything = filter(&[], &[]);
+        assert!(everything.allows_file("a/b/c.bin"));
+
+        let filter = filter(&["*.txt", "docs/**"], &["*.tmp", "build", "/secret.txt"]);
+        assert!(filter.allows_file("notes.txt"));
+        assert!(filter.allows_file("deep/down/notes.txt"));
+        assert!(filter.allows_file("docs/manual.pdf"));
+        assert!(!filter.allows_file("photo.jpg"));
+        assert!(filter.allows_folder("photos"));
+
+        // Exclusions win and apply to whole folders
+        assert!(!filter.allows_file("docs/draft.tmp"));
+        assert!(!filter.allows_file("build/out.txt"));
+        assert!(!filter.allows_file("src/build/out.txt"));
+        assert!(!filter.allows_folder("src/build"));
+
+        // A leading slash anchors the pattern to the mapping root
+        assert!(!filter.allows_file("secret.txt"));
+        assert!(filter.allows_file("old/secret.txt"));
+
+        // Downloads in progress never are
+        assert!(!everything.allows_file("docs/.report.pdf.partial"));
+    }
+
+    #[test]
+    fn test_ignore_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("app/src"))?;
+        std::fs::write(root.join(IGNORE_FILE), "target/\n*.log\n!keep.log\n/build\n")?;
+        std::fs::write(root.join("app").join(IGNORE_FILE), "node_modules/\n!debug.log\nsrc/*.gen.rs\n")?;
+        std::fs::create_dir_all(root.join("app/target"))?;
+        std::fs::create_dir_all(root.join("app/node_modules"))?;
+        let mapping = FolderMapping { local_path: root.to_path_buf(), ..Default::default() };
+        let filter = PathFilter::for_mapping(&mapping)?;
+
+        // Folder-only patterns, at any depth
+        assert!(!filter.allows_file("target/debug/app"));
+        assert!(!filter.allows_file("app/target/debug/app"));
+        assert!(!filter.allows_folder("app/target"));
+        assert!(!filter.allows_file("app/node_modules/left-pad/index.js"));
+        assert!(filter.allows_file("node_modules/index.js"));
+
+        // Negation, in the same file and in a nested one
+        assert!(!filter.allows_file("server.log"));
+        assert!(filter.allows_file("keep.log"));
+        assert!(filter.allows_file("app/debug.log"));
+        assert!(!filter.allows_file("debug.log"));
+
+        // Anchored paths are relative to the folder of the ignore file
+        assert!(!filter.allows_file("build/out.bin"));
+        assert!(filter.allows_file("app/build/out.bin"));
+        assert!(!filter.allows_file("app/src/schema.gen.rs"));
+        assert!(filter.allows_file("app/src/main.rs"));
+
+        // The ignore files themselves are synced
+        assert!(filter.allows_file(".filesyncignore"));
+        assert!(is_ignore_file("app/.filesyncignore"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_pattern_is_reported() {
+        let mapping = FolderMapping { exclude: vec!["[oops".to_string()], ..Default::default() };
+        assert!(PathFilter::for_mapping(&mapping).is_err());
+    }
+}
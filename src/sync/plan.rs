@@ -0,0 +1,144 @@
+use serde::Serialize;
+use std::path::PathBuf;
+
+use super::SyncAction;
+use crate::config::{ConflictStrategy, FolderMapping};
+use crate::state::Decision;
+
+/// What a reconciliation pass would do to one mapping, as shown by a dry run
+#[derive(Debug, Clone, Serialize)]
+pub struct MappingPlan {
+    pub provider: String,
+    pub local_path: PathBuf,
+    pub remote_path: String,
+    pub actions: Vec<SyncAction>,
+    pub summary: PlanSummary,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PlanSummary {
+    pub uploads: usize,
+    pub downloads: usize,
+    pub local_deletes: usize,
+    pub remote_deletes: usize,
+    pub moves: usize,
+    pub conflicts: usize,
+    pub upload_bytes: u64,
+    pub download_bytes: u64,
+}
+
+impl MappingPlan {
+    pub fn new(provider: &str, mapping: &FolderMapping, actions: Vec<SyncAction>) -> Self {
+        let summary = PlanSummary::new(&actions, mapping.conflict_strategy);
+        Self {
+            provider: provider.to_string(),
+            local_path: mapping.local_path.clone(),
+            remote_path: mapping.remote_path.clone(),
+            actions,
+            summary,
+        }
+    }
+}
+
+impl PlanSummary {
+    /// Count the actions of a plan. Conflicts add the transfers their strategy would make.
+    pub fn new(actions: &[SyncAction], strategy: ConflictStrategy) -> Self {
+        let mut summary = Self::default();
+
+        for action in actions {
+            let local_size = action.local.as_ref().map_or(0, |local| local.size);
+            let remote_size = action.remote.as_ref().map_or(0, |remote| remote.size);
+
+            match action.decision {
+                Decision::Upload => {
+                    summary.uploads += 1;
+                    summary.upload_bytes += local_size;
+                }
+                Decision::Download => {
+                    summary.downloads += 1;
+                    summary.download_bytes += remote_size;
+                }
+                Decision::DeleteLocal => summary.local_deletes += 1,
+                Decision::DeleteRemote => summary.remote_deletes += 1,
+                Decision::MoveRemote | Decision::MoveLocal => summary.moves += 1,
+                Decision::Conflict => {
+                    summary.conflicts += 1;
+                    let newest_is_local = match (&action.local, &action.remote) {
+                        (Some(local), Some(remote)) => local.modified >= remote.modified,
+                        _ => false,
+                    };
+                    match strategy {
+                        ConflictStrategy::Manual => {}
+                        // The remote version comes down and the local one goes up as a copy
+                        ConflictStrategy::KeepBoth => {
+                            summary.download_bytes += remote_size;
+                            summary.upload_bytes += local_size;
+                        }
+                        ConflictStrategy::PreferLocal => summary.upload_bytes += local_size,
+                        ConflictStrategy::PreferRemote => summary.download_bytes += remote_size,
+                        ConflictStrategy::PreferNewest if newest_is_local => summary.upload_bytes += local_size,
+                        ConflictStrategy::PreferNewest => summary.download_bytes += remote_size,
+                    }
+                }
+                Decision::InSync | Decision::Record | Decision::Forget | Decision::Ignore => {}
+            }
+        }
+
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{LocalVersion, RemoteVersion};
+    use chrono::{DateTime, Utc};
+
+    fn action(decision: Decision, local_size: Option<u64>, remote_size: Option<u64>) -> SyncAction {
+        SyncAction {
+            relative: "file".to_string(),
+            decision,
+            local: local_size.map(|size| LocalVersion {
+                hash: String::new(),
+                size,
+                modified: DateTime::<Utc>::UNIX_EPOCH,
+                inode: None,
+            }),
+            remote: remote_size.map(|size| RemoteVersion {
+                id: String::new(),
+                etag: None,
+                size,
+                modified: DateTime::<Utc>::UNIX_EPOCH,
+            }),
+            moved_from: None,
+        }
+    }
+
+    #[test]
+    fn test_summary_counts_transfers() {
+        let actions = [
+            action(Decision::Upload, Some(10), None),
+            action(Decision::Download, None, Some(200)),
+            action(Decision::DeleteRemote, None, Some(5)),
+            action(Decision::MoveLocal, None, Some(7)),
+            action(Decision::Conflict, Some(1), Some(3000)),
+        ];
+
+        let keep_both = PlanSummary::new(&actions, ConflictStrategy::KeepBoth);
+        assert_eq!(keep_both, PlanSummary {
+            uploads: 1,
+            downloads: 1,
+            local_deletes: 0,
+            remote_deletes: 1,
+            moves: 1,
+            conflicts: 1,
+            upload_bytes: 11,
+            download_bytes: 3200,
+        });
+
+        let manual = PlanSummary::new(&actions, ConflictStrategy::Manual);
+        assert_eq!((manual.upload_bytes, manual.download_bytes), (10, 200));
+        let prefer_local = PlanSummary::new(&actions, ConflictStrategy::PreferLocal);
+        assert_eq!((prefer_local.upload_bytes, prefer_local.download_bytes), (11, 200));
+    }
+}
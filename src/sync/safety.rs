@@ -1,197 +1,314 @@
-use anyhow::Result;
-use chrono::{DateTime, Utc};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Duration, Utc};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
-    fs,
+    collections::HashSet,
     path::{Path, PathBuf},
-    sync::Arc,
 };
 use tokio::{
-    fs::File,
+    fs,
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::RwLock,
 };
 
-const MAX_BACKUPS: usize = 5;
+use crate::config::Retention;
+
+/// Objects younger than this are never pruned
+const PRUNE_GRACE_MINUTES: i64 = 10;
 
-#[derive(Clone, Debug)]
-pub struct FileMetadata {
+/// One stored version of a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    /// SHA-256 of the content, which is also where the store keeps it
     pub hash: String,
-    pub modified: DateTime<Utc>,
     pub size: u64,
-    pub backup_path: Option<PathBuf>,
+    /// Modification time of the file when it was saved
+    pub modified: DateTime<Utc>,
+    pub saved_at: DateTime<Utc>,
+    /// The file was deleted at `saved_at`; such a version has no content
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
 }
 
+/// Every version of one file, newest first
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct VersionIndex {
+    relative: String,
+    versions: Vec<Version>,
+}
+
+/// Content-addressed store of past file versions for one mapping.
+///
+/// Contents live once under `objects/<aa>/<sha256>`, however many files or
+/// versions share them. Each file has its own index under `index/`, keyed by
+/// a hash of its path relative to the mapping root, so files with the same
+/// name in different folders never mix.
 pub struct SafeSync {
-    metadata_cache: Arc<RwLock<HashMap<PathBuf, FileMetadata>>>,
-    backup_dir: PathBuf,
+    root: PathBuf,
+    retention: Retention,
 }
 
 impl SafeSync {
-    pub fn new(backup_dir: PathBuf) -> Self {
-        Self {
-            metadata_cache: Arc::new(RwLock::new(HashMap::new())),
-            backup_dir,
-        }
-    }
-
-    /// Verifica se é seguro sincronizar um arquivo
-    pub async fn is_safe_to_sync(&self, path: &Path) -> Result<bool> {
-        let metadata = tokio::fs::metadata(path).await?;
-        let modified = metadata.modified()?.into();
-        let size = metadata.len();
-
-        let hash = self.calculate_hash(path).await?;
-
-        // Verificar se o arquivo existe no cache
-        if let Some(cached) = self.metadata_cache.read().await.get(path) {
-            // Se o arquivo foi modificado, verificar se temos backup
-            if cached.modified != modified || cached.size != size || cached.hash != hash {
-                if let Some(backup_path) = &cached.backup_path {
-                    // Verificar integridade do backup
-                    if self.verify_backup(backup_path, &cached.hash).await? {
-                        return Ok(true);
-                    }
-                }
-                return Ok(false);
-            }
+    pub fn new(root: PathBuf) -> Self {
+        Self { root, retention: Retention::default() }
+    }
+
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.root.join("objects").join(&hash[..2]).join(hash)
+    }
+
+    fn index_path(&self, relative: &str) -> PathBuf {
+        let key = hex::encode(Sha256::digest(relative.as_bytes()));
+        self.root.join("index").join(format!("{}.json", &key[..32]))
+    }
+
+    async fn read_index(&self, relative: &str) -> Result<VersionIndex> {
+        let path = self.index_path(relative);
+        if !fs::try_exists(&path).await? {
+            return Ok(VersionIndex { relative: relative.to_string(), versions: Vec::new() });
         }
+        Ok(serde_json::from_str(&fs::read_to_string(&path).await?)?)
+    }
 
-        // Se o arquivo não existe no cache, criar backup
-        self.create_backup(path, &hash).await?;
-        Ok(true)
+    async fn write_index(&self, index: &VersionIndex) -> Result<()> {
+        let path = self.index_path(&index.relative);
+        if index.versions.is_empty() {
+            return match fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_string_pretty(index)?).await?;
+        fs::rename(&partial, &path).await?;
+        Ok(())
     }
 
-    /// Calcula o hash SHA-256 de um arquivo
-    async fn calculate_hash(&self, path: &Path) -> Result<String> {
-        let mut file = File::open(path).await?;
-        let mut hasher = Sha256::new();
-        let mut buffer = [0; 8192];
+    /// Copy `path` into the store, hashing it on the way so the object always
+    /// matches its name even if the file changes meanwhile
+    async fn store_object(&self, path: &Path) -> Result<(String, u64)> {
+        let staging = self.root.join("staging");
+        fs::create_dir_all(&staging).await?;
+        let partial = tempfile::NamedTempFile::new_in(&staging)?.into_temp_path();
 
+        let mut source = fs::File::open(path).await?;
+        let mut target = fs::File::create(&partial).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut size = 0;
         loop {
-            let bytes_read = file.read(&mut buffer).await?;
-            if bytes_read == 0 {
+            let read = source.read(&mut buffer).await?;
+            if read == 0 {
                 break;
             }
-            hasher.update(&buffer[..bytes_read]);
+            hasher.update(&buffer[..read]);
+            target.write_all(&buffer[..read]).await?;
+            size += read as u64;
         }
+        target.sync_all().await?;
 
-        Ok(format!("{:x}", hasher.finalize()))
+        let hash = hex::encode(hasher.finalize());
+        let object = self.object_path(&hash);
+        if !fs::try_exists(&object).await? {
+            fs::create_dir_all(object.parent().expect("objects have a parent")).await?;
+            fs::rename(&partial, &object).await?;
+        }
+        Ok((hash, size))
     }
 
-    /// Cria um backup do arquivo
-    async fn create_backup(&self, path: &Path, hash: &str) -> Result<()> {
-        // Criar diretório de backup se não existir
-        tokio::fs::create_dir_all(&self.backup_dir).await?;
-
-        let backup_path = self.backup_dir.join(format!(
-            "{}_{}",
-            path.file_name().unwrap().to_string_lossy(),
-            hash
-        ));
+    /// Save the current content of `path` as a version of `relative`, unless
+    /// it is the same as the newest one, then apply the retention policy to
+    /// that file. Objects no longer referenced are removed by `prune`.
+    pub async fn save(&self, relative: &str, path: &Path) -> Result<Version> {
+        let modified: DateTime<Utc> = fs::metadata(path).await?.modified()?.into();
+        let (hash, size) = self.store_object(path).await?;
 
-        // Copiar arquivo para backup
-        tokio::fs::copy(path, &backup_path).await?;
+        let mut index = self.read_index(relative).await?;
+        if let Some(newest) = index.versions.first().filter(|newest| newest.hash == hash) {
+            return Ok(newest.clone());
+        }
 
-        // Atualizar cache
-        let metadata = tokio::fs::metadata(path).await?;
-        let file_metadata = FileMetadata {
-            hash: hash.to_string(),
-            modified: metadata.modified()?.into(),
-            size: metadata.len(),
-            backup_path: Some(backup_path),
-        };
+        let version = Version { hash, size, modified, saved_at: Utc::now(), deleted: false };
+        index.versions.insert(0, version.clone());
+        index.versions = retain(index.versions, &self.retention, Utc::now());
+        self.write_index(&index).await?;
+        Ok(version)
+    }
 
-        self.metadata_cache
-            .write()
-            .await
-            .insert(path.to_path_buf(), file_metadata);
+    /// Note that `relative` was deleted, so a restore to a later point in
+    /// time leaves it out. Does nothing for files without versions.
+    pub async fn record_deletion(&self, relative: &str) -> Result<()> {
+        let mut index = self.read_index(relative).await?;
+        let Some(newest) = index.versions.first() else { return Ok(()) };
+        if newest.deleted {
+            return Ok(());
+        }
 
-        // Limpar backups antigos
-        self.cleanup_old_backups(path).await?;
+        let now = Utc::now();
+        let tombstone = Version { hash: String::new(), size: 0, modified: now, saved_at: now, deleted: true };
+        index.versions.insert(0, tombstone);
+        index.versions = retain(index.versions, &self.retention, now);
+        self.write_index(&index).await
+    }
 
-        Ok(())
+    /// Stored versions of `relative`, newest first
+    pub async fn versions(&self, relative: &str) -> Result<Vec<Version>> {
+        Ok(self.read_index(relative).await?.versions)
     }
 
-    /// Verifica a integridade de um backup
-    async fn verify_backup(&self, backup_path: &Path, expected_hash: &str) -> Result<bool> {
-        let hash = self.calculate_hash(backup_path).await?;
-        Ok(hash == expected_hash)
+    /// The version of `relative` that was current at `at`, if the file
+    /// existed then
+    pub async fn version_at(&self, relative: &str, at: DateTime<Utc>) -> Result<Option<Version>> {
+        let versions = self.versions(relative).await?;
+        Ok(versions.into_iter().find(|version| version.saved_at <= at).filter(|version| !version.deleted))
     }
 
-    /// Restaura um arquivo do backup mais recente
-    pub async fn restore_from_backup(&self, path: &Path) -> Result<()> {
-        if let Some(metadata) = self.metadata_cache.read().await.get(path) {
-            if let Some(backup_path) = &metadata.backup_path {
-                if self.verify_backup(backup_path, &metadata.hash).await? {
-                    tokio::fs::copy(backup_path, path).await?;
-                    return Ok(());
-                }
+    /// Every path that has stored versions
+    pub async fn paths(&self) -> Result<Vec<String>> {
+        let mut paths = Vec::new();
+        let dir = self.root.join("index");
+        if !fs::try_exists(&dir).await? {
+            return Ok(paths);
+        }
+
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_some_and(|extension| extension == "json") {
+                let index: VersionIndex = serde_json::from_str(&fs::read_to_string(entry.path()).await?)?;
+                paths.push(index.relative);
             }
         }
-        Err(anyhow::anyhow!("No valid backup found"))
+        paths.sort();
+        Ok(paths)
     }
 
-    /// Remove backups antigos mantendo apenas os MAX_BACKUPS mais recentes
-    async fn cleanup_old_backups(&self, path: &Path) -> Result<()> {
-        let filename = path.file_name().unwrap().to_string_lossy();
-        let mut backups: Vec<_> = fs::read_dir(&self.backup_dir)?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| {
-                entry
-                    .file_name()
-                    .to_string_lossy()
-                    .starts_with(&filename.to_string())
-            })
-            .collect();
-
-        // Ordenar por data de modificação (mais recente primeiro)
-        backups
-            .sort_by_key(|entry| std::cmp::Reverse(entry.metadata().unwrap().modified().unwrap()));
-
-        // Remover backups excedentes
-        for entry in backups.iter().skip(MAX_BACKUPS) {
-            fs::remove_file(entry.path())?;
+    /// Write a stored version to `target`, checking it against its hash first
+    pub async fn restore(&self, version: &Version, target: &Path) -> Result<()> {
+        if version.deleted {
+            return Err(anyhow!("There is nothing to restore from a deletion"));
+        }
+        let object = self.object_path(&version.hash);
+        if !self.verify(version).await? {
+            return Err(anyhow!("Stored version {} is damaged or missing", version.hash));
         }
 
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let partial = target.with_file_name(format!(
+            ".{}.partial",
+            target.file_name().map(|name| name.to_string_lossy()).unwrap_or_default()
+        ));
+        fs::copy(&object, &partial).await?;
+        fs::rename(&partial, target).await?;
         Ok(())
     }
 
-    /// Verifica a integridade de um arquivo
-    pub async fn verify_file_integrity(&self, path: &Path) -> Result<bool> {
-        if let Some(metadata) = self.metadata_cache.read().await.get(path) {
-            let current_hash = self.calculate_hash(path).await?;
-            Ok(current_hash == metadata.hash)
-        } else {
-            Ok(false)
+    /// Whether the stored content of `version` still matches its hash
+    pub async fn verify(&self, version: &Version) -> Result<bool> {
+        if version.deleted {
+            return Ok(true);
+        }
+        let object = self.object_path(&version.hash);
+        if !fs::try_exists(&object).await? {
+            return Ok(false);
+        }
+        Ok(crate::state::hash_file(&object).await? == version.hash)
+    }
+
+    /// Apply the retention policy to every file and delete the objects no
+    /// version refers to anymore. Returns how many objects were deleted.
+    pub async fn prune(&self) -> Result<usize> {
+        let mut referenced = HashSet::new();
+        for relative in self.paths().await? {
+            let mut index = self.read_index(&relative).await?;
+            index.versions = retain(index.versions, &self.retention, Utc::now());
+            self.write_index(&index).await?;
+            referenced.extend(index.versions.into_iter().filter(|version| !version.deleted).map(|version| version.hash));
         }
+
+        let mut removed = 0;
+        let objects = self.root.join("objects");
+        if !fs::try_exists(&objects).await? {
+            return Ok(removed);
+        }
+        let mut shards = fs::read_dir(&objects).await?;
+        while let Some(shard) = shards.next_entry().await? {
+            if !shard.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut entries = fs::read_dir(shard.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                // A `save` running meanwhile may have stored an object it has not indexed yet
+                let stored_at: DateTime<Utc> = entry.metadata().await?.modified()?.into();
+                let settled = Utc::now() - stored_at > Duration::minutes(PRUNE_GRACE_MINUTES);
+                if settled && !referenced.contains(entry.file_name().to_string_lossy().as_ref()) {
+                    fs::remove_file(entry.path()).await?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
     }
 }
 
+/// The versions, newest first, that `retention` keeps at `now`
+fn retain(versions: Vec<Version>, retention: &Retention, now: DateTime<Utc>) -> Vec<Version> {
+    let mut days = HashSet::new();
+    let mut weeks = HashSet::new();
+    let daily_since = now - Duration::days(retention.keep_daily.into());
+    let weekly_since = now - Duration::weeks(retention.keep_weekly.into());
+
+    versions
+        .into_iter()
+        .enumerate()
+        .filter(|(position, version)| {
+            let date = version.saved_at.date_naive();
+            let week = date.iso_week();
+            // Evaluate every rule so each one marks its day or week as taken
+            let last = *position < retention.keep_last.max(1);
+            let daily = version.saved_at > daily_since && days.insert(date);
+            let weekly = version.saved_at > weekly_since && weeks.insert((week.year(), week.week()));
+            last || daily || weekly
+        })
+        .map(|(_, version)| version)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use tempfile::tempdir;
 
+    fn version(hash: &str, saved_at: DateTime<Utc>) -> Version {
+        Version { hash: hash.to_string(), size: 0, modified: saved_at, saved_at, deleted: false }
+    }
+
     #[tokio::test]
-    async fn test_safe_sync() -> Result<()> {
+    async fn test_versions_are_keyed_by_path() -> Result<()> {
         let temp_dir = tempdir()?;
-        let backup_dir = temp_dir.path().join("backups");
-        let safe_sync = SafeSync::new(backup_dir);
-
-        // Criar arquivo de teste
-        let test_file = temp_dir.path().join("test.txt");
-        tokio::fs::write(&test_file, b"test data").await?;
+        let store = SafeSync::new(temp_dir.path().join("versions"));
+        let file = temp_dir.path().join("README.md");
 
-        // Verificar se é seguro sincronizar
-        assert!(safe_sync.is_safe_to_sync(&test_file).await?);
+        tokio::fs::write(&file, b"project a").await?;
+        store.save("a/README.md", &file).await?;
+        tokio::fs::write(&file, b"project b").await?;
+        store.save("b/README.md", &file).await?;
+        tokio::fs::write(&file, b"project a, edited").await?;
+        store.save("a/README.md", &file).await?;
 
-        // Modificar arquivo
-        tokio::fs::write(&test_file, b"modified data").await?;
-
-        // Verificar que não é seguro sincronizar sem backup
-        assert!(!safe_sync.is_safe_to_sync(&test_file).await?);
+        assert_eq!(store.versions("a/README.md").await?.len(), 2);
+        assert_eq!(store.versions("b/README.md").await?.len(), 1);
+        assert_eq!(store.paths().await?, ["a/README.md", "b/README.md"]);
 
         Ok(())
     }
@@ -199,51 +316,127 @@ mod tests {
     #[tokio::test]
     async fn test_backup_restore() -> Result<()> {
         let temp_dir = tempdir()?;
-        let backup_dir = temp_dir.path().join("backups");
-        let safe_sync = SafeSync::new(backup_dir);
-
-        // Criar arquivo de teste
+        let store = SafeSync::new(temp_dir.path().join("versions"));
         let test_file = temp_dir.path().join("test.txt");
-        let original_data = b"test data";
-        tokio::fs::write(&test_file, original_data).await?;
 
-        // Criar backup
-        assert!(safe_sync.is_safe_to_sync(&test_file).await?);
-
-        // Modificar arquivo
+        tokio::fs::write(&test_file, b"test data").await?;
+        let original = store.save("test.txt", &test_file).await?;
         tokio::fs::write(&test_file, b"modified data").await?;
+        store.save("test.txt", &test_file).await?;
 
-        // Restaurar do backup
-        safe_sync.restore_from_backup(&test_file).await?;
+        store.restore(&original, &test_file).await?;
+        assert_eq!(tokio::fs::read(&test_file).await?, b"test data");
 
-        // Verificar conteúdo restaurado
-        let restored_data = tokio::fs::read(&test_file).await?;
-        assert_eq!(restored_data, original_data);
+        // A damaged object is refused instead of restored
+        tokio::fs::write(store.object_path(&original.hash), b"bit rot").await?;
+        assert!(!store.verify(&original).await?);
+        assert!(store.restore(&original, &test_file).await.is_err());
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_backup_cleanup() -> Result<()> {
+    async fn test_identical_content_is_stored_once() -> Result<()> {
         let temp_dir = tempdir()?;
-        let backup_dir = temp_dir.path().join("backups");
-        let safe_sync = SafeSync::new(backup_dir.clone());
+        let store = SafeSync::new(temp_dir.path().join("versions"))
+            .with_retention(Retention { keep_last: 2, keep_daily: 0, keep_weekly: 0 });
+        let file = temp_dir.path().join("logo.png");
+
+        tokio::fs::write(&file, b"same bytes").await?;
+        store.save("logo.png", &file).await?;
+        store.save("assets/logo.png", &file).await?;
+        // Saving unchanged content again does not add a version
+        store.save("logo.png", &file).await?;
+        assert_eq!(store.versions("logo.png").await?.len(), 1);
+
+        for i in 0..3 {
+            tokio::fs::write(&file, format!("data {}", i)).await?;
+            store.save("logo.png", &file).await?;
+        }
+        assert_eq!(store.versions("logo.png").await?.len(), 2);
 
-        // Criar arquivo de teste
-        let test_file = temp_dir.path().join("test.txt");
+        // Fresh objects may belong to a save in progress
+        assert_eq!(store.prune().await?, 0);
+        age_objects(&temp_dir.path().join("versions/objects")).await?;
 
-        // Criar múltiplos backups
-        for i in 0..MAX_BACKUPS + 2 {
-            tokio::fs::write(&test_file, format!("data {}", i)).await?;
-            safe_sync.is_safe_to_sync(&test_file).await?;
-        }
+        // "same bytes" is still used by assets/logo.png, "data 0" by nothing
+        assert_eq!(store.prune().await?, 1);
+        let objects = walk_count(&temp_dir.path().join("versions/objects")).await?;
+        assert_eq!(objects, 3);
 
-        // Verificar número de backups
-        let backup_count = fs::read_dir(&backup_dir)?
-            .filter_map(|entry| entry.ok())
-            .count();
-        assert_eq!(backup_count, MAX_BACKUPS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version_at() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let store = SafeSync::new(temp_dir.path().join("versions"));
+        let file = temp_dir.path().join("notes.txt");
+
+        let before = Utc::now();
+        tokio::fs::write(&file, b"first").await?;
+        let first = store.save("notes.txt", &file).await?;
+        let between = Utc::now();
+        tokio::fs::write(&file, b"second").await?;
+        let second = store.save("notes.txt", &file).await?;
+        let after_edit = Utc::now();
+        store.record_deletion("notes.txt").await?;
+        store.record_deletion("never-saved.txt").await?;
+
+        assert_eq!(store.version_at("notes.txt", before - Duration::seconds(1)).await?, None);
+        assert_eq!(store.version_at("notes.txt", between).await?, Some(first));
+        assert_eq!(store.version_at("notes.txt", after_edit).await?, Some(second));
+        assert_eq!(store.version_at("notes.txt", Utc::now()).await?, None);
+        assert_eq!(store.paths().await?, ["notes.txt"]);
 
         Ok(())
     }
+
+    async fn age_objects(dir: &Path) -> Result<()> {
+        let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        let mut shards = fs::read_dir(dir).await?;
+        while let Some(shard) = shards.next_entry().await? {
+            let mut entries = fs::read_dir(shard.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                std::fs::File::options().write(true).open(entry.path())?.set_modified(an_hour_ago)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn walk_count(dir: &Path) -> Result<usize> {
+        let mut count = 0;
+        let mut shards = fs::read_dir(dir).await?;
+        while let Some(shard) = shards.next_entry().await? {
+            let mut entries = fs::read_dir(shard.path()).await?;
+            while entries.next_entry().await?.is_some() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    #[test]
+    fn test_retention_policy() {
+        let now = "2024-05-31T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let hours_ago = |hours: i64| now - Duration::hours(hours);
+        let versions = vec![
+            version("today-late", hours_ago(1)),
+            version("today-early", hours_ago(3)),
+            version("yesterday-late", hours_ago(20)),
+            version("yesterday-early", hours_ago(22)),
+            version("last-week", hours_ago(24 * 8)),
+            version("last-month", hours_ago(24 * 40)),
+        ];
+        let kept = |retention: Retention| -> Vec<String> {
+            retain(versions.clone(), &retention, now).into_iter().map(|v| v.hash).collect()
+        };
+
+        assert_eq!(kept(Retention { keep_last: 2, keep_daily: 0, keep_weekly: 0 }), ["today-late", "today-early"]);
+        assert_eq!(kept(Retention { keep_last: 0, keep_daily: 7, keep_weekly: 0 }), ["today-late", "yesterday-late"]);
+        assert_eq!(
+            kept(Retention { keep_last: 1, keep_daily: 0, keep_weekly: 4 }),
+            ["today-late", "last-week"]
+        );
+    }
 }
@@ -0,0 +1,368 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+use super::{filter::PathFilter, partial_path};
+use crate::config::FolderMapping;
+use crate::provider::{CloudProvider, RemoteItem};
+use crate::state::{hash_file, reconcile, restrict, Decision, LocalVersion, RemoteVersion, SyncEntry, SyncState};
+
+/// One planned step of a reconciliation pass
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncAction {
+    /// Path relative to the mapping root, `/` separated
+    pub relative: String,
+    pub decision: Decision,
+    pub local: Option<LocalVersion>,
+    pub remote: Option<RemoteVersion>,
+    /// Source path of a `MoveRemote`/`MoveLocal`, relative like `relative`
+    pub moved_from: Option<String>,
+}
+
+/// Every regular file below `root`, keyed by relative path. Symlinks are
+/// skipped, and so are the folders `descend` turns down, e.g. an excluded
+/// `node_modules`, without reading anything below them.
+pub async fn scan_local(root: &Path, descend: impl Fn(&str) -> bool) -> Result<BTreeMap<String, PathBuf>> {
+    let mut files = BTreeMap::new();
+    if !fs::try_exists(root).await? {
+        return Ok(files);
+    }
+
+    let mut pending = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let relative = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+            let file_type = entry.file_type().await?;
+
+            if file_type.is_dir() {
+                if descend(&relative) {
+                    pending.push((entry.path(), relative));
+                }
+            } else if file_type.is_file() {
+                files.insert(relative, entry.path());
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Every file below `remote_root`, walked with `list_files`. Paths are built
+/// from item names since not every provider uses paths as ids.
+pub async fn scan_remote(provider: &dyn CloudProvider, remote_root: &str) -> Result<BTreeMap<String, RemoteItem>> {
+    let mut files = BTreeMap::new();
+    let remote_root = remote_root.trim_end_matches('/');
+    if provider.get_item(if remote_root.is_empty() { "/" } else { remote_root }).await?.is_none() {
+        return Ok(files);
+    }
+
+    let mut pending = vec![String::new()];
+    while let Some(prefix) = pending.pop() {
+        let dir = if prefix.is_empty() { format!("{}/", remote_root) } else { format!("{}/{}", remote_root, prefix) };
+        for item in provider.list_files(&dir).await? {
+            let relative = if prefix.is_empty() { item.name.clone() } else { format!("{}/{}", prefix, item.name) };
+            if item.is_folder {
+                pending.push(relative);
+            } else {
+                files.insert(relative, item);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Diff both trees of a mapping against the stored state, within what the
+/// mapping's mode and filters allow. Paths that are in sync or ignored are
+/// left out. Filtered paths are never deleted; if they were synced before,
+/// they are only forgotten.
+pub async fn plan_mapping(
+    provider: &dyn CloudProvider,
+    mapping: &FolderMapping,
+    state: &SyncState,
+) -> Result<Vec<SyncAction>> {
+    let filter = PathFilter::for_mapping(mapping)?;
+    let local_files = scan_local(&mapping.local_path, |folder| filter.allows_folder(folder)).await?;
+    let remote_files = scan_remote(provider, &mapping.remote_path).await?;
+    let stored = state.entries().await;
+
+    let paths: BTreeSet<&String> = local_files.keys().chain(remote_files.keys()).chain(stored.keys()).collect();
+    let mut actions = Vec::new();
+
+    for relative in paths {
+        if !filter.allows_file(relative) {
+            if stored.contains_key(relative) {
+                let decision = Decision::Forget;
+                actions.push(SyncAction { relative: relative.clone(), decision, local: None, remote: None, moved_from: None });
+            }
+            continue;
+        }
+
+        let local = match local_files.get(relative) {
+            Some(path) => state.local_version(relative, path).await?,
+            None => None,
+        };
+        let remote = remote_files.get(relative).map(RemoteVersion::from);
+        let decision = decide(provider, mapping, relative, stored.get(relative), local.as_ref(), remote.as_ref()).await?;
+
+        if !matches!(decision, Decision::InSync | Decision::Ignore) {
+            actions.push(SyncAction { relative: relative.clone(), decision, local, remote, moved_from: None });
+        }
+    }
+
+    pair_renames(&mut actions, &stored);
+    Ok(actions)
+}
+
+/// `reconcile` narrowed to the mapping's mode. A file on both sides that the
+/// state does not know yet, e.g. in a new mapping over an existing copy, is
+/// only a conflict if the content differs: upload times make mtimes useless there.
+pub(crate) async fn decide(
+    provider: &dyn CloudProvider,
+    mapping: &FolderMapping,
+    relative: &str,
+    base: Option<&SyncEntry>,
+    local: Option<&LocalVersion>,
+    remote: Option<&RemoteVersion>,
+) -> Result<Decision> {
+    let mut decision = reconcile(base, local, remote);
+    if let (None, Decision::Conflict, Some(local), Some(remote)) = (base, decision, local, remote) {
+        if same_content(provider, mapping, relative, local, remote).await? {
+            decision = Decision::Record;
+        }
+    }
+    Ok(restrict(decision, mapping.mode, local.is_some(), remote.is_some()))
+}
+
+/// Whether the remote copy of `relative` has the content of the local one.
+/// Only a download can tell for every provider, so sizes are compared first.
+async fn same_content(
+    provider: &dyn CloudProvider,
+    mapping: &FolderMapping,
+    relative: &str,
+    local: &LocalVersion,
+    remote: &RemoteVersion,
+) -> Result<bool> {
+    if local.size != remote.size {
+        return Ok(false);
+    }
+    let remote_path = format!("{}/{}", mapping.remote_path.trim_end_matches('/'), relative);
+    let probe = partial_path(&mapping.local_path.join(relative));
+    let hash = match provider.download_file(&remote_path, &probe).await {
+        Ok(()) => hash_file(&probe).await,
+        Err(e) => Err(e),
+    };
+    let _ = fs::remove_file(&probe).await;
+    Ok(hash? == local.hash)
+}
+
+fn same_local_file(base: &LocalVersion, current: &LocalVersion) -> bool {
+    match (base.inode, current.inode) {
+        (Some(base_inode), Some(inode)) if base_inode == inode => true,
+        _ => base.size == current.size && base.hash == current.hash,
+    }
+}
+
+fn same_remote_file(base: &RemoteVersion, current: &RemoteVersion) -> bool {
+    base.id == current.id
+        || (base.size == current.size && base.etag.is_some() && base.etag == current.etag)
+}
+
+/// Turn a deletion plus a matching new file into a single move. A file that
+/// vanished on one side pairs with a new, unknown file on the same side that
+/// has the same inode or content, so renaming a large folder does not
+/// re-transfer it.
+fn pair_renames(actions: &mut Vec<SyncAction>, stored: &BTreeMap<String, SyncEntry>) {
+    let mut consumed = BTreeSet::new();
+
+    for index in 0..actions.len() {
+        let target = &actions[index];
+        if stored.contains_key(&target.relative) {
+            continue;
+        }
+
+        let (source_decision, moved) = match target.decision {
+            Decision::Upload => (Decision::DeleteRemote, Decision::MoveRemote),
+            Decision::Download => (Decision::DeleteLocal, Decision::MoveLocal),
+            _ => continue,
+        };
+
+        let source = actions.iter().enumerate().find(|(candidate, action)| {
+            if action.decision != source_decision || consumed.contains(candidate) {
+                return false;
+            }
+            let Some(base) = stored.get(&action.relative) else { return false };
+            match (moved, &target.local, &target.remote) {
+                (Decision::MoveRemote, Some(local), _) => same_local_file(&base.local, local),
+                (Decision::MoveLocal, _, Some(remote)) => same_remote_file(&base.remote, remote),
+                _ => false,
+            }
+        });
+
+        if let Some((source_index, source)) = source {
+            let from = source.relative.clone();
+            consumed.insert(source_index);
+            actions[index].decision = moved;
+            actions[index].moved_from = Some(from);
+        }
+    }
+
+    let mut index = 0;
+    actions.retain(|_| {
+        let keep = !consumed.contains(&index);
+        index += 1;
+        keep
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::tests::{fixture, Fixture};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_plan_covers_both_sides() -> Result<()> {
+        let Fixture { provider, sync_op: _, state, mapping, dir: _dir } = fixture().await?;
+        fs::create_dir_all(mapping.local_path.join("docs")).await?;
+        fs::write(mapping.local_path.join("docs/local-only.txt"), b"up").await?;
+
+        provider.put_file("/backup/photos/remote-only.jpg", "down")?;
+        provider.put_file("/elsewhere.txt", "outside the mapping")?;
+
+        let plan = plan_mapping(&provider, &mapping, &state).await?;
+        let summary: Vec<(&str, Decision)> = plan.iter().map(|a| (a.relative.as_str(), a.decision)).collect();
+        assert_eq!(summary, [
+            ("docs/local-only.txt", Decision::Upload),
+            ("photos/remote-only.jpg", Decision::Download),
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plan_compares_content_of_untracked_copies() -> Result<()> {
+        let Fixture { provider, sync_op: _, state, mapping, dir: _dir } = fixture().await?;
+        for (name, local, remote) in [("same.txt", "same", "same"), ("edited.txt", "mine", "your")] {
+            let path = mapping.local_path.join(name);
+            fs::write(&path, local).await?;
+            // Copied here long before the remote copy was uploaded
+            let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+            std::fs::File::options().write(true).open(&path)?.set_modified(an_hour_ago)?;
+            provider.put_file(&format!("/backup/{}", name), remote)?;
+        }
+
+        let plan = plan_mapping(&provider, &mapping, &state).await?;
+        let summary: Vec<(&str, Decision)> = plan.iter().map(|a| (a.relative.as_str(), a.decision)).collect();
+        assert_eq!(summary, [("edited.txt", Decision::Conflict), ("same.txt", Decision::Record)]);
+        assert_eq!(scan_local(&mapping.local_path, |_| true).await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plan_skips_filtered_paths() -> Result<()> {
+        let Fixture { provider, sync_op: _, state, mut mapping, dir: _dir } = fixture().await?;
+        mapping.include = vec!["*.txt".to_string()];
+        mapping.exclude = vec!["cache".to_string()];
+        fs::create_dir_all(mapping.local_path.join("cache")).await?;
+        fs::write(mapping.local_path.join("notes.txt"), b"up").await?;
+        fs::write(mapping.local_path.join("photo.jpg"), b"not included").await?;
+        fs::write(mapping.local_path.join("cache/index.txt"), b"excluded").await?;
+
+        provider.put_file("/backup/todo.txt", "down")?;
+        provider.put_file("/backup/cache/remote.txt", "excluded")?;
+        provider.put_file("/backup/video.mp4", "not included")?;
+
+        let plan = plan_mapping(&provider, &mapping, &state).await?;
+        let summary: Vec<(&str, Decision)> = plan.iter().map(|a| (a.relative.as_str(), a.decision)).collect();
+        assert_eq!(summary, [("notes.txt", Decision::Upload), ("todo.txt", Decision::Download)]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scan_skips_turned_down_folders() -> Result<()> {
+        let dir = tempdir()?;
+        fs::create_dir_all(dir.path().join("node_modules/pkg")).await?;
+        fs::write(dir.path().join("node_modules/pkg/index.js"), b"js").await?;
+        fs::create_dir_all(dir.path().join("src")).await?;
+        fs::write(dir.path().join("src/main.rs"), b"rs").await?;
+
+        let asked = std::sync::Mutex::new(Vec::new());
+        let files = scan_local(dir.path(), |folder| {
+            asked.lock().unwrap().push(folder.to_string());
+            folder != "node_modules"
+        })
+        .await?;
+        assert_eq!(files.into_keys().collect::<Vec<_>>(), ["src/main.rs"]);
+        // Nothing below the pruned folder was even looked at
+        asked.lock().unwrap().sort();
+        assert_eq!(*asked.lock().unwrap(), ["node_modules", "src"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plan_forgets_newly_ignored_files() -> Result<()> {
+        let Fixture { provider, sync_op: _, state, mapping, dir: _dir } = fixture().await?;
+        fs::create_dir_all(mapping.local_path.join("target")).await?;
+        fs::write(mapping.local_path.join("target/app"), b"binary").await?;
+        provider.put_file("/backup/target/app", "binary")?;
+        for action in plan_mapping(&provider, &mapping, &state).await? {
+            state.set(&action.relative, SyncEntry {
+                local: action.local.unwrap(),
+                remote: action.remote.unwrap(),
+                synced_at: chrono::Utc::now(),
+            }).await;
+        }
+
+        fs::write(mapping.local_path.join(".filesyncignore"), b"target/\n").await?;
+        fs::remove_file(mapping.local_path.join("target/app")).await?;
+
+        let plan = plan_mapping(&provider, &mapping, &state).await?;
+        let summary: Vec<(&str, Decision)> = plan.iter().map(|a| (a.relative.as_str(), a.decision)).collect();
+        assert_eq!(summary, [(".filesyncignore", Decision::Upload), ("target/app", Decision::Forget)]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plan_pairs_renames() -> Result<()> {
+        let Fixture { provider, sync_op: _, state, mapping, dir: _dir } = fixture().await?;
+        fs::write(mapping.local_path.join("video.mp4"), b"big video").await?;
+        fs::write(mapping.local_path.join("notes.txt"), b"notes").await?;
+        provider.put_file("/backup/video.mp4", "big video")?;
+        provider.put_file("/backup/notes.txt", "notes")?;
+
+        // Adopt both files into the state, then rename one on each side
+        for action in plan_mapping(&provider, &mapping, &state).await? {
+            state.set(&action.relative, SyncEntry {
+                local: action.local.unwrap(),
+                remote: action.remote.unwrap(),
+                synced_at: chrono::Utc::now(),
+            }).await;
+        }
+        fs::create_dir_all(mapping.local_path.join("videos")).await?;
+        fs::rename(mapping.local_path.join("video.mp4"), mapping.local_path.join("videos/video.mp4")).await?;
+        provider.move_item("/backup/notes.txt", "/backup/archive/notes.txt").await?;
+
+        let plan = plan_mapping(&provider, &mapping, &state).await?;
+        let summary: Vec<(&str, Decision, Option<&str>)> = plan
+            .iter()
+            .map(|a| (a.relative.as_str(), a.decision, a.moved_from.as_deref()))
+            .collect();
+        assert_eq!(summary, [
+            ("archive/notes.txt", Decision::MoveLocal, Some("notes.txt")),
+            ("videos/video.mp4", Decision::MoveRemote, Some("video.mp4")),
+        ]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use super::safety::{SafeSync, Version};
+use super::{partial_path, SyncOperation};
+use crate::config::FolderMapping;
+use crate::provider::Revision;
+use crate::state::{hash_file, SyncState};
+
+/// What restoring one file to a point in time changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestoreChange {
+    /// The file did not exist at the target, it is written there
+    Create,
+    /// The target holds other content, which is replaced
+    Overwrite,
+    /// The file did not exist at that time, it goes to the trash
+    Delete,
+}
+
+impl RestoreChange {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RestoreChange::Create => "create",
+            RestoreChange::Overwrite => "overwrite",
+            RestoreChange::Delete => "delete",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoreAction {
+    pub relative: String,
+    pub change: RestoreChange,
+    /// The version written back, `None` for deletions
+    pub version: Option<Version>,
+}
+
+/// Bring a mapping, or a subtree of it, back to how it was at a point in
+/// time, from the versions kept by its `SafeSync` store.
+///
+/// Restoring in place changes the local folder, and the next sync carries
+/// that over to the remote side. Whatever a restore replaces or deletes is
+/// saved as a version first, and what it writes back becomes the newest
+/// version, so a restore can itself be undone. Restoring
+/// into another folder only ever writes files there, keeping their paths
+/// relative to the mapping root.
+#[derive(Debug, Clone, Serialize)]
+pub struct RestorePlan {
+    pub at: DateTime<Utc>,
+    /// Folder the files are restored into
+    pub target: PathBuf,
+    pub in_place: bool,
+    /// Files that already match are left out
+    pub actions: Vec<RestoreAction>,
+}
+
+impl RestorePlan {
+    /// Work out what restoring `subtree` (empty for the whole mapping) to
+    /// `at` would change, without touching anything. Files the store never
+    /// saw are left alone.
+    pub async fn new(
+        mapping: &FolderMapping,
+        state: &SyncState,
+        subtree: &str,
+        at: DateTime<Utc>,
+        into: Option<&Path>,
+    ) -> Result<Self> {
+        let store = SafeSync::new(state.versions_dir());
+        let target = into.unwrap_or(&mapping.local_path).to_path_buf();
+        let in_place = into.is_none();
+        let prefix = format!("{}/", subtree);
+
+        let mut actions = Vec::new();
+        for relative in store.paths().await? {
+            if !(subtree.is_empty() || relative == subtree || relative.starts_with(&prefix)) {
+                continue;
+            }
+
+            let path = target.join(&relative);
+            let current = match fs::metadata(&path).await {
+                Ok(metadata) if metadata.is_file() => Some(hash_file(&path).await?),
+                _ => None,
+            };
+            let change = match (store.version_at(&relative, at).await?, current) {
+                (Some(version), Some(hash)) if version.hash == hash => None,
+                (Some(version), Some(_)) => Some((RestoreChange::Overwrite, Some(version))),
+                (Some(version), None) => Some((RestoreChange::Create, Some(version))),
+                (None, Some(_)) if in_place => Some((RestoreChange::Delete, None)),
+                (None, _) => None,
+            };
+            if let Some((change, version)) = change {
+                actions.push(RestoreAction { relative, change, version });
+            }
+        }
+
+        Ok(Self { at, target, in_place, actions })
+    }
+
+    /// Carry out the plan. Returns how many files were changed.
+    pub async fn apply(&self, mapping: &FolderMapping, state: &SyncState) -> Result<usize> {
+        let store = SafeSync::new(state.versions_dir()).with_retention(mapping.versions.unwrap_or_default());
+
+        for action in &self.actions {
+            let path = self.target.join(&action.relative);
+            if self.in_place && fs::try_exists(&path).await? {
+                store.save(&action.relative, &path).await?;
+            }
+
+            match &action.version {
+                Some(version) => {
+                    println!("Restoring {:?} as of {}", path, version.saved_at.format("%Y-%m-%d %H:%M:%S"));
+                    store.restore(version, &path).await?;
+                    if self.in_place {
+                        store.save(&action.relative, &path).await?;
+                    }
+                }
+                None => {
+                    SyncOperation::trash_local(mapping, &action.relative).await?;
+                    store.record_deletion(&action.relative).await?;
+                }
+            }
+        }
+
+        Ok(self.actions.len())
+    }
+}
+
+impl SyncOperation {
+    /// The revisions the provider keeps of a file of the mapping, newest first
+    pub async fn revisions(&self, mapping: &FolderMapping, relative: &str) -> Result<Vec<Revision>> {
+        self.provider.list_revisions(&Self::remote_path_for(mapping, relative)).await
+    }
+
+    /// Bring back a revision the provider keeps of a file. In place, it
+    /// replaces the local copy, which is saved as a version first if the
+    /// mapping keeps versions, and is synced up as the newest revision. With
+    /// `into`, it is only downloaded into that folder. Returns where it went.
+    pub async fn restore_revision(
+        &self,
+        mapping: &FolderMapping,
+        state: &SyncState,
+        relative: &str,
+        revision_id: &str,
+        into: Option<&Path>,
+    ) -> Result<PathBuf> {
+        let remote_path = Self::remote_path_for(mapping, relative);
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        let target = match into {
+            Some(dir) => dir.join(name),
+            None => mapping.local_path.join(relative),
+        };
+
+        println!("Downloading revision {} of {} to {:?}", revision_id, remote_path, target);
+        let partial = partial_path(&target);
+        if let Err(e) = self.provider.download_revision(&remote_path, revision_id, &partial).await {
+            let _ = fs::remove_file(&partial).await;
+            return Err(e);
+        }
+        if into.is_none() {
+            Self::keep_version(mapping, state, relative).await?;
+        }
+        fs::rename(&partial, &target).await?;
+
+        if into.is_none() {
+            self.sync_batch(mapping, state, &[relative.to_string()]).await?;
+        }
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::tests::{fixture, Fixture};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_restore_to_point_in_time() -> Result<()> {
+        let dir = tempdir()?;
+        let state = SyncState::open(dir.path().join("state.json")).await?;
+        let mapping = FolderMapping { local_path: dir.path().join("sync"), ..Default::default() };
+        let store = SafeSync::new(state.versions_dir());
+        let local = |relative: &str| mapping.local_path.join(relative);
+        fs::create_dir_all(local("docs")).await?;
+
+        fs::write(local("docs/a.txt"), b"a v1").await?;
+        store.save("docs/a.txt", &local("docs/a.txt")).await?;
+        fs::write(local("docs/gone.txt"), b"deleted later").await?;
+        store.save("docs/gone.txt", &local("docs/gone.txt")).await?;
+        fs::write(local("top.txt"), b"outside the subtree").await?;
+        store.save("top.txt", &local("top.txt")).await?;
+        let at = Utc::now();
+
+        fs::write(local("docs/a.txt"), b"a v2").await?;
+        store.save("docs/a.txt", &local("docs/a.txt")).await?;
+        fs::remove_file(local("docs/gone.txt")).await?;
+        store.record_deletion("docs/gone.txt").await?;
+        fs::write(local("docs/new.txt"), b"created later").await?;
+        store.save("docs/new.txt", &local("docs/new.txt")).await?;
+        fs::write(local("top.txt"), b"edited").await?;
+
+        // Into another folder, nothing is deleted
+        let elsewhere = dir.path().join("restored");
+        let plan = RestorePlan::new(&mapping, &state, "docs", at, Some(&elsewhere)).await?;
+        let changes: Vec<_> = plan.actions.iter().map(|a| (a.relative.as_str(), a.change)).collect();
+        assert_eq!(changes, [("docs/a.txt", RestoreChange::Create), ("docs/gone.txt", RestoreChange::Create)]);
+        plan.apply(&mapping, &state).await?;
+        assert_eq!(fs::read(elsewhere.join("docs/a.txt")).await?, b"a v1");
+        assert_eq!(fs::read(local("docs/a.txt")).await?, b"a v2");
+
+        // In place, files created since then go to the trash
+        let plan = RestorePlan::new(&mapping, &state, "docs", at, None).await?;
+        let changes: Vec<_> = plan.actions.iter().map(|a| (a.relative.as_str(), a.change)).collect();
+        assert_eq!(changes, [
+            ("docs/a.txt", RestoreChange::Overwrite),
+            ("docs/gone.txt", RestoreChange::Create),
+            ("docs/new.txt", RestoreChange::Delete),
+        ]);
+        assert_eq!(plan.apply(&mapping, &state).await?, 3);
+        assert_eq!(fs::read(local("docs/a.txt")).await?, b"a v1");
+        assert_eq!(fs::read(local("docs/gone.txt")).await?, b"deleted later");
+        assert!(!local("docs/new.txt").exists());
+        assert_eq!(fs::read(local("top.txt")).await?, b"edited");
+
+        // What the restore replaced or deleted is kept in turn
+        let a_versions = store.versions("docs/a.txt").await?;
+        assert_eq!(a_versions.len(), 3);
+        store.restore(&a_versions[1], &elsewhere.join("a v2.txt")).await?;
+        assert_eq!(fs::read(elsewhere.join("a v2.txt")).await?, b"a v2");
+        assert!(store.versions("docs/new.txt").await?[0].deleted);
+        let plan = RestorePlan::new(&mapping, &state, "docs", at, None).await?;
+        assert!(plan.actions.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_remote_revision() -> Result<()> {
+        let Fixture { provider, sync_op, state, mut mapping, dir } = fixture().await?;
+        mapping.versions = Some(Default::default());
+        let file = mapping.local_path.join("budget.xlsx");
+        fs::write(&file, b"our numbers").await?;
+        sync_op.sync_path(&mapping, &state, "budget.xlsx").await?;
+        provider.put_file("/backup/budget.xlsx", "overwritten")?;
+        sync_op.sync_path(&mapping, &state, "budget.xlsx").await?;
+
+        let revisions = sync_op.revisions(&mapping, "budget.xlsx").await?;
+        assert_eq!(revisions.len(), 2);
+
+        // Into another folder, only a copy is made
+        let elsewhere = dir.path().join("restored");
+        let copy = sync_op.restore_revision(&mapping, &state, "budget.xlsx", &revisions[1].id, Some(&elsewhere)).await?;
+        assert_eq!(fs::read(&copy).await?, b"our numbers");
+        assert_eq!(fs::read(&file).await?, b"overwritten");
+
+        // In place, the old revision becomes the newest one on both sides
+        sync_op.restore_revision(&mapping, &state, "budget.xlsx", &revisions[1].id, None).await?;
+        assert_eq!(fs::read(&file).await?, b"our numbers");
+        assert_eq!(provider.read("/backup/budget.xlsx").as_deref(), Some(&b"our numbers"[..]));
+        assert_eq!(sync_op.revisions(&mapping, "budget.xlsx").await?.len(), 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::Result;
+use chrono::{Duration, Local, NaiveDate};
+use tokio::fs;
+
+use super::{conflict_copy_path, SyncOperation};
+use crate::config::FolderMapping;
+
+/// Folder at the root of each side of a mapping that keeps deleted files,
+/// one subfolder per day. It never takes part in the sync itself.
+pub const TRASH_DIR: &str = ".filesynchub-trash";
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// `.filesynchub-trash/<date>/<relative>`, numbered from the second attempt on
+fn trash_path(relative: &str, date: &str, attempt: u32) -> String {
+    let relative = match attempt {
+        1 => relative.to_string(),
+        n => conflict_copy_path(relative, &n.to_string()),
+    };
+    format!("{}/{}/{}", TRASH_DIR, date, relative)
+}
+
+/// Whether a day folder of the trash is older than the retention allows
+fn expired(name: &str, today: NaiveDate, days: u32) -> bool {
+    NaiveDate::parse_from_str(name, DATE_FORMAT).is_ok_and(|date| date < today - Duration::days(days.into()))
+}
+
+impl SyncOperation {
+    /// Delete a remote file so it can still be restored: into the provider's
+    /// trash if it has one, into the mapping's trash folder otherwise
+    pub(super) async fn trash_remote(&self, mapping: &FolderMapping, relative: &str) -> Result<()> {
+        let remote_path = Self::remote_path_for(mapping, relative);
+        if mapping.trash_retention_days() == 0 {
+            println!("Deleting remote file: {}", remote_path);
+            return self.provider.delete(&remote_path).await;
+        }
+
+        println!("Moving remote file to the trash: {}", remote_path);
+        if self.provider.move_to_trash(&remote_path).await? {
+            return Ok(());
+        }
+
+        let date = Local::now().format(DATE_FORMAT).to_string();
+        for attempt in 1.. {
+            let target = Self::remote_path_for(mapping, &trash_path(relative, &date, attempt));
+            if !self.provider.exists(&target).await? {
+                self.provider.move_item(&remote_path, &target).await?;
+                return Ok(());
+            }
+        }
+        unreachable!("the attempt counter is unbounded")
+    }
+
+    /// Delete a local file by moving it into the mapping's local trash folder
+    pub(super) async fn trash_local(mapping: &FolderMapping, relative: &str) -> Result<()> {
+        let local_path = mapping.local_path.join(relative);
+        if mapping.trash_retention_days() == 0 {
+            println!("Deleting local file: {:?}", local_path);
+            return Ok(fs::remove_file(&local_path).await?);
+        }
+
+        println!("Moving local file to the trash: {:?}", local_path);
+        let date = Local::now().format(DATE_FORMAT).to_string();
+        for attempt in 1.. {
+            let target = mapping.local_path.join(trash_path(relative, &date, attempt));
+            if !fs::try_exists(&target).await? {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                fs::rename(&local_path, &target).await?;
+                return Ok(());
+            }
+        }
+        unreachable!("the attempt counter is unbounded")
+    }
+
+    /// Remove the days of both trash folders of a mapping that are past its
+    /// retention. Returns how many day folders were removed. Items in a
+    /// provider's own trash follow that provider's rules instead.
+    pub async fn purge_trash(&self, mapping: &FolderMapping) -> Result<usize> {
+        let days = mapping.trash_retention_days();
+        let today = Local::now().date_naive();
+        let mut purged = 0;
+
+        let local_trash = mapping.local_path.join(TRASH_DIR);
+        if fs::try_exists(&local_trash).await? {
+            let mut entries = fs::read_dir(&local_trash).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await?.is_dir() && expired(&entry.file_name().to_string_lossy(), today, days) {
+                    fs::remove_dir_all(entry.path()).await?;
+                    purged += 1;
+                }
+            }
+        }
+
+        let remote_trash = Self::remote_path_for(mapping, TRASH_DIR);
+        if self.provider.exists(&remote_trash).await? {
+            for item in self.provider.list_files(&format!("{}/", remote_trash)).await? {
+                if item.is_folder && expired(&item.name, today, days) {
+                    self.provider.delete(&format!("{}/{}", remote_trash, item.name)).await?;
+                    purged += 1;
+                }
+            }
+        }
+
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trash_path() {
+        assert_eq!(trash_path("docs/a.txt", "2024-05-01", 1), ".filesynchub-trash/2024-05-01/docs/a.txt");
+        assert_eq!(trash_path("docs/a.txt", "2024-05-01", 2), ".filesynchub-trash/2024-05-01/docs/a (2).txt");
+    }
+
+    #[test]
+    fn test_expired() {
+        let today = NaiveDate::from_ymd_opt(2024, 5, 31).unwrap();
+        assert!(expired("2024-04-30", today, 30));
+        assert!(!expired("2024-05-01", today, 30));
+        assert!(!expired("not a date", today, 0));
+    }
+}
@@ -14,17 +14,95 @@ use ratatui::{
 };
 use std::io;
 use crate::config::Config;
+use crate::provider::factory;
+use crate::state::SyncState;
+use crate::sync::SyncOperation;
+
+/// A mapping held back by the deletion guard
+struct Paused {
+    provider: usize,
+    mapping: usize,
+    summary: String,
+}
 
 pub struct Tui {
     config: Config,
+    conflicts: Vec<String>,
+    paused: Vec<Paused>,
+    selected: usize,
+    status: Option<String>,
 }
 
 impl Tui {
     pub fn new(config: Config) -> Result<Self> {
-        Ok(Self { config })
+        Ok(Self { config, conflicts: Vec::new(), paused: Vec::new(), selected: 0, status: None })
+    }
+
+    /// Collect the mappings waiting for their deletions to be confirmed
+    async fn load_paused(&mut self) -> Result<()> {
+        self.paused.clear();
+        for (provider_index, provider) in self.config.providers.iter().enumerate() {
+            for (mapping_index, mapping) in provider.mappings.iter().enumerate() {
+                let state = SyncState::for_mapping(&provider.name, mapping).await?;
+                if let Some(paused) = state.paused().await {
+                    self.paused.push(Paused {
+                        provider: provider_index,
+                        mapping: mapping_index,
+                        summary: format!(
+                            "{} {}: {} local and {} remote deletion(s) since {}",
+                            provider.name,
+                            mapping.local_path.display(),
+                            paused.delete_local.len(),
+                            paused.delete_remote.len(),
+                            paused.detected_at.format("%Y-%m-%d %H:%M"),
+                        ),
+                    });
+                }
+            }
+        }
+        self.selected = self.selected.min(self.paused.len().saturating_sub(1));
+        Ok(())
+    }
+
+    /// Carry out or undo the deletions of the selected paused mapping
+    async fn confirm_selected(&mut self, restore: bool) -> Result<()> {
+        let Some(paused) = self.paused.get(self.selected) else { return Ok(()) };
+        let provider_config = &self.config.providers[paused.provider];
+        let mapping = &provider_config.mappings[paused.mapping];
+
+        let mut provider = factory::create_provider(provider_config).await?;
+        provider.initialize().await?;
+        let sync_op = SyncOperation::new(provider);
+        let state = SyncState::for_mapping(&provider_config.name, mapping).await?;
+
+        let applied = sync_op.confirm_deletions(mapping, &state, restore).await?;
+        self.status = Some(format!("Resumed {} with {} change(s)", mapping.local_path.display(), applied.len()));
+        self.load_paused().await
+    }
+
+    /// Collect the unresolved conflicts of every mapping
+    async fn load_conflicts(&mut self) -> Result<()> {
+        self.conflicts.clear();
+        for provider in &self.config.providers {
+            for mapping in &provider.mappings {
+                let state = SyncState::for_mapping(&provider.name, mapping).await?;
+                for conflict in state.unresolved_conflicts().await {
+                    self.conflicts.push(format!(
+                        "{} {} ({})",
+                        provider.name,
+                        mapping.local_path.join(&conflict.relative).display(),
+                        conflict.detected_at.format("%Y-%m-%d %H:%M"),
+                    ));
+                }
+            }
+        }
+        Ok(())
     }
 
     pub async fn run(&mut self) -> Result<()> {
+        self.load_conflicts().await?;
+        self.load_paused().await?;
+
         // Set up terminal
         enable_raw_mode()?;
         let mut stdout = io::stdout();
@@ -53,6 +131,8 @@ impl Tui {
                     .constraints([
                         Constraint::Length(3),
                         Constraint::Min(0),
+                        Constraint::Length(self.conflicts.len().clamp(1, 8) as u16 + 2),
+                        Constraint::Length(self.paused.len().clamp(1, 8) as u16 + 2),
                     ].as_ref())
                     .split(f.size());
 
@@ -67,7 +147,7 @@ impl Tui {
                     .iter()
                     .map(|p| {
                         let status = if p.enabled { "✓" } else { "✗" };
-                        let text = format!("{} {} ({})", status, p.name, p.credentials.provider_type);
+                        let text = format!("{} {} ({})", status, p.name, p.credentials.provider_type());
                         ListItem::new(Text::raw(text))
                     })
                     .collect();
@@ -76,11 +156,53 @@ impl Tui {
                     .block(Block::default().title("Providers").borders(Borders::ALL))
                     .style(Style::default().fg(Color::White));
                 f.render_widget(providers, chunks[1]);
+
+                // Conflicts waiting for `filesync resolve`
+                let conflict_items: Vec<ListItem> = if self.conflicts.is_empty() {
+                    vec![ListItem::new(Text::raw("No conflicts"))]
+                } else {
+                    self.conflicts.iter().map(|c| ListItem::new(Text::raw(c.as_str()))).collect()
+                };
+                let conflict_color = if self.conflicts.is_empty() { Color::White } else { Color::Yellow };
+                let conflicts = List::new(conflict_items)
+                    .block(Block::default().title("Conflicts").borders(Borders::ALL))
+                    .style(Style::default().fg(conflict_color));
+                f.render_widget(conflicts, chunks[2]);
+
+                // Mappings paused by the deletion guard, settled with `c` or `r`
+                let paused_items: Vec<ListItem> = if self.paused.is_empty() {
+                    vec![ListItem::new(Text::raw("No paused mappings"))]
+                } else {
+                    self.paused
+                        .iter()
+                        .enumerate()
+                        .map(|(index, paused)| {
+                            let marker = if index == self.selected { ">" } else { " " };
+                            ListItem::new(Text::raw(format!("{} {}", marker, paused.summary)))
+                        })
+                        .collect()
+                };
+                let paused_color = if self.paused.is_empty() { Color::White } else { Color::Red };
+                let title = match &self.status {
+                    Some(status) => format!("Paused: c confirm, r restore ({})", status),
+                    None => "Paused: c confirm, r restore".to_string(),
+                };
+                let paused = List::new(paused_items)
+                    .block(Block::default().title(title).borders(Borders::ALL))
+                    .style(Style::default().fg(paused_color));
+                f.render_widget(paused, chunks[3]);
             })?;
 
             if let Event::Key(key) = event::read()? {
                 match key.code {
                     KeyCode::Char('q') => return Ok(()),
+                    KeyCode::Up => self.selected = self.selected.saturating_sub(1),
+                    KeyCode::Down if self.selected + 1 < self.paused.len() => self.selected += 1,
+                    KeyCode::Char(c @ ('c' | 'r')) => {
+                        if let Err(e) = self.confirm_selected(c == 'r').await {
+                            self.status = Some(e.to_string());
+                        }
+                    }
                     _ => {}
                 }
             }
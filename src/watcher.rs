@@ -1,107 +1,950 @@
 use anyhow::Result;
-use notify::{RecommendedWatcher, RecursiveMode};
-use notify_debouncer_mini::{new_debouncer, DebouncedEvent, Debouncer};
+use notify::{
+    event::{AccessKind, AccessMode, MetadataKind, ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
 use std::{
-    path::PathBuf,
-    sync::Arc,
-    time::Duration,
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    fs,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Interval, MissedTickBehavior},
 };
-use tokio::sync::mpsc;
 
+/// How long the first half of a rename waits for the second before it counts
+/// as the path leaving the watched folder
+const RENAME_WINDOW: Duration = Duration::from_millis(100);
+
+/// How long a file must keep its size and modification time before it
+/// counts as written, unless its writer closes it first
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// A change to a file or folder on the local disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeType {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Deleted(PathBuf),
+    Renamed { from: PathBuf, to: PathBuf },
+    /// Changes below this folder may have been missed, look at all of it again
+    Rescan(PathBuf),
+}
+
+/// Watches a local folder and everything below it, whatever provider the
+/// folder is synced with, and reports what changed as `ChangeType`s.
+///
+/// When the platform says events were lost, e.g. an inotify queue overflow,
+/// or a folder could not be watched, the affected folder is reported as a
+/// `Rescan`. An optional interval adds a `Rescan` of the whole tree now and
+/// then, so whatever still slipped through is eventually picked up.
+///
+/// Network shares get no events for changes other machines make, so a
+/// watcher can poll instead: it compares snapshots of the tree taken every
+/// poll interval.
+///
+/// Files being written are held back until they are done, so nothing is
+/// synced half-written. A new file renamed over another, or written after
+/// the old one was moved aside, as editors save, is reported as a single
+/// change of the file it replaced.
 pub struct FileWatcher {
     path: PathBuf,
-    watcher: Option<Debouncer<RecommendedWatcher>>,
+    rescan_interval: Option<Duration>,
+    poll_interval: Option<Duration>,
+    settle_time: Duration,
+    watcher: Option<RecommendedWatcher>,
+    task: Option<JoinHandle<()>>,
 }
 
 impl FileWatcher {
     pub fn new(path: PathBuf) -> Self {
-        Self {
-            path,
-            watcher: None,
+        Self { path, rescan_interval: None, poll_interval: None, settle_time: SETTLE_TIME, watcher: None, task: None }
+    }
+
+    /// How long a file must stay unchanged before it is reported, unless its
+    /// writer closes it first
+    pub fn with_settle_time(mut self, settle_time: Duration) -> Self {
+        self.settle_time = settle_time;
+        self
+    }
+
+    /// Ask for a full rescan every `interval`, on top of the watched events
+    pub fn with_rescan_interval(mut self, interval: Option<Duration>) -> Self {
+        self.rescan_interval = interval.filter(|interval| !interval.is_zero());
+        self
+    }
+
+    /// Poll the tree every `interval` instead of listening to events
+    pub fn with_poll_interval(mut self, interval: Option<Duration>) -> Self {
+        self.poll_interval = interval.filter(|interval| !interval.is_zero());
+        self
+    }
+
+    pub async fn start(&mut self, tx: mpsc::Sender<ChangeType>) -> Result<()> {
+        let rescans = self.rescan_interval.map(every);
+        match self.poll_interval {
+            Some(period) => self.start_polling(period, rescans, tx).await,
+            None => self.start_native(rescans, tx),
         }
     }
 
-    pub async fn start<F, Fut>(&mut self, callback: F) -> anyhow::Result<()>
-    where
-        F: Fn(DebouncedEvent) -> Fut + Send + Sync + 'static,
-        Fut: std::future::Future<Output = ()> + Send + 'static,
-    {
-        let (tx, mut rx) = mpsc::channel(100);
-        let callback = Arc::new(callback);
-
-        let mut debouncer = new_debouncer(
-            Duration::from_millis(2000),
-            move |events: Result<Vec<DebouncedEvent>, notify::Error>| {
-                if let Ok(events) = events {
-                    for event in events {
-                        if let Err(e) = tx.blocking_send(event) {
-                            log::error!("Error sending event: {}", e);
+    fn start_native(&mut self, mut rescans: Option<Interval>, tx: mpsc::Sender<ChangeType>) -> Result<()> {
+        // notify calls back on its own thread, which must never wait on the service
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let _ = event_tx.send(event);
+        })?;
+        if let Err(e) = watcher.watch(&self.path, RecursiveMode::Recursive) {
+            // Huge trees can run out of watches; with periodic scans the mapping still converges
+            if self.rescan_interval.is_none() {
+                return Err(e.into());
+            }
+            log::warn!("Could not watch all of {:?}, relying on periodic scans: {}", self.path, e);
+        }
+        self.watcher = Some(watcher);
+
+        let root = self.path.clone();
+        let mut settler = Settler::new(self.settle_time);
+        self.task = Some(tokio::spawn(async move {
+            let mut translator = Translator::new(root.clone());
+            let mut checks = every(RENAME_WINDOW);
+            loop {
+                let changes = tokio::select! {
+                    event = event_rx.recv() => match event {
+                        Some(Ok(event)) => {
+                            let closed = closed_by_writer(&event);
+                            settler.admit(translator.translate(event), &closed, Instant::now()).await
                         }
+                        Some(Err(e)) => translator.error(e),
+                        None => break,
+                    },
+                    _ = tick(&mut rescans) => vec![ChangeType::Rescan(root.clone())],
+                    _ = checks.tick() => {
+                        let now = Instant::now();
+                        let mut changes = settler.admit(translator.flush(now), &[], now).await;
+                        changes.extend(settler.ready(now).await);
+                        changes
+                    }
+                };
+                for change in changes {
+                    if tx.send(change).await.is_err() {
+                        return;
                     }
                 }
-            },
-        )?;
+            }
+        }));
+
+        Ok(())
+    }
 
-        debouncer.watcher().watch(&self.path, RecursiveMode::Recursive)?;
-        self.watcher = Some(debouncer);
+    async fn start_polling(
+        &mut self,
+        period: Duration,
+        mut rescans: Option<Interval>,
+        tx: mpsc::Sender<ChangeType>,
+    ) -> Result<()> {
+        // Whatever is there now is the baseline, only later changes are reported
+        let mut snapshot = Snapshot::take(&self.path).await?;
+        let mut polls = every(period);
 
-        tokio::spawn(async move {
-            while let Some(event) = rx.recv().await {
-                let callback = Arc::clone(&callback);
-                callback(event).await;
+        let root = self.path.clone();
+        let mut settler = Settler::new(self.settle_time);
+        self.task = Some(tokio::spawn(async move {
+            let mut checks = every(RENAME_WINDOW);
+            loop {
+                let changes = tokio::select! {
+                    _ = polls.tick() => match Snapshot::take(&root).await {
+                        Ok(mut next) => {
+                            let mut changes = snapshot.diff(&next);
+                            // A listing that raced a rename can miss both names, so look again before believing a deletion
+                            if changes.iter().any(|change| matches!(change, ChangeType::Deleted(_))) {
+                                if let Ok(again) = Snapshot::take(&root).await {
+                                    changes = snapshot.diff(&again);
+                                    next = again;
+                                }
+                            }
+                            snapshot = next;
+                            settler.admit(changes, &[], Instant::now()).await
+                        }
+                        // An unreachable share is not an empty one, wait for it to come back
+                        Err(e) => {
+                            log::warn!("Error polling {:?}: {}", root, e);
+                            Vec::new()
+                        }
+                    },
+                    _ = tick(&mut rescans) => vec![ChangeType::Rescan(root.clone())],
+                    _ = checks.tick() => settler.ready(Instant::now()).await,
+                };
+                for change in changes {
+                    if tx.send(change).await.is_err() {
+                        return;
+                    }
+                }
             }
-        });
+        }));
 
         Ok(())
     }
 
-    pub async fn stop(&mut self) -> anyhow::Result<()> {
-        if let Some(watcher) = self.watcher.take() {
-            drop(watcher);
+    pub async fn stop(&mut self) -> Result<()> {
+        self.watcher.take();
+        if let Some(task) = self.task.take() {
+            task.abort();
         }
         Ok(())
     }
 }
 
+/// An interval whose first tick is one period from now, and that does not
+/// try to catch up after a slow round
+fn every(period: Duration) -> Interval {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Turns raw notify events into `ChangeType`s. Backends report a rename as
+/// two events, the old name then the new one, sometimes followed by a third
+/// with both; the halves are paired here, and a half left alone means the
+/// path moved out of or into the watched folder.
+struct Translator {
+    root: PathBuf,
+    /// Old names waiting for their new name, with the backend's rename cookie if it has one
+    pending: Vec<(Option<usize>, PathBuf, Instant)>,
+    /// Cookies of renames already reported, whose combined event is still to come
+    paired: Vec<usize>,
+}
+
+impl Translator {
+    fn new(root: PathBuf) -> Self {
+        Self { root, pending: Vec::new(), paired: Vec::new() }
+    }
+
+    fn translate(&mut self, event: Event) -> Vec<ChangeType> {
+        let now = Instant::now();
+        let mut changes = self.flush(now);
+        if event.need_rescan() {
+            // Lost events say nothing about where, unless the platform gives a folder
+            let path = event.paths.into_iter().next().unwrap_or_else(|| self.root.clone());
+            changes.push(ChangeType::Rescan(path));
+            return changes;
+        }
+
+        let tracker = event.tracker();
+        let mut paths = event.paths.into_iter();
+
+        match event.kind {
+            EventKind::Create(_) => changes.extend(paths.map(ChangeType::Created)),
+            EventKind::Remove(_) => changes.extend(paths.map(ChangeType::Deleted)),
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                self.pending.extend(paths.map(|path| (tracker, path, now)));
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                for to in paths {
+                    // Without a cookie, only the old name reported right before can be the match
+                    let matched = match tracker {
+                        Some(_) => self.pending.iter().position(|(cookie, _, _)| *cookie == tracker),
+                        None => self.pending.last().filter(|(cookie, _, _)| cookie.is_none()).map(|_| self.pending.len() - 1),
+                    };
+                    match matched {
+                        Some(index) => {
+                            let (_, from, _) = self.pending.remove(index);
+                            self.paired.extend(tracker);
+                            changes.push(ChangeType::Renamed { from, to });
+                        }
+                        None => changes.push(ChangeType::Created(to)),
+                    }
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let Some(index) = tracker.and_then(|cookie| self.paired.iter().position(|paired| *paired == cookie)) {
+                    self.paired.remove(index);
+                } else if let (Some(from), Some(to)) = (paths.next(), paths.next()) {
+                    self.pending.retain(|(cookie, _, _)| tracker.is_none() || *cookie != tracker);
+                    changes.push(ChangeType::Renamed { from, to });
+                }
+            }
+            // Backends that cannot tell which side of a rename they saw
+            EventKind::Modify(ModifyKind::Name(_)) => {
+                changes.extend(paths.map(|path| match path.exists() {
+                    true => ChangeType::Created(path),
+                    false => ChangeType::Deleted(path),
+                }));
+            }
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::AccessTime)) | EventKind::Access(AccessKind::Read) => {}
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) | EventKind::Modify(_) | EventKind::Any => {
+                changes.extend(paths.map(ChangeType::Modified))
+            }
+            EventKind::Access(_) | EventKind::Other => {}
+        }
+
+        changes
+    }
+
+    /// A watch that failed, e.g. on a folder created when the watch limit was
+    /// already reached, leaves whatever it covers unseen
+    fn error(&mut self, error: notify::Error) -> Vec<ChangeType> {
+        log::error!("Error watching local changes: {}", error);
+        let path = error.paths.into_iter().next().unwrap_or_else(|| self.root.clone());
+        vec![ChangeType::Rescan(path)]
+    }
+
+    /// Give up on old names whose new name never came: they left the folder
+    fn flush(&mut self, now: Instant) -> Vec<ChangeType> {
+        let mut changes = Vec::new();
+        self.pending.retain(|(_, path, since)| {
+            let expired = now.duration_since(*since) >= RENAME_WINDOW;
+            if expired {
+                changes.push(ChangeType::Deleted(path.clone()));
+            }
+            !expired
+        });
+        changes
+    }
+}
+
+/// Files whose writer closed them, which the backend only reports on some platforms
+fn closed_by_writer(event: &Event) -> Vec<PathBuf> {
+    match event.kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => event.paths.clone(),
+        _ => Vec::new(),
+    }
+}
+
+/// A created or modified file that is not done being written yet
+struct Settling {
+    /// Reported as created rather than modified
+    created: bool,
+    /// Size and modification time when last looked at
+    stamp: Option<(u64, Option<SystemTime>)>,
+    /// When the file last changed, by an event or by its stamp
+    changed: Instant,
+    closed: Option<Instant>,
+}
+
+/// Holds back created and modified files until their writer closes them or
+/// they stop changing, and folds a new file renamed into place into one
+/// change of its new name. An old copy moved aside for a new one, as vim
+/// saves, is folded the same way. Everything else passes right through.
+struct Settler {
+    settle_time: Duration,
+    pending: BTreeMap<PathBuf, Settling>,
+    /// Files renamed away, held for a moment in case a new file takes their old name
+    moved: Vec<(PathBuf, PathBuf, Instant)>,
+    /// Old copies moved aside for a new file, whose deletion is not reported
+    aside: Vec<(PathBuf, Instant)>,
+}
+
+impl Settler {
+    fn new(settle_time: Duration) -> Self {
+        Self { settle_time, pending: BTreeMap::new(), moved: Vec::new(), aside: Vec::new() }
+    }
+
+    /// Take in new changes, along with the files whose writer just closed
+    /// them. Returns the changes that need not wait.
+    async fn admit(&mut self, changes: Vec<ChangeType>, closed: &[PathBuf], now: Instant) -> Vec<ChangeType> {
+        let mut passed = Vec::new();
+        for change in changes {
+            let created = matches!(change, ChangeType::Created(_));
+            let is_dir = match &change {
+                ChangeType::Created(path) | ChangeType::Modified(path) | ChangeType::Renamed { to: path, .. } => {
+                    fs::metadata(path).await.is_ok_and(|metadata| metadata.is_dir())
+                }
+                _ => false,
+            };
+            match change {
+                // By the time events arrive a file can be gone already, renamed into place or deleted
+                ChangeType::Created(path) | ChangeType::Modified(path) if !is_dir => {
+                    let replaced = self.moved.iter().position(|(from, _, _)| *from == path);
+                    let created = match replaced {
+                        // A new copy written where the old one was moved aside
+                        Some(index) => {
+                            let (_, to, _) = self.moved.remove(index);
+                            self.aside.push((to, now));
+                            false
+                        }
+                        None => created || self.pending.get(&path).is_some_and(|settling| settling.created),
+                    };
+                    self.pending.insert(path, Settling { created, stamp: None, changed: now, closed: None });
+                }
+                ChangeType::Deleted(path) if self.aside.iter().any(|(aside, _)| *aside == path) => {
+                    self.aside.retain(|(aside, _)| *aside != path);
+                }
+                ChangeType::Deleted(path) => {
+                    // Renamed and deleted right after, the old name is what went away
+                    if let Some(index) = self.moved.iter().position(|(_, to, _)| *to == path) {
+                        let (from, _, _) = self.moved.remove(index);
+                        passed.push(ChangeType::Deleted(from));
+                        continue;
+                    }
+                    passed.extend(self.release(|_| true));
+                    // Written and deleted again before anyone saw it, e.g. a lock file
+                    let transient = self.pending.remove(&path).is_some_and(|settling| settling.created);
+                    self.pending.retain(|pending, _| !pending.starts_with(&path));
+                    if !transient {
+                        passed.push(ChangeType::Deleted(path));
+                    }
+                }
+                ChangeType::Renamed { from, to } => match self.pending.remove(&from) {
+                    // Saved under a temporary name and renamed into place
+                    Some(settling) if settling.created => {
+                        self.pending.insert(to, Settling { created: false, ..settling });
+                    }
+                    None if !is_dir => {
+                        passed.extend(self.release(|_| true));
+                        self.moved.push((from, to, now));
+                    }
+                    settling => {
+                        passed.extend(self.release(|_| true));
+                        self.pending = std::mem::take(&mut self.pending)
+                            .into_iter()
+                            .map(|(path, settling)| match path.strip_prefix(&from) {
+                                Ok(rest) => (to.join(rest), settling),
+                                Err(_) => (path, settling),
+                            })
+                            .collect();
+                        self.pending.extend(settling.map(|settling| (to.clone(), settling)));
+                        passed.push(ChangeType::Renamed { from, to });
+                    }
+                },
+                change => passed.push(change),
+            }
+        }
+
+        for path in closed {
+            if let Some(settling) = self.pending.get_mut(path) {
+                settling.closed = Some(now);
+            }
+        }
+        passed
+    }
+
+    /// Report the held renames that match, as they happened
+    fn release(&mut self, matches: impl Fn(Instant) -> bool) -> Vec<ChangeType> {
+        let mut released = Vec::new();
+        self.moved.retain(|(from, to, since)| {
+            let release = matches(*since);
+            if release {
+                released.push(ChangeType::Renamed { from: from.clone(), to: to.clone() });
+            }
+            !release
+        });
+        released
+    }
+
+    /// The files that are done being written
+    async fn ready(&mut self, now: Instant) -> Vec<ChangeType> {
+        let mut ready = self.release(|since| now.duration_since(since) >= RENAME_WINDOW);
+        // An old copy kept around, e.g. a backup, is a file like any other
+        let mut kept = Vec::new();
+        self.aside.retain(|(path, since)| {
+            let expired = now.duration_since(*since) >= self.settle_time;
+            if expired {
+                kept.push(path.clone());
+            }
+            !expired
+        });
+        for path in kept {
+            if fs::metadata(&path).await.is_ok() {
+                ready.push(ChangeType::Created(path));
+            }
+        }
+        let mut settled = Vec::new();
+        for (path, settling) in &mut self.pending {
+            let done = match settling.closed {
+                // A new file may still be renamed into place, which is better reported as one change
+                Some(closed) => !settling.created || now.duration_since(closed) >= RENAME_WINDOW,
+                None => match fs::metadata(path).await {
+                    Ok(metadata) => {
+                        let stamp = (metadata.len(), metadata.modified().ok());
+                        if settling.stamp != Some(stamp) {
+                            settling.stamp = Some(stamp);
+                            settling.changed = now;
+                        }
+                        now.duration_since(settling.changed) >= self.settle_time
+                    }
+                    // Gone again, which is reported on its own
+                    Err(_) => {
+                        settled.push(path.clone());
+                        continue;
+                    }
+                },
+            };
+            if done {
+                ready.push(match settling.created {
+                    true => ChangeType::Created(path.clone()),
+                    false => ChangeType::Modified(path.clone()),
+                });
+                settled.push(path.clone());
+            }
+        }
+        for path in settled {
+            self.pending.remove(&path);
+        }
+        ready
+    }
+}
+
+/// What polling compares between two rounds. The inode tells a rename from a
+/// deletion plus a creation, and a file saved by renaming a new copy over it
+/// from one left alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    size: u64,
+    inode: u64,
+    is_dir: bool,
+}
+
+impl Stamp {
+    fn new(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Self { modified: metadata.modified().ok(), size: metadata.len(), inode, is_dir: metadata.is_dir() }
+    }
+
+    /// Whether `other` can be this entry under another name. A rename leaves
+    /// a file's size and modification time alone, which also keeps a reused
+    /// inode from passing for a rename.
+    fn same_entry(&self, other: &Stamp) -> bool {
+        self.inode != 0
+            && self.inode == other.inode
+            && self.is_dir == other.is_dir
+            && (self.is_dir || (self.size == other.size && self.modified == other.modified))
+    }
+}
+
+/// Every file and folder below a polled root, symlinks skipped
+#[derive(Debug, Default)]
+struct Snapshot(BTreeMap<PathBuf, Stamp>);
+
+impl Snapshot {
+    async fn take(root: &Path) -> Result<Self> {
+        let mut entries = BTreeMap::new();
+        let mut pending = vec![root.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let mut listing = match fs::read_dir(&dir).await {
+                Ok(listing) => listing,
+                // Deleted since its parent was listed
+                Err(e) if e.kind() == ErrorKind::NotFound && dir != root => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = listing.next_entry().await? {
+                let metadata = match entry.metadata().await {
+                    Ok(metadata) => metadata,
+                    Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                if metadata.is_dir() {
+                    pending.push(entry.path());
+                } else if !metadata.is_file() {
+                    continue;
+                }
+                entries.insert(entry.path(), Stamp::new(&metadata));
+            }
+        }
+        Ok(Self(entries))
+    }
+
+    /// What changed between this snapshot and a later one. Like native
+    /// events, a folder that comes, goes or moves is reported once for
+    /// everything in it.
+    fn diff(&self, next: &Snapshot) -> Vec<ChangeType> {
+        let mut changes = Vec::new();
+
+        // Parents sort before their children, so a moved folder is found before what it holds
+        let mut moves: Vec<(&Path, &Path)> = Vec::new();
+        for (from, old) in self.0.iter().filter(|(path, _)| !next.0.contains_key(*path)) {
+            if moves.iter().any(|(moved, _)| from.starts_with(moved)) {
+                continue;
+            }
+            let to = next.0.iter().find(|(to, stamp)| {
+                !self.0.contains_key(*to) && old.same_entry(stamp) && !moves.iter().any(|(_, moved)| to.starts_with(moved))
+            });
+            if let Some((to, _)) = to {
+                moves.push((from, to));
+                changes.push(ChangeType::Renamed { from: from.clone(), to: to.clone() });
+            }
+        }
+
+        // Compare the rest as if the moves had happened before this round
+        let before: BTreeMap<PathBuf, Stamp> = self
+            .0
+            .iter()
+            .map(|(path, stamp)| {
+                let path = moves
+                    .iter()
+                    .find_map(|(from, to)| path.strip_prefix(from).ok().map(|rest| relocate(to, rest)))
+                    .unwrap_or_else(|| path.clone());
+                (path, *stamp)
+            })
+            .collect();
+
+        for (path, old) in &before {
+            match next.0.get(path) {
+                None if !parent_only_in(path, &before, &next.0) => changes.push(ChangeType::Deleted(path.clone())),
+                Some(new) if new.is_dir && !old.is_dir => changes.push(ChangeType::Created(path.clone())),
+                Some(new) if !new.is_dir && new != old => changes.push(ChangeType::Modified(path.clone())),
+                _ => {}
+            }
+        }
+        for path in next.0.keys() {
+            if !before.contains_key(path) && !parent_only_in(path, &next.0, &before) {
+                changes.push(ChangeType::Created(path.clone()));
+            }
+        }
+
+        changes
+    }
+}
+
+fn relocate(to: &Path, rest: &Path) -> PathBuf {
+    match rest.as_os_str().is_empty() {
+        true => to.to_path_buf(),
+        false => to.join(rest),
+    }
+}
+
+/// Whether the folder holding `path` is in `a` but not in `b`, in which case
+/// reporting the folder covers `path` too
+fn parent_only_in(path: &Path, a: &BTreeMap<PathBuf, Stamp>, b: &BTreeMap<PathBuf, Stamp>) -> bool {
+    path.parent().is_some_and(|parent| a.contains_key(parent) && !b.contains_key(parent))
+}
+
+/// Whether `path` is on a network filesystem, where changes made by other
+/// machines raise no events here. Only Linux can tell, by the filesystem's
+/// magic number; elsewhere this is always false.
+#[cfg(target_os = "linux")]
+pub fn is_network_filesystem(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    // From statfs(2)
+    const NETWORK: &[u32] = &[
+        0x6969,     // NFS
+        0x517b,     // SMB
+        0xff534d42, // CIFS
+        0xfe534d42, // SMB2
+        0x5346414f, // AFS
+        0x73757245, // Coda
+        0x00c36400, // Ceph
+        0x01021997, // 9p, which also serves Windows drives to WSL
+        0x564c,     // NCP
+    ];
+
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else { return false };
+    // SAFETY: `path` is a valid C string and `stat` a plain struct that statfs fills in
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+    NETWORK.contains(&(stat.f_type as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_network_filesystem(_path: &Path) -> bool {
+    false
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::Duration;
+    use notify::event::CreateKind;
     use tempfile::tempdir;
-    use tokio::sync::mpsc;
+
+    fn event(kind: EventKind, paths: &[&str], tracker: Option<usize>) -> Event {
+        let mut event = Event::new(kind);
+        for path in paths {
+            event = event.add_path(PathBuf::from(path));
+        }
+        match tracker {
+            Some(tracker) => event.set_tracker(tracker),
+            None => event,
+        }
+    }
+
+    #[test]
+    fn test_renames_are_paired() {
+        let name = |mode| EventKind::Modify(ModifyKind::Name(mode));
+        let renamed = ChangeType::Renamed { from: PathBuf::from("/w/a"), to: PathBuf::from("/w/b") };
+
+        // inotify: both halves with a cookie, then the combined event
+        let mut translator = Translator::new(PathBuf::from("/w"));
+        assert!(translator.translate(event(name(RenameMode::From), &["/w/a"], Some(7))).is_empty());
+        assert_eq!(translator.translate(event(name(RenameMode::To), &["/w/b"], Some(7))), std::slice::from_ref(&renamed));
+        assert!(translator.translate(event(name(RenameMode::Both), &["/w/a", "/w/b"], Some(7))).is_empty());
+
+        // Windows: adjacent halves without a cookie
+        let mut translator = Translator::new(PathBuf::from("/w"));
+        assert!(translator.translate(event(name(RenameMode::From), &["/w/a"], None)).is_empty());
+        assert_eq!(translator.translate(event(name(RenameMode::To), &["/w/b"], None)), [renamed]);
+
+        // Moved in and out of the watched folder
+        let mut translator = Translator::new(PathBuf::from("/w"));
+        assert_eq!(
+            translator.translate(event(name(RenameMode::To), &["/w/in"], Some(1))),
+            [ChangeType::Created(PathBuf::from("/w/in"))]
+        );
+        assert!(translator.translate(event(name(RenameMode::From), &["/w/out"], Some(2))).is_empty());
+        assert_eq!(
+            translator.flush(Instant::now() + RENAME_WINDOW),
+            [ChangeType::Deleted(PathBuf::from("/w/out"))]
+        );
+    }
+
+    #[test]
+    fn test_lost_events_ask_for_rescan() {
+        let mut translator = Translator::new(PathBuf::from("/w"));
+        let overflow = Event::new(EventKind::Other).set_flag(notify::event::Flag::Rescan);
+        assert_eq!(translator.translate(overflow), [ChangeType::Rescan(PathBuf::from("/w"))]);
+
+        let error = notify::Error::new(notify::ErrorKind::MaxFilesWatch).add_path(PathBuf::from("/w/huge"));
+        assert_eq!(translator.error(error), [ChangeType::Rescan(PathBuf::from("/w/huge"))]);
+    }
 
     #[tokio::test]
-    async fn test_file_watcher() -> Result<()> {
+    async fn test_periodic_rescan() -> Result<()> {
         let temp_dir = tempdir()?;
-        let test_file = temp_dir.path().join("test.txt");
+        let mut watcher = FileWatcher::new(temp_dir.path().to_path_buf())
+            .with_rescan_interval(Some(Duration::from_millis(200)));
+        let (tx, mut rx) = mpsc::channel(100);
+        watcher.start(tx).await?;
 
-        // Create watcher
-        let mut watcher = FileWatcher::new(temp_dir.path().to_path_buf());
+        let event = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await?;
+        assert_eq!(event, Some(ChangeType::Rescan(temp_dir.path().to_path_buf())));
+
+        watcher.stop().await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let file = |inode, size| Stamp { modified: None, size, inode, is_dir: false };
+        let dir = |inode| Stamp { modified: None, size: 0, inode, is_dir: true };
+        let snapshot = |entries: &[(&str, Stamp)]| {
+            Snapshot(entries.iter().map(|(path, stamp)| (PathBuf::from(path), *stamp)).collect())
+        };
+        let before = snapshot(&[
+            ("/w/docs", dir(1)),
+            ("/w/docs/a.txt", file(2, 10)),
+            ("/w/docs/b.txt", file(3, 10)),
+            ("/w/edited.txt", file(4, 10)),
+            ("/w/saved.txt", file(5, 10)),
+            ("/w/gone", dir(6)),
+            ("/w/gone/c.txt", file(7, 10)),
+            ("/w/old.txt", file(8, 10)),
+        ]);
+        let after = snapshot(&[
+            // The folder moved, and one file in it changed on the way
+            ("/w/papers", dir(1)),
+            ("/w/papers/a.txt", file(2, 10)),
+            ("/w/papers/b.txt", file(3, 20)),
+            ("/w/edited.txt", file(4, 20)),
+            // Saved by renaming a new copy over it
+            ("/w/saved.txt", file(9, 10)),
+            ("/w/new.txt", file(8, 10)),
+            ("/w/added", dir(10)),
+            ("/w/added/d.txt", file(11, 10)),
+        ]);
+
+        let path = PathBuf::from;
+        assert_eq!(before.diff(&after), [
+            ChangeType::Renamed { from: path("/w/docs"), to: path("/w/papers") },
+            ChangeType::Renamed { from: path("/w/old.txt"), to: path("/w/new.txt") },
+            ChangeType::Modified(path("/w/edited.txt")),
+            ChangeType::Deleted(path("/w/gone")),
+            ChangeType::Modified(path("/w/papers/b.txt")),
+            ChangeType::Modified(path("/w/saved.txt")),
+            ChangeType::Created(path("/w/added")),
+        ]);
+        assert!(after.diff(&after).is_empty());
+    }
 
-        // Create channel for test events
+    #[tokio::test]
+    async fn test_polling_watcher() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let test_file = temp_dir.path().join("test.txt");
+        let renamed_file = temp_dir.path().join("renamed.txt");
+        assert!(!is_network_filesystem(temp_dir.path()));
+
+        let mut watcher = FileWatcher::new(temp_dir.path().to_path_buf())
+            .with_poll_interval(Some(Duration::from_millis(100)))
+            .with_settle_time(Duration::from_millis(200));
         let (tx, mut rx) = mpsc::channel(100);
+        watcher.start(tx).await?;
 
-        // Start watching
-        watcher
-            .start(move |event| {
-                let tx = tx.clone();
-                async move {
-                    let _ = tx.send(event).await;
+        tokio::fs::write(&test_file, b"test data").await?;
+        let event = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await?;
+        assert_eq!(event, Some(ChangeType::Created(test_file.clone())));
+
+        // A poll can land mid-write, so the content may show up as a change first
+        tokio::fs::rename(&test_file, &renamed_file).await?;
+        let renamed = ChangeType::Renamed { from: test_file.clone(), to: renamed_file };
+        let event = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match rx.recv().await {
+                    Some(ChangeType::Modified(path)) if path == test_file => continue,
+                    event => return event,
                 }
-            })
-            .await?;
+            }
+        })
+        .await?;
+        assert_eq!(event, Some(renamed));
 
-        // Create test file
-        tokio::fs::write(&test_file, b"test data").await?;
+        watcher.stop().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_files_settle_before_they_are_reported() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let path = |name: &str| temp_dir.path().join(name);
+        let settle_time = Duration::from_secs(2);
+        let mut settler = Settler::new(settle_time);
+        let now = Instant::now();
+
+        // Still being written: only reported once it stops changing
+        std::fs::write(path("big.iso"), b"first half")?;
+        assert!(settler.admit(vec![ChangeType::Created(path("big.iso"))], &[], now).await.is_empty());
+        assert!(settler.ready(now).await.is_empty());
+        std::fs::write(path("big.iso"), b"first half, second half")?;
+        assert!(settler.ready(now + settle_time / 2).await.is_empty());
+        assert!(settler.ready(now + settle_time).await.is_empty());
+        assert_eq!(settler.ready(now + settle_time * 2).await, [ChangeType::Created(path("big.iso"))]);
+
+        // Closed by its writer: reported right away
+        std::fs::write(path("notes.txt"), b"done")?;
+        assert!(settler.admit(vec![ChangeType::Modified(path("notes.txt"))], &[path("notes.txt")], now).await.is_empty());
+        assert_eq!(settler.ready(now).await, [ChangeType::Modified(path("notes.txt"))]);
+
+        // An editor saving through a temporary file is a single change
+        std::fs::write(path("report.txt"), b"old")?;
+        std::fs::write(path(".report.txt.tmp"), b"new")?;
+        let changes = vec![ChangeType::Created(path(".report.txt.tmp")), ChangeType::Modified(path(".report.txt.tmp"))];
+        assert!(settler.admit(changes, &[path(".report.txt.tmp")], now).await.is_empty());
+        assert!(settler.ready(now).await.is_empty());
+        std::fs::rename(path(".report.txt.tmp"), path("report.txt"))?;
+        let renamed = ChangeType::Renamed { from: path(".report.txt.tmp"), to: path("report.txt") };
+        assert!(settler.admit(vec![renamed], &[], now).await.is_empty());
+        assert_eq!(settler.ready(now).await, [ChangeType::Modified(path("report.txt"))]);
+
+        // A file that comes and goes before it settles is never seen
+        std::fs::write(path(".~lock"), b"")?;
+        assert!(settler.admit(vec![ChangeType::Created(path(".~lock"))], &[], now).await.is_empty());
+        std::fs::remove_file(path(".~lock"))?;
+        assert!(settler.admit(vec![ChangeType::Deleted(path(".~lock"))], &[], now).await.is_empty());
+        assert!(settler.ready(now + settle_time * 2).await.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_editor_save_is_one_change() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let document = temp_dir.path().join("report.txt");
+        let temporary = temp_dir.path().join(".report.txt.tmp");
+        tokio::fs::write(&document, b"old").await?;
+
+        let mut watcher = FileWatcher::new(temp_dir.path().to_path_buf());
+        let (tx, mut rx) = mpsc::channel(100);
+        watcher.start(tx).await?;
 
-        // Wait for event
+        tokio::fs::write(&temporary, b"new").await?;
+        tokio::fs::rename(&temporary, &document).await?;
+        let event = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await?;
+        assert_eq!(event, Some(ChangeType::Modified(document)));
+        assert!(tokio::time::timeout(Duration::from_millis(500), rx.recv()).await.is_err());
+
+        watcher.stop().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_old_copy_moved_aside_is_one_change() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let path = |name: &str| temp_dir.path().join(name);
+        let mut settler = Settler::new(Duration::from_secs(2));
+        let now = Instant::now();
+
+        // vim moves the old copy aside, writes the new one and deletes the old
+        std::fs::write(path("notes.txt~"), b"old")?;
+        std::fs::write(path("notes.txt"), b"new")?;
+        let changes = vec![
+            ChangeType::Renamed { from: path("notes.txt"), to: path("notes.txt~") },
+            ChangeType::Created(path("notes.txt")),
+            ChangeType::Modified(path("notes.txt")),
+        ];
+        assert!(settler.admit(changes, &[path("notes.txt")], now).await.is_empty());
+        std::fs::remove_file(path("notes.txt~"))?;
+        assert!(settler.admit(vec![ChangeType::Deleted(path("notes.txt~"))], &[], now).await.is_empty());
+        assert_eq!(settler.ready(now + RENAME_WINDOW).await, [ChangeType::Modified(path("notes.txt"))]);
+
+        // A plain rename still comes through, once nothing takes the old name
+        std::fs::rename(path("notes.txt"), path("ideas.txt"))?;
+        let renamed = ChangeType::Renamed { from: path("notes.txt"), to: path("ideas.txt") };
+        assert!(settler.admit(vec![renamed.clone()], &[], now).await.is_empty());
+        assert_eq!(settler.ready(now + RENAME_WINDOW).await, [renamed]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plain_events() {
+        let mut translator = Translator::new(PathBuf::from("/w"));
+        assert_eq!(
+            translator.translate(event(EventKind::Create(CreateKind::File), &["/w/a"], None)),
+            [ChangeType::Created(PathBuf::from("/w/a"))]
+        );
+        assert_eq!(
+            translator.translate(event(EventKind::Modify(ModifyKind::Any), &["/w/a"], None)),
+            [ChangeType::Modified(PathBuf::from("/w/a"))]
+        );
+        assert!(translator.translate(event(EventKind::Access(AccessKind::Read), &["/w/a"], None)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_file_watcher() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let test_file = temp_dir.path().join("test.txt");
+        let renamed_file = temp_dir.path().join("renamed.txt");
+
+        let mut watcher = FileWatcher::new(temp_dir.path().to_path_buf());
+        let (tx, mut rx) = mpsc::channel(100);
+        watcher.start(tx).await?;
+
+        tokio::fs::write(&test_file, b"test data").await?;
         let event = tokio::time::timeout(Duration::from_secs(5), rx.recv())
             .await?
             .expect("No event received");
+        assert_eq!(event, ChangeType::Created(test_file.clone()));
 
-        assert_eq!(event.path, test_file);
+        tokio::fs::rename(&test_file, &renamed_file).await?;
+        let renamed = ChangeType::Renamed { from: test_file, to: renamed_file };
+        let seen = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(event) = rx.recv().await {
+                if event == renamed {
+                    return true;
+                }
+            }
+            false
+        })
+        .await?;
+        assert!(seen);
 
-        // Stop watcher
         watcher.stop().await?;
 
         Ok(())